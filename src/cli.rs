@@ -1,93 +1,162 @@
 use chrono::{prelude::*, Duration};
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use regex::Regex;
+
+use crate::datetime::parse::{self, Week};
+use crate::token_storage::TokenStorage;
+use crate::util::SearchOptions;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub(crate) struct Cli {
-    /// Start of search window in the form of MM/DD/YYYY (default now)
+    /// Start of search window, e.g. MM/DD/YYYY, YYYY-MM-DD, tomorrow, next monday, in 3 days (default now)
     #[arg(long, value_parser = parse_datetime)]
     pub start: Option<DateTime<Local>>,
 
-    /// End of search window in the form of MM/DD/YYYY (default start + 7 days)
+    /// End of search window, e.g. MM/DD/YYYY, YYYY-MM-DD, friday, end of week, end of month (default start + 7 days)
     #[arg(long, value_parser = parse_datetime)]
     pub end: Option<DateTime<Local>>,
 
-    /// Minimum time for availability in the form of <int>:<int>am/pm (default 9:00am)
+    /// Search the current or next week, starting on Monday
+    #[arg(long, value_enum, conflicts_with_all = ["start", "end", "window"])]
+    pub week: Option<Week>,
+
+    /// Minimum time for availability, e.g. 9:00am, 9am, 09:00 (default 9:00am)
     #[arg(long, value_parser = parse_naivetime)]
     pub min: Option<NaiveTime>,
 
-    /// Maximum time for availability in the form of <int>:<int>am/pm (default 5:00pm)
+    /// Maximum time for availability, e.g. 5:00pm, 5pm, 17:00 (default 5:00pm)
     #[arg(long, value_parser = parse_naivetime)]
     pub max: Option<NaiveTime>,
 
-    /// Duration of search window, specify with <int>(w|d|h|m) (default 1w)
+    /// Duration of search window, e.g. 1w, 10d, 1d12h (default 1w)
     #[arg(short, long, value_parser = parse_duration)]
     pub window: Option<Duration>,
 
     /// Option to include weekends in availability search (default false)
-    #[arg(long, default_value_t = false)]
+    #[arg(long, default_value_t = false, overrides_with = "no_include_weekends")]
     pub include_weekends: bool,
 
-    /// Duration of availability window, specify with <int>(w|d|h|m) (default 30m)
+    /// Exclude weekends even if the config file includes them
+    #[arg(long, default_value_t = false, overrides_with = "include_weekends")]
+    pub no_include_weekends: bool,
+
+    /// Duration of availability window, e.g. 30m, 1h30m, PT90M, 90 (default 30m)
     #[arg(short, long, value_parser = parse_duration)]
     pub duration: Option<Duration>,
 
     /// Create a hold event (default false)
-    #[arg(short, long, default_value_t = false)]
+    #[arg(
+        short,
+        long,
+        default_value_t = false,
+        overrides_with = "no_create_hold_event"
+    )]
     pub create_hold_event: bool,
 
+    /// Don't create a hold event even if the config file does
+    #[arg(long, default_value_t = false, overrides_with = "create_hold_event")]
+    pub no_create_hold_event: bool,
+
+    /// How long hold events are kept before `holds gc` releases them, e.g. 3d, 1w (default never)
+    #[arg(long, value_parser = parse_duration)]
+    pub hold_ttl: Option<Duration>,
+
+    /// Template used to format availability, one of plain, markdown, html or a template in ~/.avail/templates (default plain)
+    #[arg(short, long)]
+    pub template: Option<String>,
+
+    /// Skip copying availability to the clipboard (default false)
+    #[arg(long, default_value_t = false, overrides_with = "copy")]
+    pub no_copy: bool,
+
+    /// Copy availability to the clipboard even if the config file sets no_copy
+    #[arg(long, default_value_t = false, overrides_with = "no_copy")]
+    pub copy: bool,
+
+    /// Calendar groups or calendar names or ids to search instead of the selected calendars, e.g. work,family
+    #[arg(long, value_delimiter = ',')]
+    pub calendars: Option<Vec<String>>,
+
+    /// Named preset from the config file to use for options that aren't specified
+    #[arg(short, long)]
+    pub preset: Option<String>,
+
+    /// Ignore cached events and retrieve all events again (default false)
+    #[arg(long, default_value_t = false)]
+    pub refresh: bool,
+
+    /// Only use cached events, without accessing the network (default false)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["refresh", "create_hold_event"])]
+    pub offline: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
-fn parse_datetime(arg: &str) -> Result<DateTime<Local>, chrono::ParseError> {
-    let dt_str: String = arg.to_string();
-    let non_local_d = NaiveDate::parse_from_str(&dt_str, "%m/%d/%Y");
-    let time = NaiveTime::from_hms(0, 0, 0);
+impl Cli {
+    pub fn search_options(&self) -> SearchOptions {
+        // Flags and their negations, e.g. --include-weekends and --no-include-weekends,
+        // override the config file either way.
+        let flag = |on: bool, off: bool| match (on, off) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        };
 
-    let datetime = NaiveDateTime::new(non_local_d?, time);
-    Ok(Local.from_local_datetime(&datetime).unwrap())
+        SearchOptions {
+            start: self.start,
+            end: self.end,
+            week: self.week,
+            min: self.min,
+            max: self.max,
+            window: self.window,
+            include_weekends: flag(self.include_weekends, self.no_include_weekends),
+            duration: self.duration,
+            create_hold_event: flag(self.create_hold_event, self.no_create_hold_event),
+            hold_ttl: self.hold_ttl,
+            template: self.template.to_owned(),
+            no_copy: flag(self.no_copy, self.copy),
+            calendars: self.calendars.to_owned(),
+        }
+    }
 }
 
-fn parse_naivetime(arg: &str) -> Result<NaiveTime, chrono::ParseError> {
-    let time_str: String = arg.to_string();
-    NaiveTime::parse_from_str(&time_str, "%l:%M%P")
+fn parse_datetime(arg: &str) -> anyhow::Result<DateTime<Local>> {
+    parse::parse_date(arg, Local::now())
 }
 
-fn parse_duration(arg: &str) -> anyhow::Result<Duration> {
-    let duration_str: String = arg.to_string();
-
-    let re = Regex::new(r"([0-9]*)(w|d|h|m)").unwrap();
-    let caps = re.captures(&duration_str).unwrap();
-
-    let group_1 = caps.get(1);
-    let group_2 = caps.get(2);
+fn parse_naivetime(arg: &str) -> anyhow::Result<NaiveTime> {
+    parse::parse_time(arg)
+}
 
-    if let (Some(match_1), Some(match_2)) = (group_1, group_2) {
-        let num = match_1.as_str().parse::<i64>()?;
-        let unit = match_2.as_str();
+fn parse_duration(arg: &str) -> anyhow::Result<Duration> {
+    parse::parse_duration(arg)
+}
 
-        match unit {
-            "w" => Ok(Duration::weeks(num)),
-            "d" => Ok(Duration::days(num)),
-            "h" => Ok(Duration::hours(num)),
-            "m" => Ok(Duration::minutes(num)),
-            _ => Err(anyhow::anyhow!("Unsupported duration unit")),
-        }
-    } else {
-        Err(anyhow::anyhow!("Failed to parse duration."))
-    }
+fn parse_token_storage(arg: &str) -> anyhow::Result<TokenStorage> {
+    arg.parse()
 }
 
 #[derive(Subcommand)]
 pub(crate) enum Commands {
     /// Manages OAuth accounts (Microsoft Outlook and Google Calendar)
     Accounts(AccountsCmd),
-    /// Allows specifying which calendars to use when querying, refreshes calendar cache for added accounts
+    /// Manages which calendars are used when querying and for hold events
     Calendars(CalendarsCmd),
+    /// Manages the cache of calendar events
+    Cache(CacheCmd),
+    /// Manages named groups of calendars that can be searched with --calendars
+    Groups(GroupsCmd),
+    /// Lists, confirms and releases hold events created by avail
+    Holds(HoldsCmd),
+    /// Mirrors events of calendars as busy blocks in other calendars
+    Sync(SyncCmd),
+    /// Manages where OAuth tokens are stored
+    Tokens(TokensCmd),
+    /// Shows and applies migrations of the local database
+    Db(DbCmd),
 }
 
 #[derive(Args)]
@@ -97,7 +166,46 @@ pub(crate) struct AccountsCmd {
 }
 
 #[derive(Args)]
-pub(crate) struct CalendarsCmd {}
+pub(crate) struct CalendarsCmd {
+    #[command(subcommand)]
+    pub command: Option<CalendarCommands>,
+}
+
+#[derive(Args)]
+pub(crate) struct CacheCmd {
+    #[command(subcommand)]
+    pub command: CacheCommands,
+}
+
+#[derive(Args)]
+pub(crate) struct TokensCmd {
+    #[command(subcommand)]
+    pub command: TokenCommands,
+}
+
+#[derive(Args)]
+pub(crate) struct GroupsCmd {
+    #[command(subcommand)]
+    pub command: GroupCommands,
+}
+
+#[derive(Args)]
+pub(crate) struct HoldsCmd {
+    #[command(subcommand)]
+    pub command: HoldCommands,
+}
+
+#[derive(Args)]
+pub(crate) struct SyncCmd {
+    #[command(subcommand)]
+    pub command: Option<SyncCommands>,
+}
+
+#[derive(Args)]
+pub(crate) struct DbCmd {
+    #[command(subcommand)]
+    pub command: DbCommands,
+}
 
 #[derive(Subcommand)]
 pub(crate) enum AccountCommands {
@@ -107,12 +215,18 @@ pub(crate) enum AccountCommands {
     Remove(AccountRemove),
     /// Lists all OAuth accounts
     List(AccountList),
+    /// Signs in to an OAuth account again, keeping its calendar selection
+    Reauth(AccountReauth),
 }
 
 #[derive(Args)]
 pub(crate) struct AccountAdd {
     /// The email of the account to add
     pub email: String,
+
+    /// Sign in by entering a code on another device, e.g. when connected over SSH (default false)
+    #[arg(long, default_value_t = false)]
+    pub device: bool,
 }
 
 #[derive(Args)]
@@ -124,6 +238,261 @@ pub(crate) struct AccountRemove {
 #[derive(Args)]
 pub(crate) struct AccountList {}
 
+#[derive(Args)]
+pub(crate) struct AccountReauth {
+    /// The email of the account to sign in to again
+    pub email: String,
+
+    /// Sign in by entering a code on another device, e.g. when connected over SSH (default false)
+    #[arg(long, default_value_t = false)]
+    pub device: bool,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum CalendarCommands {
+    /// Lists the calendars of all accounts and whether they're selected or used for hold events
+    List(CalendarList),
+    /// Uses a calendar's events when finding availability
+    Select(CalendarSelect),
+    /// Stops using a calendar's events when finding availability
+    Deselect(CalendarSelect),
+    /// Creates hold events in a calendar, in addition to the other hold calendars
+    SetHold(CalendarSelect),
+    /// Stops creating hold events in a calendar
+    UnsetHold(CalendarSelect),
+    /// Retrieves the calendars of accounts again, selecting new ones and keeping the current selection
+    Refresh(CalendarRefresh),
+    /// Prompts for the calendars to use for each account and the calendars for hold events (default)
+    Configure(CalendarConfigure),
+}
+
+#[derive(Args)]
+pub(crate) struct CalendarList {}
+
+#[derive(Args)]
+pub(crate) struct CalendarSelect {
+    /// The id or name of the calendar
+    pub calendar: String,
+
+    /// Only match calendars of the account with this email
+    #[arg(long)]
+    pub account: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct CalendarRefresh {
+    /// Only retrieve the calendars of the account with this email
+    #[arg(long)]
+    pub account: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct CalendarConfigure {}
+
+#[derive(Subcommand)]
+pub(crate) enum GroupCommands {
+    /// Adds calendars to a group, creating it if it doesn't exist
+    Add(GroupAdd),
+    /// Removes calendars from a group, or the whole group if no calendars are given
+    Remove(GroupRemove),
+    /// Lists all groups and their calendars
+    List(GroupList),
+}
+
+#[derive(Args)]
+pub(crate) struct GroupAdd {
+    /// The name of the group
+    pub group: String,
+
+    /// The ids or names of the calendars to add
+    #[arg(required = true)]
+    pub calendars: Vec<String>,
+
+    /// Only match calendars of the account with this email
+    #[arg(long)]
+    pub account: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct GroupRemove {
+    /// The name of the group
+    pub group: String,
+
+    /// The ids or names of the calendars to remove
+    pub calendars: Vec<String>,
+
+    /// Only match calendars of the account with this email
+    #[arg(long)]
+    pub account: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct GroupList {}
+
+#[derive(Subcommand)]
+pub(crate) enum HoldCommands {
+    /// Lists hold events that haven't been released
+    List(HoldList),
+    /// Shows the details of a hold event
+    Show(HoldShow),
+    /// Deletes hold events from their calendar
+    Release(HoldRelease),
+    /// Turns a hold event into the meeting and releases the others from the same query
+    Confirm(HoldConfirm),
+    /// Releases expired hold events that weren't edited since they were created
+    Gc(HoldGc),
+}
+
+#[derive(Args)]
+pub(crate) struct HoldList {
+    /// Only list the hold events created by this query
+    #[arg(long)]
+    pub query: Option<u32>,
+}
+
+#[derive(Args)]
+pub(crate) struct HoldShow {
+    /// The id of the hold event
+    pub id: u32,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("holds").required(true).args(["ids", "all", "query"])))]
+pub(crate) struct HoldRelease {
+    /// The ids of the hold events to release
+    pub ids: Vec<u32>,
+
+    /// Release all hold events
+    #[arg(long, default_value_t = false)]
+    pub all: bool,
+
+    /// Release the hold events created by this query
+    #[arg(long)]
+    pub query: Option<u32>,
+}
+
+#[derive(Args)]
+pub(crate) struct HoldConfirm {
+    /// The id of the hold event to keep
+    pub id: u32,
+
+    /// Title of the meeting, defaults to the hold's title without its prefix
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Description of the meeting
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// Emails of people to invite, e.g. alice@example.com,bob@example.com
+    #[arg(long = "attendee", value_delimiter = ',')]
+    pub attendees: Vec<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct HoldGc {
+    /// Print the hold events that would be released without releasing them
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Also release upcoming hold events that avail created but no longer tracks, they're
+    /// only listed otherwise since another machine may share the hold calendars
+    #[arg(long, default_value_t = false)]
+    pub release_untracked: bool,
+
+    /// How long ago untracked hold events must have been created to be released, e.g. 12h, 3d (default 1d)
+    #[arg(long, value_parser = parse_duration, requires = "release_untracked")]
+    pub min_age: Option<Duration>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum SyncCommands {
+    /// Creates, updates and deletes busy blocks to match the source calendars (default)
+    Run(SyncRun),
+    /// Mirrors the events of a source calendar as busy blocks in a target calendar
+    Add(SyncPair),
+    /// Stops mirroring a source calendar, deleting its busy blocks from the target calendar
+    Remove(SyncPair),
+    /// Lists the synced calendars
+    List(SyncList),
+}
+
+#[derive(Args)]
+pub(crate) struct SyncRun {
+    /// How far ahead events are mirrored, e.g. 2w, 30d (default 4w)
+    #[arg(short, long, value_parser = parse_duration)]
+    pub window: Option<Duration>,
+}
+
+#[derive(Args)]
+pub(crate) struct SyncPair {
+    /// The id or name of the calendar whose events are mirrored
+    pub source: String,
+
+    /// The id or name of the calendar the busy blocks are created in
+    pub target: String,
+
+    /// Only match source calendars of the account with this email
+    #[arg(long)]
+    pub source_account: Option<String>,
+
+    /// Only match target calendars of the account with this email
+    #[arg(long)]
+    pub target_account: Option<String>,
+}
+
+#[derive(Args)]
+pub(crate) struct SyncList {}
+
+#[derive(Subcommand)]
+pub(crate) enum CacheCommands {
+    /// Deletes all cached events, they will be retrieved again on the next search
+    Clear(CacheClear),
+    /// Shows the number of cached events and when they were last synced for each calendar
+    Stats(CacheStats),
+}
+
+#[derive(Subcommand)]
+pub(crate) enum TokenCommands {
+    /// Moves the tokens of all accounts from one storage backend to another
+    Migrate(TokensMigrate),
+}
+
+#[derive(Args)]
+pub(crate) struct TokensMigrate {
+    /// Backend to move tokens from, one of keyring, file or command:<helper>
+    #[arg(long, value_parser = parse_token_storage)]
+    pub from: TokenStorage,
+
+    /// Backend to move tokens to, one of keyring, file or command:<helper> (default the configured backend)
+    #[arg(long, value_parser = parse_token_storage)]
+    pub to: Option<TokenStorage>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum DbCommands {
+    /// Shows the schema version of the database and the migrations that haven't been applied
+    Status(DbStatus),
+    /// Backs up the database and applies pending migrations
+    Migrate(DbMigrate),
+}
+
+#[derive(Args)]
+pub(crate) struct DbStatus {}
+
+#[derive(Args)]
+pub(crate) struct DbMigrate {
+    /// List the migrations that would be applied without applying them (default false)
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct CacheClear {}
+
+#[derive(Args)]
+pub(crate) struct CacheStats {}
+
 pub(crate) struct ProgressIndicator {
     multi: MultiProgress,
     style: ProgressStyle,
@@ -145,6 +514,10 @@ impl ProgressIndicator {
         self.multi.add(p).with_style(self.style.clone())
     }
 
+    pub fn println(&self, msg: &str) {
+        let _ = self.multi.println(msg);
+    }
+
     pub fn clear(&self) {
         self.multi.clear().unwrap();
     }
@@ -0,0 +1,190 @@
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use copypasta::{ClipboardContext, ClipboardProvider};
+use serde::{Deserialize, Serialize};
+
+/// Backends that can be used to copy to the clipboard, `auto` tries each in order until one succeeds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardBackend {
+    #[default]
+    Auto,
+    Copypasta,
+    WlCopy,
+    Xclip,
+    Xsel,
+    Osc52,
+    None,
+}
+
+const AUTO_BACKENDS: [ClipboardBackend; 5] = [
+    ClipboardBackend::Copypasta,
+    ClipboardBackend::WlCopy,
+    ClipboardBackend::Xclip,
+    ClipboardBackend::Xsel,
+    ClipboardBackend::Osc52,
+];
+
+// Backends that can set the content type, used to copy HTML when chosen explicitly.
+const HTML_BACKENDS: [ClipboardBackend; 2] = [ClipboardBackend::WlCopy, ClipboardBackend::Xclip];
+
+pub struct ClipboardContent {
+    /// Plain text version of the content, copied by backends that can't copy HTML.
+    pub text: String,
+    /// Copied as text/html instead of `text` by backends that can set the content type.
+    pub html: Option<String>,
+}
+
+impl ClipboardBackend {
+    /// Copies the content to the clipboard, returning the backend that was used.
+    pub fn copy(&self, content: &ClipboardContent) -> anyhow::Result<ClipboardBackend> {
+        match self {
+            ClipboardBackend::Auto => {
+                // The HTML backends can only offer one content type, so pasting HTML into
+                // terminals or plain text fields would get nothing. The plain text version
+                // is copied instead.
+                let content = ClipboardContent {
+                    text: content.text.to_owned(),
+                    html: None,
+                };
+                for backend in AUTO_BACKENDS.iter() {
+                    if backend.copy(&content).is_ok() {
+                        return Ok(*backend);
+                    }
+                }
+                Err(anyhow::anyhow!("No clipboard backend is available"))
+            }
+            ClipboardBackend::Copypasta => {
+                let mut ctx = ClipboardContext::new().map_err(|e| anyhow::anyhow!("{}", e))?;
+                ctx.set_contents(content.text.to_owned())
+                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+                Ok(*self)
+            }
+            ClipboardBackend::WlCopy => {
+                if env::var_os("WAYLAND_DISPLAY").is_none() {
+                    return Err(anyhow::anyhow!("Not running under Wayland"));
+                }
+                match &content.html {
+                    Some(html) => pipe_to_command("wl-copy", &["--type", "text/html"], html)?,
+                    None => pipe_to_command("wl-copy", &[], &content.text)?,
+                }
+                Ok(*self)
+            }
+            ClipboardBackend::Xclip => {
+                match &content.html {
+                    Some(html) => pipe_to_command(
+                        "xclip",
+                        &["-selection", "clipboard", "-t", "text/html"],
+                        html,
+                    )?,
+                    None => pipe_to_command("xclip", &["-selection", "clipboard"], &content.text)?,
+                }
+                Ok(*self)
+            }
+            ClipboardBackend::Xsel => {
+                pipe_to_command("xsel", &["--clipboard", "--input"], &content.text)?;
+                Ok(*self)
+            }
+            ClipboardBackend::Osc52 => {
+                copy_osc52(&content.text)?;
+                Ok(*self)
+            }
+            ClipboardBackend::None => Err(anyhow::anyhow!("Copying to the clipboard is disabled")),
+        }
+    }
+}
+
+impl ClipboardBackend {
+    /// Whether the backend copies HTML as text/html, others copy the plain text version.
+    ///
+    /// Neither wl-copy nor xclip can offer several content types at once, so only the HTML
+    /// is copied. `auto` copies the plain text version so it pastes anywhere.
+    pub fn copies_html(&self) -> bool {
+        HTML_BACKENDS.contains(self)
+    }
+}
+
+impl std::fmt::Display for ClipboardBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ClipboardBackend::Auto => "auto",
+            ClipboardBackend::Copypasta => "copypasta",
+            ClipboardBackend::WlCopy => "wl-copy",
+            ClipboardBackend::Xclip => "xclip",
+            ClipboardBackend::Xsel => "xsel",
+            ClipboardBackend::Osc52 => "osc52",
+            ClipboardBackend::None => "none",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn pipe_to_command(program: &str, args: &[&str], input: &str) -> anyhow::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("{} exited with {}", program, status));
+    }
+
+    Ok(())
+}
+
+/// Asks the terminal to set the clipboard, works over SSH and inside tmux.
+fn copy_osc52(text: &str) -> anyhow::Result<()> {
+    let term = console::Term::stdout();
+    if !term.is_term() {
+        return Err(anyhow::anyhow!("stdout is not a terminal"));
+    }
+
+    let sequence = osc52_sequence(text, env::var_os("TMUX").is_some());
+    let mut stdout = std::io::stdout();
+    stdout.write_all(sequence.as_bytes())?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+fn osc52_sequence(text: &str, tmux: bool) -> String {
+    let sequence = format!("\x1b]52;c;{}\x07", base64::encode(text));
+    if tmux {
+        // tmux requires escape sequences to be wrapped in a passthrough sequence.
+        format!("\x1bPtmux;\x1b{}\x1b\\", sequence)
+    } else {
+        sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(osc52_sequence("avail", false), "\x1b]52;c;YXZhaWw=\x07");
+        assert_eq!(
+            osc52_sequence("avail", true),
+            "\x1bPtmux;\x1b\x1b]52;c;YXZhaWw=\x07\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_disabled_backend() {
+        let content = ClipboardContent {
+            text: "avail".to_string(),
+            html: None,
+        };
+        assert!(ClipboardBackend::None.copy(&content).is_err());
+    }
+}
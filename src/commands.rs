@@ -2,27 +2,52 @@ use std::sync::Arc;
 
 use chrono::{prelude::*, Duration};
 use colored::Colorize;
-use copypasta::{ClipboardContext, ClipboardProvider};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
 use indicatif::ProgressBar;
 use itertools::Itertools;
 use tokio::{sync::Semaphore, task::JoinHandle};
 
 use crate::cli::ProgressIndicator;
+use crate::clipboard::{ClipboardBackend, ClipboardContent};
 use crate::datetime::{
-    availability::{
-        format_availability, merge_overlapping_avails, split_availability, Availability,
-    },
+    availability::{merge_overlapping_avails, split_availability, Availability},
     finder::AvailabilityFinder,
+    locale::Locale,
 };
-use crate::events::{google, microsoft, Calendar, Event, GetResources};
-use crate::store::{AccountModel, CalendarModel, Platform, Store, PLATFORMS};
-use crate::util::AvailConfig;
+use crate::events::{
+    google, is_marker, marker, microsoft, Calendar, CreatedEvent, Event, EventChanges, EventUpdate,
+    GetResources, NewEvent, NotFound, ShowAs, BUSY_MARKER, HOLD_MARKER, MEETING_MARKER,
+};
+use crate::migrations;
+use crate::oauth::ReauthRequired;
+use crate::store::{
+    AccountModel, BusyBlockModel, CacheStats, CalendarGroupModel, CalendarInfo, CalendarModel,
+    EventModel, HoldModel, Platform, Store, SyncModel, SyncPairModel, PLATFORMS,
+};
+use crate::template::{html_to_text, Template};
+use crate::token_storage::{self, TokenStorage};
+use crate::tokens::{self, TokenCache};
+use crate::util::{AvailConfig, HoldConfig};
+
+// Events are cached for at least this many weeks from the start of the search window.
+const CACHE_WEEKS: i64 = 4;
+
+/// How cached events are used when finding availability.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Sync changes since the last search.
+    Sync,
+    /// Discard cached events and retrieve all events again.
+    Refresh,
+    /// Only use cached events, without accessing the network.
+    Offline,
+}
 
 pub async fn add_account(
     db: Store,
     email: &str,
     cfg: &AvailConfig,
+    device: bool,
     shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
 ) -> anyhow::Result<()> {
     let selection = Select::with_theme(&ColorfulTheme::default())
@@ -34,7 +59,7 @@ pub async fn add_account(
 
     let selected_platform = PLATFORMS[selection];
 
-    let accounts = db.execute(Box::new(AccountModel::get))??;
+    let accounts = db.execute(AccountModel::get).await?;
     if accounts
         .iter()
         .any(|a| a.name == email && a.platform.unwrap() == selected_platform)
@@ -42,32 +67,28 @@ pub async fn add_account(
         return Err(anyhow::anyhow!("Account already exists with that email"));
     }
 
-    match selected_platform {
-        Platform::Microsoft => {
-            let (_, refresh_token) = microsoft::get_authorization_code(
-                &cfg.microsoft.to_owned().unwrap_or_default(),
-                shutdown_receiver,
-            )
-            .await?;
-            crate::store::store_token(email, &refresh_token)?;
-        }
-        Platform::Google => {
-            let (_, refresh_token) = google::get_authorization_code(
-                &cfg.google.to_owned().unwrap_or_default(),
-                shutdown_receiver,
-            )
-            .await?;
-            crate::store::store_token(email, &refresh_token)?;
-        }
-        _ => return Err(anyhow::anyhow!("Unsupported platform")),
+    let refresh_token = authorize(selected_platform, cfg, device, shutdown_receiver).await?;
+    let storage = cfg.token_storage.to_owned().unwrap_or_default();
+
+    // Tokens used to be stored under the email only, so the token of an account with the
+    // same email on another platform is moved before it becomes ambiguous.
+    for existing in accounts.iter().filter(|a| a.name == email) {
+        let _ = storage.get_or_migrate(&existing.token_user(), &existing.name);
     }
 
-    let account = AccountModel {
+    let mut account = AccountModel {
         name: email.to_owned(),
         platform: Some(selected_platform),
         id: None,
+        needs_reauth: false,
     };
-    db.execute(Box::new(move |conn| account.insert(conn)))??;
+    let insert = account.clone();
+    account.id = Some(db.execute(move |conn| insert.insert(conn)).await?);
+
+    if let Err(e) = storage.store(&account.token_user(), &refresh_token) {
+        db.execute(move |conn| account.delete(conn)).await?;
+        return Err(e);
+    }
     println!("\nSuccessfully added account.");
     println!(
         "Run the \"{}\" command to update the calendars cache with this account's calendars.",
@@ -77,27 +98,204 @@ pub async fn add_account(
     Ok(())
 }
 
-pub fn remove_account(db: Store, email: &str) -> anyhow::Result<()> {
+/// Runs the browser flow, or the device flow if `device` is set, for the platform,
+/// returning the refresh token.
+async fn authorize(
+    platform: Platform,
+    cfg: &AvailConfig,
+    device: bool,
+    shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
+) -> anyhow::Result<String> {
+    let (_, refresh_token) = match platform {
+        Platform::Microsoft => {
+            let cfg = cfg.microsoft.to_owned().unwrap_or_default();
+            if device {
+                microsoft::get_device_authorization(&cfg).await?
+            } else {
+                microsoft::get_authorization_code(&cfg, shutdown_receiver).await?
+            }
+        }
+        Platform::Google => {
+            let cfg = cfg.google.to_owned().unwrap_or_default();
+            if device {
+                google::get_device_authorization(&cfg).await?
+            } else {
+                google::get_authorization_code(&cfg, shutdown_receiver).await?
+            }
+        }
+        _ => return Err(anyhow::anyhow!("Unsupported platform")),
+    };
+    Ok(refresh_token)
+}
+
+pub async fn reauth_account(
+    db: Store,
+    email: &str,
+    cfg: &AvailConfig,
+    device: bool,
+    shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let account = find_account(&db, email).await?;
+
+    let refresh_token =
+        authorize(account.platform.unwrap(), cfg, device, shutdown_receiver).await?;
+    let storage = cfg.token_storage.to_owned().unwrap_or_default();
+    storage.store(&account.token_user(), &refresh_token)?;
+    tokens::delete_persisted_token(&storage, &account.token_user());
+
+    let account_id = account.id.unwrap();
+    db.execute(move |conn| AccountModel::set_needs_reauth(conn, &account_id, false))
+        .await?;
+    println!("\nSuccessfully re-authenticated account.");
+
+    Ok(())
+}
+
+/// Finds the account with the email, asking which one is meant if the email is used on
+/// several platforms.
+async fn find_account(db: &Store, email: &str) -> anyhow::Result<AccountModel> {
+    let mut accounts: Vec<AccountModel> = db
+        .execute(AccountModel::get)
+        .await?
+        .into_iter()
+        .filter(|a| a.name == email)
+        .collect();
+
+    match accounts.len() {
+        0 => Err(anyhow::anyhow!("No account exists with that email")),
+        1 => Ok(accounts.remove(0)),
+        _ => {
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Which platform is the account \"{}\" on?", email))
+                .items(&accounts.iter().map(|a| a.platform.unwrap()).collect_vec())
+                .default(0)
+                .interact()?;
+            Ok(accounts.remove(selection))
+        }
+    }
+}
+
+/// Marks the account as needing re-authentication if `err` is because its refresh token
+/// has expired or was revoked, returning whether it was.
+async fn mark_if_reauth_required(
+    db: &Store,
+    account: &AccountModel,
+    err: &anyhow::Error,
+) -> anyhow::Result<bool> {
+    if !err.is::<ReauthRequired>() {
+        return Ok(false);
+    }
+    let account_id = account.id.unwrap();
+    db.execute(move |conn| AccountModel::set_needs_reauth(conn, &account_id, true))
+        .await?;
+    Ok(true)
+}
+
+fn reauth_error(email: &str) -> String {
+    format!(
+        "Access to {} has expired or was revoked. Run \"{}\" to sign in again.",
+        email,
+        format!("accounts reauth {}", email).italic().bold()
+    )
+}
+
+fn reauth_warning(email: &str) -> String {
+    format!("{} Skipping it.", reauth_error(email))
+        .yellow()
+        .to_string()
+}
+
+pub async fn remove_account(
+    db: Store,
+    tokens: &TokenCache,
+    email: &str,
+    cfg: &AvailConfig,
+) -> anyhow::Result<()> {
+    let account = find_account(&db, email).await?;
+
     if Confirm::with_theme(&ColorfulTheme::default())
-        .with_prompt(format!("Do you want to delete the account \"{}\"?", email))
+        .with_prompt(format!(
+            "Do you want to delete the account \"{}\" on {}?",
+            email,
+            account.platform.unwrap()
+        ))
         .interact()
         .unwrap()
     {
-        crate::store::delete_token(email)?;
-        let account = AccountModel {
-            name: email.to_owned(),
-            id: None,
-            platform: None,
-        };
-        db.execute(Box::new(move |conn| account.delete(conn)))??;
+        // Busy blocks synced from or into the account's calendars are deleted first, removing
+        // the account stops tracking them.
+        let account_id = account.id.unwrap();
+        let res = delete_busy_blocks_of(&db, tokens, |id, _| id == account_id).await;
+        if let Err(e) = res {
+            println!(
+                "{}",
+                format!("Failed to delete the busy blocks of the account: {}", e).red()
+            );
+            if !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(
+                    "Remove the account anyway? The remaining busy blocks won't be deleted.",
+                )
+                .default(false)
+                .interact()
+                .unwrap()
+            {
+                return Ok(());
+            }
+        }
+
+        let storage = cfg.token_storage.to_owned().unwrap_or_default();
+        let user = account.token_user();
+        // Older versions stored the token under the account name, a missing token is fine.
+        storage.delete(&user)?;
+        storage.delete(&account.name)?;
+        tokens::delete_persisted_token(&storage, &user);
+        db.execute(move |conn| account.delete(conn)).await?;
         println!("Successfully removed account.");
     }
 
     Ok(())
 }
 
-pub fn list_accounts(db: Store) -> anyhow::Result<()> {
-    let accounts = db.execute(Box::new(AccountModel::get))??;
+pub async fn migrate_tokens(
+    db: Store,
+    cfg: &AvailConfig,
+    from: &TokenStorage,
+    to: Option<&TokenStorage>,
+) -> anyhow::Result<()> {
+    let to = to
+        .cloned()
+        .unwrap_or_else(|| cfg.token_storage.to_owned().unwrap_or_default());
+    if *from == to {
+        return Err(anyhow::anyhow!("Tokens are already stored in {}", to));
+    }
+
+    let accounts = db.execute(AccountModel::get).await?;
+    let users: Vec<String> = accounts
+        .iter()
+        .flat_map(|a| {
+            [
+                a.token_user(),
+                tokens::access_token_user(&a.token_user()),
+                // Stored by older versions.
+                a.name.to_owned(),
+            ]
+        })
+        .collect();
+
+    let moved = token_storage::migrate(from, &to, &users)?;
+    println!("Moved {} token(s) from {} to {}.", moved, from, to);
+    if cfg.token_storage.to_owned().unwrap_or_default() != to {
+        println!(
+            "Set the token storage in the config file to {} to use them.",
+            to.to_string().bold()
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn list_accounts(db: Store) -> anyhow::Result<()> {
+    let accounts = db.execute(AccountModel::get).await?;
 
     if accounts.is_empty() {
         println!("Configured accounts: None");
@@ -105,9 +303,14 @@ pub fn list_accounts(db: Store) -> anyhow::Result<()> {
         println!("Configured accounts:");
         for account in accounts {
             println!(
-                "- {} on {}",
+                "- {} on {}{}",
                 account.name.bold().blue(),
-                account.platform.unwrap()
+                account.platform.unwrap(),
+                if account.needs_reauth {
+                    " (needs re-authentication)".red().to_string()
+                } else {
+                    String::new()
+                }
             );
         }
     }
@@ -115,8 +318,9 @@ pub fn list_accounts(db: Store) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn refresh_calendars(db: Store, cfg: &AvailConfig) -> anyhow::Result<()> {
-    let accounts = db.execute(Box::new(AccountModel::get))??;
+/// Returns the accounts, or only the one with the email if it's set.
+async fn get_accounts(db: &Store, email: Option<&str>) -> anyhow::Result<Vec<AccountModel>> {
+    let accounts = db.execute(AccountModel::get).await?;
 
     if accounts.is_empty() {
         return Err(anyhow::anyhow!(format!(
@@ -125,36 +329,120 @@ pub async fn refresh_calendars(db: Store, cfg: &AvailConfig) -> anyhow::Result<(
         )));
     }
 
-    for account in accounts {
-        let refresh_token = crate::store::get_token(&account.name)?;
-
-        let account_id = account.id.unwrap().to_owned();
-        let mut calendars = match account.platform.unwrap() {
-            Platform::Microsoft => {
-                let access_token = microsoft::refresh_access_token(
-                    &cfg.microsoft.to_owned().unwrap_or_default(),
-                    &refresh_token,
-                )
-                .await?;
-                microsoft::MicrosoftGraph::get_calendars(&access_token).await?
+    match email {
+        Some(email) => {
+            let accounts: Vec<AccountModel> =
+                accounts.into_iter().filter(|a| a.name == email).collect();
+            if accounts.is_empty() {
+                return Err(anyhow::anyhow!("No account exists with that email"));
             }
-            Platform::Google => {
-                let access_token = google::refresh_access_token(
-                    &cfg.google.to_owned().unwrap_or_default(),
-                    &refresh_token,
-                )
-                .await?;
-                google::GoogleAPI::get_calendars(&access_token).await?
+            Ok(accounts)
+        }
+        None => Ok(accounts),
+    }
+}
+
+/// Retrieves the account's calendars, returning `None` if it has to be re-authenticated.
+async fn fetch_calendars(
+    db: &Store,
+    tokens: &TokenCache,
+    account: &AccountModel,
+) -> anyhow::Result<Option<Vec<Calendar>>> {
+    let platform = account.platform.unwrap();
+    let res = tokens
+        .with_token(account, |token| async move {
+            match platform {
+                Platform::Microsoft => microsoft::MicrosoftGraph::get_calendars(&token).await,
+                Platform::Google => google::GoogleAPI::get_calendars(&token).await,
+                _ => Err(anyhow::anyhow!("Unsupported platform")),
             }
-            _ => return Err(anyhow::anyhow!("Unsupported platform")),
+        })
+        .await;
+    match res {
+        Ok(calendars) => Ok(Some(calendars)),
+        Err(e) if mark_if_reauth_required(db, account, &e).await? => {
+            println!("{}", reauth_warning(&account.name));
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes the busy blocks synced from or into the account's calendars that are no longer
+/// in `calendars`, since replacing the calendars stops tracking them.
+async fn delete_removed_calendar_blocks(
+    db: &Store,
+    tokens: &TokenCache,
+    account: &AccountModel,
+    calendars: &[CalendarModel],
+) -> anyhow::Result<()> {
+    let account_id = account.id.unwrap();
+    let res = delete_busy_blocks_of(db, tokens, |id, calendar_id| {
+        id == account_id && !calendars.iter().any(|c| c.id == calendar_id)
+    })
+    .await;
+    res.map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to delete the busy blocks synced from or into a removed calendar of {}, its calendars are kept so this can be retried: {}",
+            account.name,
+            e
+        )
+    })
+}
+
+/// Retrieves the calendars of the accounts again without prompting, new calendars are
+/// selected and the selection of existing ones is kept.
+pub async fn refresh_calendars(
+    db: Store,
+    tokens: &TokenCache,
+    email: Option<&str>,
+) -> anyhow::Result<()> {
+    for account in get_accounts(&db, email).await? {
+        let account_id = account.id.unwrap();
+        let calendars = match fetch_calendars(&db, tokens, &account).await? {
+            Some(calendars) => calendars,
+            None => continue,
+        };
+        let num_calendars = calendars.len();
+
+        let calendars: Vec<CalendarModel> = calendars
+            .into_iter()
+            .map(|c| CalendarModel {
+                account_id: Some(account_id),
+                id: c.id,
+                name: c.name,
+                selected: true,
+            })
+            .collect();
+        delete_removed_calendar_blocks(&db, tokens, &account, &calendars).await?;
+        db.execute(move |conn| CalendarModel::replace_for_account(conn, &account_id, calendars))
+            .await?;
+        println!(
+            "Retrieved {} calendar(s) for {}.",
+            num_calendars,
+            account.name.bold().blue()
+        );
+    }
+
+    Ok(())
+}
+
+/// Prompts for the calendars to use for each account and the calendars to create hold
+/// events in.
+pub async fn configure_calendars(db: Store, tokens: &TokenCache) -> anyhow::Result<()> {
+    for account in get_accounts(&db, None).await? {
+        let account_id = account.id.unwrap();
+        let mut calendars = match fetch_calendars(&db, tokens, &account).await? {
+            Some(calendars) => calendars,
+            None => continue,
         };
 
-        let mut prev_unselected_calendars = db
-            .execute(Box::new(move |conn| {
-                CalendarModel::get_all_selected(conn, &account_id.to_owned(), false)
-            }))??
+        let prev_unselected_calendars: Vec<String> = db
+            .execute(move |conn| CalendarModel::get_all_selected(conn, &account_id, false))
+            .await?
             .into_iter()
-            .map(|c| c.id);
+            .map(|c| c.id)
+            .collect();
 
         let mut defaults = vec![];
         for cal in calendars.iter() {
@@ -170,15 +458,11 @@ pub async fn refresh_calendars(db: Store, cfg: &AvailConfig) -> anyhow::Result<(
             ))
             .interact()?;
 
-        for (i, mut cal) in calendars.iter_mut().enumerate() {
+        for (i, cal) in calendars.iter_mut().enumerate() {
             cal.selected = selected_calendars_idx.contains(&i);
         }
 
-        db.execute(Box::new(move |conn| {
-            CalendarModel::delete_for_account(conn, &account_id)
-        }))??;
-
-        let insert_calendars: Vec<CalendarModel> = calendars
+        let calendars: Vec<CalendarModel> = calendars
             .into_iter()
             .map(|c| CalendarModel {
                 account_id: account.id,
@@ -188,216 +472,707 @@ pub async fn refresh_calendars(db: Store, cfg: &AvailConfig) -> anyhow::Result<(
             })
             .collect();
 
-        db.execute(Box::new(|conn| {
-            CalendarModel::insert_many(conn, insert_calendars)
-        }))??;
+        // Replacing the calendars keeps the groups and cached events of existing ones.
+        delete_removed_calendar_blocks(&db, tokens, &account, &calendars).await?;
+        db.execute(move |conn| {
+            let selected: Vec<(String, bool)> = calendars
+                .iter()
+                .map(|c| (c.id.to_owned(), c.selected))
+                .collect();
+            CalendarModel::replace_for_account(conn, &account_id, calendars)?;
+            for (id, selected) in selected {
+                CalendarModel::set_selected(conn, &account_id, &id, selected)?;
+            }
+            Ok(())
+        })
+        .await?;
     }
 
-    let mut all_calendars: Vec<Calendar> = db
-        .execute(Box::new(CalendarModel::get_all))??
-        .into_iter()
-        .map(|c| Calendar {
-            account_id: c.account_id.unwrap(),
-            id: c.id,
-            name: c.name,
-            selected: false,
-        })
-        .collect_vec();
+    let calendars = db.execute(CalendarInfo::get).await?;
+    if calendars.is_empty() {
+        return Ok(());
+    }
+
+    let items: Vec<String> = calendars
+        .iter()
+        .map(|c| format!("{} ({})", c.name, c.account))
+        .collect();
+    let defaults: Vec<bool> = calendars.iter().map(|c| c.hold).collect();
+    let hold_calendars_idx: Vec<usize> = MultiSelect::with_theme(&ColorfulTheme::default())
+        .items(&items)
+        .defaults(&defaults)
+        .with_prompt("Select the calendars you want to create hold events in")
+        .interact()?;
 
-    let previous_selected = db.execute(Box::new(move |conn| {
-        CalendarModel::get_hold_event_calendar(conn)
-    }))??;
+    db.execute(move |conn| {
+        for (i, cal) in calendars.iter().enumerate() {
+            let hold = hold_calendars_idx.contains(&i);
+            CalendarModel::set_hold(conn, &cal.account_id, &cal.id, hold)?;
+        }
+        Ok(())
+    })
+    .await?;
 
-    let previous_selected_idx: usize = if let Some((_, cal)) = previous_selected {
-        let e = all_calendars.iter().enumerate().find(|e| e.1.id == cal.id);
-        e.unwrap().0
-    } else {
-        0
-    };
+    Ok(())
+}
 
-    let selected_calendar_idx: usize = Select::with_theme(&ColorfulTheme::default())
-        .items(&all_calendars)
-        .default(previous_selected_idx)
-        .with_prompt("Which calendar would you like to use to create hold events?")
-        .interact()?;
+pub async fn list_calendars(db: Store) -> anyhow::Result<()> {
+    let calendars = db.execute(CalendarInfo::get).await?;
 
-    let mut selected_calendar = all_calendars.get_mut(selected_calendar_idx).unwrap();
-    selected_calendar.selected = true;
+    if calendars.is_empty() {
+        println!(
+            "Calendars: None, run \"{}\" to retrieve them.",
+            "calendars refresh".bold()
+        );
+        return Ok(());
+    }
 
-    let update_calendar = CalendarModel {
-        account_id: Some(selected_calendar.account_id),
-        id: selected_calendar.id.to_owned(),
-        name: selected_calendar.name.to_owned(),
-        selected: true,
-    };
+    let width = |f: fn(&CalendarInfo) -> usize| calendars.iter().map(f).max().unwrap_or(0);
+    let (account_width, id_width, name_width) = (
+        width(|c| c.account.len()).max("ACCOUNT".len()),
+        width(|c| c.id.len()).max("ID".len()),
+        width(|c| c.name.len()).max("NAME".len()),
+    );
 
-    db.execute(Box::new(move |conn| {
-        CalendarModel::update_hold_event_calendar(conn, update_calendar)
-    }))??;
+    println!(
+        "{:account_width$}  {:id_width$}  {:name_width$}  SELECTED  HOLD",
+        "ACCOUNT", "ID", "NAME"
+    );
+    for cal in calendars {
+        println!(
+            "{:account_width$}  {:id_width$}  {:name_width$}  {:8}  {}",
+            cal.account,
+            cal.id,
+            cal.name,
+            if cal.selected { "yes" } else { "no" },
+            if cal.hold { "yes" } else { "no" },
+        );
+    }
 
     Ok(())
 }
 
-pub fn print_and_copy_availability(avails: &[Availability<Local>]) {
-    let s = format_availability(avails);
-    let mut ctx = ClipboardContext::new().unwrap();
-    print!("{}", s);
-    if ctx.set_contents(s).is_ok() {
-        println!("\nCopied to clipboard.")
-    }
+/// Finds the calendar with the id or name, only considering the account's calendars if
+/// `email` is set.
+async fn find_calendar(
+    db: &Store,
+    calendar: &str,
+    email: Option<&str>,
+) -> anyhow::Result<CalendarInfo> {
+    let calendars = db.execute(CalendarInfo::get).await?;
+    match_calendar(&calendars, calendar, email)
 }
 
-pub(crate) async fn find_availability(
-    db: &Store,
-    cfg: &AvailConfig,
-    finder: AvailabilityFinder,
-    m: &ProgressIndicator,
-) -> anyhow::Result<Vec<Availability<Local>>> {
-    let accounts = db.execute(Box::new(AccountModel::get))??;
+// `Option::is_none_or` needs a newer Rust than avail otherwise requires.
+#[allow(clippy::unnecessary_map_or)]
+fn match_calendar(
+    calendars: &[CalendarInfo],
+    calendar: &str,
+    email: Option<&str>,
+) -> anyhow::Result<CalendarInfo> {
+    let calendars: Vec<&CalendarInfo> = calendars
+        .iter()
+        .filter(|c| email.map_or(true, |email| c.account == email))
+        .collect();
 
-    if accounts.is_empty() {
-        return Err(anyhow::anyhow!(format!(
-            "You must link accounts using the \"{}\" command and configure calendars using \"{}\" command before you are able to find availabilities.",
-            "accounts add".bold().italic(),
-            "calendars".bold().italic()
-        )));
+    // Ids are matched before names, which don't have to be unique.
+    let matches: Vec<&CalendarInfo> = if calendars.iter().any(|c| c.id == calendar) {
+        calendars.into_iter().filter(|c| c.id == calendar).collect()
+    } else {
+        calendars
+            .into_iter()
+            .filter(|c| c.name.eq_ignore_ascii_case(calendar))
+            .collect()
+    };
+
+    match matches[..] {
+        [] => Err(anyhow::anyhow!(
+            "No calendar matches \"{}\", run \"{}\" to see all calendars",
+            calendar,
+            "calendars list".bold()
+        )),
+        [cal] => Ok(cal.clone()),
+        _ => Err(anyhow::anyhow!(
+            "Several calendars match \"{}\", use its id or --account to choose one:\n{}",
+            calendar,
+            matches
+                .iter()
+                .map(|c| format!("- {} ({}): {}", c.name, c.account, c.id))
+                .join("\n")
+        )),
     }
+}
+
+/// Sets whether the calendar's events are used when finding availability.
+pub async fn select_calendar(
+    db: Store,
+    calendar: &str,
+    email: Option<&str>,
+    selected: bool,
+) -> anyhow::Result<()> {
+    let cal = find_calendar(&db, calendar, email).await?;
 
+    let (account_id, id) = (cal.account_id, cal.id.to_owned());
+    db.execute(move |conn| CalendarModel::set_selected(conn, &account_id, &id, selected))
+        .await?;
     println!(
-        "Finding availability between {} and {}\n",
-        format!("{}", finder.start.format("%b %-d %Y"))
-            .bold()
-            .blue(),
-        format!("{}", finder.end.format("%b %-d %Y")).bold().blue()
+        "{} {} ({}).",
+        if selected { "Selected" } else { "Deselected" },
+        cal.name.bold().blue(),
+        cal.account
     );
 
-    let pb = m.add(ProgressBar::new(1));
-    pb.set_message("Retrieving events...");
-    pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
+    Ok(())
+}
 
-    // Microsoft Graph has 4 concurrent requests limit
-    let semaphore = Arc::new(Semaphore::new(4));
-    let mut tasks: Vec<JoinHandle<anyhow::Result<Vec<Event>>>> = vec![];
+/// Sets whether hold events are created in the calendar, in addition to the other
+/// calendars they're created in.
+pub async fn set_hold_calendar(
+    db: Store,
+    calendar: &str,
+    email: Option<&str>,
+    hold: bool,
+) -> anyhow::Result<()> {
+    let cal = find_calendar(&db, calendar, email).await?;
 
-    for account in accounts {
-        let account_id = account.id.unwrap().to_owned();
-        let selected_calendars: Vec<String> = db
-            .execute(Box::new(move |conn| {
-                CalendarModel::get_all_selected(conn, &account_id, true)
-            }))??
-            .into_iter()
-            .map(|c| c.id)
-            .collect();
+    let (account_id, id) = (cal.account_id, cal.id.to_owned());
+    db.execute(move |conn| CalendarModel::set_hold(conn, &account_id, &id, hold))
+        .await?;
+    println!(
+        "Hold events will {}be created in {} ({}).",
+        if hold { "" } else { "no longer " },
+        cal.name.bold().blue(),
+        cal.account
+    );
 
-        match account.platform.unwrap() {
-            Platform::Microsoft => {
-                let refresh_token = crate::store::get_token(&account.name)?;
-                let access_token = microsoft::refresh_access_token(
-                    &cfg.microsoft.to_owned().unwrap_or_default(),
-                    &refresh_token,
-                )
-                .await?;
+    Ok(())
+}
 
-                for cal_id in selected_calendars {
-                    let token = access_token.clone();
-                    let permit = semaphore
-                        .clone()
-                        .acquire_owned()
-                        .await
-                        .expect("unable to acquire permit"); // Acquire a permit
-                    tasks.push(tokio::task::spawn(async move {
-                        let res = microsoft::MicrosoftGraph::get_calendar_events(
-                            &token,
-                            &cal_id,
-                            finder.start,
-                            finder.end,
-                        )
-                        .await?;
-                        drop(permit);
-                        Ok(res)
-                    }));
-                }
-            }
-            Platform::Google => {
-                let refresh_token = crate::store::get_token(&account.name)?;
-                let access_token = google::refresh_access_token(
-                    &cfg.google.to_owned().unwrap_or_default(),
-                    &refresh_token,
-                )
-                .await?;
+pub async fn add_to_group(
+    db: Store,
+    group: &str,
+    calendars: &[String],
+    email: Option<&str>,
+) -> anyhow::Result<()> {
+    let all = db.execute(CalendarInfo::get).await?;
+    // All calendars are matched before any are added, so a typo doesn't add some of them.
+    let calendars = calendars
+        .iter()
+        .map(|c| match_calendar(&all, c, email))
+        .collect::<anyhow::Result<Vec<CalendarInfo>>>()?;
+
+    for cal in calendars.iter() {
+        let member = CalendarGroupModel {
+            name: group.to_owned(),
+            account_id: cal.account_id,
+            calendar_id: cal.id.to_owned(),
+        };
+        db.execute(move |conn| member.insert(conn)).await?;
+        println!(
+            "Added {} ({}) to {}.",
+            cal.name.bold().blue(),
+            cal.account,
+            group.bold()
+        );
+    }
 
-                for cal_id in selected_calendars {
-                    let token = access_token.clone();
-                    tasks.push(tokio::task::spawn(async move {
-                        let res = google::GoogleAPI::get_calendar_events(
-                            &token,
-                            &cal_id,
-                            finder.start,
-                            finder.end,
-                        )
-                        .await?;
-                        Ok(res)
-                    }));
-                }
-            }
-            _ => return Err(anyhow::anyhow!("Unsupported platform")),
+    Ok(())
+}
+
+pub async fn remove_from_group(
+    db: Store,
+    group: &str,
+    calendars: &[String],
+    email: Option<&str>,
+) -> anyhow::Result<()> {
+    if calendars.is_empty() {
+        let name = group.to_owned();
+        let removed = db
+            .execute(move |conn| CalendarGroupModel::delete_group(conn, &name))
+            .await?;
+        if removed == 0 {
+            return Err(anyhow::anyhow!("No group is named \"{}\"", group));
         }
+        println!("Removed the group {}.", group.bold());
+        return Ok(());
     }
 
-    let events: Vec<Event> = futures::future::join_all(tasks)
-        .await
-        .into_iter()
-        .filter_map(|r| r.ok())
-        .flat_map(Result::unwrap)
-        .collect();
-
-    pb.finish_with_message("Retrieved events.");
+    let all = db.execute(CalendarInfo::get).await?;
+    for c in calendars {
+        let cal = match_calendar(&all, c, email)?;
+        let member = CalendarGroupModel {
+            name: group.to_owned(),
+            account_id: cal.account_id,
+            calendar_id: cal.id.to_owned(),
+        };
+        if db.execute(move |conn| member.delete(conn)).await? {
+            println!(
+                "Removed {} ({}) from {}.",
+                cal.name.bold().blue(),
+                cal.account,
+                group.bold()
+            );
+        } else {
+            println!(
+                "{} ({}) isn't in {}.",
+                cal.name.bold().blue(),
+                cal.account,
+                group.bold()
+            );
+        }
+    }
 
-    let pb = m.add(ProgressBar::new(1));
-    pb.set_message("Computing availabilities...");
-    pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
+    Ok(())
+}
 
-    let availability = finder.get_availability(events)?;
-    let slots: Vec<Availability<Local>> = availability.into_iter().flat_map(|(_d, a)| a).collect();
+pub async fn list_groups(db: Store) -> anyhow::Result<()> {
+    let groups = db.execute(CalendarGroupModel::get_all).await?;
+    let calendars = db.execute(CalendarInfo::get).await?;
 
-    pb.finish_with_message("Computed availabilities.");
+    if groups.is_empty() {
+        println!("Calendar groups: None");
+        return Ok(());
+    }
 
-    if slots.is_empty() {
-        return Ok(vec![]);
+    println!("Calendar groups:");
+    for (name, members) in &groups.iter().group_by(|g| g.name.to_owned()) {
+        println!("- {}", name.bold().blue());
+        for member in members {
+            if let Some(cal) = calendars
+                .iter()
+                .find(|c| c.account_id == member.account_id && c.id == member.calendar_id)
+            {
+                println!("  - {} ({})", cal.name, cal.account);
+            }
+        }
     }
 
-    // TODO: add multi-level multiselect
-    // Right arrow goes into a time window (can select granular windows)
-    // Left arrow goes back to parent
-    // Needs to work with paging
-    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select time window(s)")
-        .items(&slots[..])
-        .interact()
-        .unwrap();
+    Ok(())
+}
 
-    let selected_slots = selection.into_iter().map(|i| slots.get(i).unwrap());
+pub fn print_and_copy_availability(
+    avails: &[Availability<Local>],
+    template: &Template,
+    locale: &Locale,
+    clipboard: ClipboardBackend,
+) -> anyhow::Result<()> {
+    let s = template.render(avails, locale)?;
+    print!("{}", s);
 
-    // (day, day_avails)
-    let days = selected_slots.group_by(|e| (e.start.date()));
+    if clipboard == ClipboardBackend::None {
+        return Ok(());
+    }
 
-    let mut iter = days.into_iter().peekable();
+    let content = if template.html {
+        ClipboardContent {
+            text: html_to_text(&s),
+            html: Some(s),
+        }
+    } else {
+        ClipboardContent {
+            text: s,
+            html: None,
+        }
+    };
 
-    let mut selected: Vec<Availability<Local>> = vec![];
+    // Copying is best effort, the availability has already been printed.
+    match clipboard.copy(&content) {
+        Ok(backend) if content.html.is_some() && !clipboard.copies_html() => println!(
+            "\nCopied to clipboard as plain text using {}, set the clipboard to wl-copy or xclip to copy HTML.",
+            backend
+        ),
+        Ok(backend) => println!("\nCopied to clipboard using {}.", backend),
+        Err(e) => println!(
+            "\n{}",
+            format!("Unable to copy to clipboard: {}", e).dimmed()
+        ),
+    }
+    Ok(())
+}
 
-    while iter.peek().is_some() {
-        let i = iter.next();
+/// Syncs the cached events of the selected calendars between start and end, returning
+/// the synced calendars.
+#[allow(clippy::too_many_arguments)]
+async fn sync_calendars(
+    db: &Store,
+    tokens: &Arc<TokenCache>,
+    accounts: Vec<AccountModel>,
+    selected: &[CalendarInfo],
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    refresh: bool,
+    m: &ProgressIndicator,
+) -> anyhow::Result<Vec<(u32, String)>> {
+    // Microsoft Graph has 4 concurrent requests limit
+    let semaphore = Arc::new(Semaphore::new(4));
+    let mut tasks: Vec<JoinHandle<anyhow::Result<()>>> = vec![];
+    let mut calendars: Vec<(u32, String)> = vec![];
+    let mut skipped = 0;
+    let num_accounts = accounts.len();
+
+    for account in accounts {
+        let platform = account.platform.unwrap();
+        if platform == Platform::Unsupported {
+            return Err(anyhow::anyhow!("Unsupported platform"));
+        }
+
+        // Skip accounts whose refresh token was revoked, the query continues with the others.
+        let reauth_required = account.needs_reauth
+            || match tokens.get(&account).await {
+                Ok(_) => false,
+                Err(e) if mark_if_reauth_required(db, &account, &e).await? => true,
+                Err(e) => return Err(e),
+            };
+        if reauth_required {
+            m.println(&reauth_warning(&account.name));
+            skipped += 1;
+            continue;
+        }
+
+        let account_id = account.id.unwrap().to_owned();
+        let selected_calendars = selected
+            .iter()
+            .filter(|c| c.account_id == account_id)
+            .map(|c| c.id.to_owned());
+
+        for cal_id in selected_calendars {
+            calendars.push((account_id, cal_id.to_owned()));
+
+            let calendar_id = cal_id.to_owned();
+            let prev_sync = if refresh {
+                None
+            } else {
+                db.execute(move |conn| SyncModel::get(conn, &account_id, &calendar_id))
+                    .await?
+            };
+
+            // Only sync incrementally if the cached events cover the search window.
+            let (window_start, window_end, sync_token) = match prev_sync {
+                Some(sync) if sync.window_start <= start && end <= sync.window_end => {
+                    (sync.window_start, sync.window_end, Some(sync.sync_token))
+                }
+                _ => (
+                    start,
+                    DateTime::max(end, start + Duration::weeks(CACHE_WEEKS)),
+                    None,
+                ),
+            };
+
+            let (db, tokens) = (db.clone(), tokens.clone());
+            let account = account.clone();
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("unable to acquire permit"); // Acquire a permit
+            tasks.push(tokio::task::spawn(async move {
+                let changes = tokens
+                    .with_token(&account, |token| {
+                        let (cal_id, sync_token) = (cal_id.to_owned(), sync_token.to_owned());
+                        async move {
+                            match platform {
+                                Platform::Microsoft => {
+                                    microsoft::MicrosoftGraph::sync_calendar_events(
+                                        &token,
+                                        &cal_id,
+                                        window_start,
+                                        window_end,
+                                        sync_token,
+                                    )
+                                    .await
+                                }
+                                _ => {
+                                    google::GoogleAPI::sync_calendar_events(
+                                        &token,
+                                        &cal_id,
+                                        window_start,
+                                        window_end,
+                                        sync_token,
+                                    )
+                                    .await
+                                }
+                            }
+                        }
+                    })
+                    .await?;
+                drop(permit);
+
+                let sync = SyncModel {
+                    account_id,
+                    calendar_id: cal_id,
+                    sync_token: changes.sync_token.to_owned(),
+                    window_start,
+                    window_end,
+                    last_synced: Local::now(),
+                };
+                // Saved while other calendars are still being retrieved.
+                save_changes(&db, sync, changes).await
+            }));
+        }
+    }
+
+    if skipped > 0 && skipped == num_accounts {
+        return Err(anyhow::anyhow!(
+            "All accounts must be re-authenticated before you are able to find availabilities."
+        ));
+    }
+
+    for res in futures::future::join_all(tasks).await {
+        res??;
+    }
+
+    Ok(calendars)
+}
+
+async fn save_changes(db: &Store, sync: SyncModel, changes: EventChanges) -> anyhow::Result<()> {
+    let events: Vec<EventModel> = changes
+        .events
+        .into_iter()
+        .map(|e| EventModel {
+            account_id: sync.account_id,
+            calendar_id: sync.calendar_id.to_owned(),
+            id: e.id,
+            name: e.name,
+            start: e.start,
+            end: e.end,
+            marker: e.marker,
+            show_as: e.show_as,
+        })
+        .collect();
+    let (full, deleted) = (changes.full, changes.deleted);
+
+    db.execute(move |conn| sync.save_changes(conn, full, events, deleted))
+        .await
+}
+
+/// Prints when the cached events of each selected calendar were last synced, returning
+/// the calendars that have cached events.
+async fn print_cache_staleness(
+    db: &Store,
+    accounts: Vec<AccountModel>,
+    selected: &[CalendarInfo],
+    finder: &AvailabilityFinder,
+    locale: &Locale,
+) -> anyhow::Result<Vec<(u32, String)>> {
+    let mut calendars: Vec<(u32, String)> = vec![];
+
+    println!("Using cached events (offline):");
+    for account in accounts {
+        let account_id = account.id.unwrap().to_owned();
+        let selected_calendars = selected.iter().filter(|c| c.account_id == account_id);
+
+        for cal in selected_calendars {
+            let calendar_id = cal.id.to_owned();
+            let sync = db
+                .execute(move |conn| SyncModel::get(conn, &account_id, &calendar_id))
+                .await?;
+
+            let status = match sync {
+                Some(sync) => {
+                    calendars.push((account_id, cal.id.to_owned()));
+
+                    let age = format!(
+                        "last synced {} {} ({})",
+                        locale.date(&sync.last_synced.date()),
+                        locale.time(&sync.last_synced),
+                        format_age(Local::now() - sync.last_synced)
+                    );
+                    if sync.window_start <= finder.start && finder.end <= sync.window_end {
+                        age
+                    } else {
+                        format!(
+                            "{}, {}",
+                            age,
+                            "doesn't cover the whole search window".yellow()
+                        )
+                    }
+                }
+                None => "never synced, skipping".red().to_string(),
+            };
+            println!(
+                "- {} ({}): {}",
+                cal.name.bold().blue(),
+                account.name,
+                status
+            );
+        }
+    }
+    println!();
+
+    if calendars.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No cached events are available, search without --offline first."
+        ));
+    }
+
+    Ok(calendars)
+}
+
+fn format_age(age: Duration) -> String {
+    if age.num_days() >= 1 {
+        format!("{} day(s) ago", age.num_days())
+    } else if age.num_hours() >= 1 {
+        format!("{} hour(s) ago", age.num_hours())
+    } else if age.num_minutes() >= 1 {
+        format!("{} minute(s) ago", age.num_minutes())
+    } else {
+        "just now".to_string()
+    }
+}
+
+/// Returns the calendars to query, the ones in the groups or with the names or ids if
+/// `calendars` is set and the selected ones otherwise.
+async fn resolve_calendars(
+    db: &Store,
+    calendars: Option<&[String]>,
+) -> anyhow::Result<Vec<CalendarInfo>> {
+    let all = db.execute(CalendarInfo::get).await?;
+    let names = match calendars {
+        Some(names) => names,
+        None => return Ok(all.into_iter().filter(|c| c.selected).collect()),
+    };
+    let groups = db.execute(CalendarGroupModel::get_all).await?;
+
+    let mut resolved: Vec<CalendarInfo> = vec![];
+    for name in names {
+        // Groups take precedence over calendars with the same name.
+        let members: Vec<CalendarInfo> = if groups.iter().any(|g| &g.name == name) {
+            all.iter()
+                .filter(|c| {
+                    groups.iter().any(|g| {
+                        &g.name == name && g.account_id == c.account_id && g.calendar_id == c.id
+                    })
+                })
+                .cloned()
+                .collect()
+        } else {
+            vec![match_calendar(&all, name, None)?]
+        };
+
+        for cal in members {
+            if !resolved
+                .iter()
+                .any(|c| c.account_id == cal.account_id && c.id == cal.id)
+            {
+                resolved.push(cal);
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+pub(crate) async fn find_availability(
+    db: &Store,
+    tokens: &Arc<TokenCache>,
+    finder: AvailabilityFinder,
+    calendars: Option<&[String]>,
+    locale: &Locale,
+    mode: CacheMode,
+    m: &ProgressIndicator,
+) -> anyhow::Result<Vec<Availability<Local>>> {
+    let mut accounts = db.execute(AccountModel::get).await?;
+
+    if accounts.is_empty() {
+        return Err(anyhow::anyhow!(format!(
+            "You must link accounts using the \"{}\" command and configure calendars using \"{}\" command before you are able to find availabilities.",
+            "accounts add".bold().italic(),
+            "calendars".bold().italic()
+        )));
+    }
+
+    // Accounts without any of the calendars aren't synced.
+    let selected = resolve_calendars(db, calendars).await?;
+    accounts.retain(|a| selected.iter().any(|c| a.id == Some(c.account_id)));
+
+    println!(
+        "Finding availability between {} and {}\n",
+        locale.date(&finder.start.date()).bold().blue(),
+        locale.date(&finder.end.date()).bold().blue()
+    );
+
+    let calendars = if mode == CacheMode::Offline {
+        print_cache_staleness(db, accounts, &selected, &finder, locale).await?
+    } else {
+        let pb = m.add(ProgressBar::new(1));
+        pb.set_message("Retrieving events...");
+        pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
+
+        let calendars = sync_calendars(
+            db,
+            tokens,
+            accounts,
+            &selected,
+            finder.start,
+            finder.end,
+            mode == CacheMode::Refresh,
+            m,
+        )
+        .await?;
+
+        pb.finish_with_message("Retrieved events.");
+        calendars
+    };
+
+    let mut events: Vec<Event> = vec![];
+    for (account_id, calendar_id) in calendars {
+        let cached = db
+            .execute(move |conn| {
+                EventModel::get_between(conn, &account_id, &calendar_id, finder.start, finder.end)
+            })
+            .await?;
+        events.extend(cached.into_iter().map(|e| Event {
+            id: e.id,
+            name: e.name,
+            start: e.start,
+            end: e.end,
+            marker: e.marker,
+            show_as: e.show_as,
+        }));
+    }
+
+    let pb = m.add(ProgressBar::new(1));
+    pb.set_message("Computing availabilities...");
+    pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
+
+    let availability = finder.get_availability(events)?;
+    let slots: Vec<Availability<Local>> = availability.into_iter().flat_map(|(_d, a)| a).collect();
+
+    pb.finish_with_message("Computed availabilities.");
+
+    if slots.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // TODO: add multi-level multiselect
+    // Right arrow goes into a time window (can select granular windows)
+    // Left arrow goes back to parent
+    // Needs to work with paging
+    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select time window(s)")
+        .items(&slots.iter().map(|s| s.format(locale)).collect_vec())
+        .interact()
+        .unwrap();
+
+    let selected_slots = selection.into_iter().map(|i| slots.get(i).unwrap());
+
+    // (day, day_avails)
+    let days = selected_slots.group_by(|e| e.start.date());
+
+    let mut iter = days.into_iter().peekable();
+
+    let mut selected: Vec<Availability<Local>> = vec![];
+
+    while iter.peek().is_some() {
+        let i = iter.next();
         let (day, avails) = i.unwrap();
 
         let day_slots: Vec<&Availability<Local>> = avails.into_iter().collect();
         let windows = split_availability(&day_slots, finder.duration);
 
         let selection = MultiSelect::with_theme(&ColorfulTheme::default())
-            .with_prompt(format!(
-                "Select time window(s) for {}",
-                day.format("%b %d %Y")
-            ))
-            .items(&windows[..])
+            .with_prompt(format!("Select time window(s) for {}", locale.date(&day)))
+            .items(&windows.iter().map(|w| w.format(locale)).collect_vec())
             .interact()
             .unwrap();
 
@@ -418,23 +1193,29 @@ pub(crate) async fn find_availability(
 
 pub(crate) async fn create_hold_events(
     db: Store,
-    cfg: &AvailConfig,
+    tokens: &Arc<TokenCache>,
     merged: &[Availability<Local>],
+    ttl: Option<Duration>,
+    cfg: &HoldConfig,
+    locale: &Locale,
     m: &ProgressIndicator,
 ) -> anyhow::Result<()> {
-    let accounts = db.execute(Box::new(AccountModel::get))??;
+    let accounts = db.execute(AccountModel::get).await?;
 
     let event_title: String = Input::with_theme(&ColorfulTheme::default())
         .with_prompt("What's the name of your event?")
         .interact_text()?;
 
-    let calendar = db.execute(Box::new(move |conn| {
-        CalendarModel::get_hold_event_calendar(conn)
-    }))??;
-
-    if calendar.is_none() {
+    let calendars: Vec<CalendarInfo> = db
+        .execute(CalendarInfo::get)
+        .await?
+        .into_iter()
+        .filter(|c| c.hold)
+        .collect();
+    if calendars.is_empty() {
         return Err(anyhow::anyhow!(
-            "No calendar is configured to be used for hold events."
+            "No calendar is configured to be used for hold events, add one with \"{}\".",
+            "calendars set-hold <calendar>".bold()
         ));
     }
 
@@ -442,89 +1223,1552 @@ pub(crate) async fn create_hold_events(
     pb.set_message("Creating hold events...");
     pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
 
-    let (platform, cal) = calendar.unwrap();
+    let query_id = db.execute(HoldModel::next_query_id).await?;
+    let title = format!("{}{}", cfg.title_prefix(), event_title);
+    let slots: Vec<String> = merged
+        .iter()
+        .map(|avail| format_slot(&avail.start, &avail.end, locale))
+        .collect();
+
+    let events: Vec<NewEvent> = merged
+        .iter()
+        .enumerate()
+        .map(|(i, avail)| {
+            let mut description: Vec<String> = cfg.description.iter().cloned().collect();
+            let others: Vec<&String> = slots
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, slot)| slot)
+                .collect();
+            if cfg.list_slots() && !others.is_empty() {
+                description.push(format!(
+                    "Other times held for {}:\n{}",
+                    event_title,
+                    others.iter().map(|slot| format!("- {}", slot)).join("\n")
+                ));
+            }
+            NewEvent {
+                title: title.to_owned(),
+                description: (!description.is_empty()).then(|| description.join("\n\n")),
+                start: avail.start,
+                end: avail.end,
+                show_as: cfg.show_as(),
+                private: cfg.private(),
+                reminders: cfg.reminders(),
+                category: cfg.category.to_owned(),
+                color: cfg.color.to_owned(),
+                marker: Some(marker(HOLD_MARKER, query_id)),
+            }
+        })
+        .collect();
 
     // Microsoft Graph has 4 concurrent requests limit
     let semaphore = Arc::new(Semaphore::new(4));
-    let mut tasks: Vec<JoinHandle<anyhow::Result<()>>> = vec![];
+    // Tasks are tagged with the index of their calendar so failures are reported per calendar.
+    let mut tasks: Vec<(usize, JoinHandle<anyhow::Result<()>>)> = vec![];
+    let mut errors: Vec<Option<anyhow::Error>> = calendars.iter().map(|_| None).collect();
+    let mut failed = vec![0; calendars.len()];
+
+    for (c, cal) in calendars.iter().enumerate() {
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(cal.account_id))
+            .unwrap();
 
-    let account_name = accounts
-        .iter()
-        .find(|a| a.id == cal.account_id)
-        .unwrap()
-        .name
-        .to_owned();
+        if account.needs_reauth {
+            errors[c] = Some(anyhow::anyhow!(reauth_error(&account.name)));
+            failed[c] = events.len();
+            continue;
+        }
+        if let Err(e) = tokens.get(account).await {
+            errors[c] = Some(if mark_if_reauth_required(&db, account, &e).await? {
+                anyhow::anyhow!(reauth_error(&account.name))
+            } else {
+                e
+            });
+            failed[c] = events.len();
+            continue;
+        }
+        let platform = account.platform.unwrap();
+
+        for event in events.iter() {
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("unable to acquire permit"); // Acquire a permit
+            let (calendar_id, event) = (cal.id.to_owned(), event.clone());
+
+            let (db, tokens) = (db.clone(), tokens.clone());
+            let account = account.clone();
+
+            let task = tokio::task::spawn(async move {
+                let res = tokens
+                    .with_token(&account, |token| {
+                        let (calendar_id, event) = (calendar_id.to_owned(), &event);
+                        async move { create_event(platform, &token, &calendar_id, event).await }
+                    })
+                    .await;
+                drop(permit);
+
+                let created_event = res?;
+                let created = Local::now();
+                let hold = HoldModel {
+                    id: None,
+                    account_id: account.id.unwrap(),
+                    calendar_id,
+                    event_id: created_event.id,
+                    title: event.title,
+                    start: event.start,
+                    end: event.end,
+                    created,
+                    query_id,
+                    expires: ttl.map(|ttl| created + ttl),
+                    etag: Some(created_event.etag),
+                };
+                db.execute(move |conn| hold.insert(conn)).await?;
+                Ok(())
+            });
+            tasks.push((c, task));
+        }
+    }
 
-    match Platform::from(&platform) {
-        Platform::Microsoft => {
-            for avail in merged.iter() {
-                let refresh_token = crate::store::get_token(&account_name)?;
-                let access_token = microsoft::refresh_access_token(
-                    &cfg.microsoft.to_owned().unwrap_or_default(),
-                    &refresh_token,
+    let (indices, handles): (Vec<usize>, Vec<_>) = tasks.into_iter().unzip();
+    for (c, res) in indices
+        .into_iter()
+        .zip(futures::future::join_all(handles).await)
+    {
+        if let Err(e) = res.map_err(anyhow::Error::from).and_then(|r| r) {
+            errors[c].get_or_insert(e);
+            failed[c] += 1;
+        }
+    }
+
+    let created = calendars.len() * events.len() - failed.iter().sum::<usize>();
+    if created == 0 {
+        pb.finish_and_clear();
+    } else {
+        pb.finish_with_message(format!(
+            "Created {} hold event(s), release them with \"{}\".",
+            created,
+            format!("holds release --query {}", query_id).bold()
+        ));
+    }
+
+    for ((cal, error), failed) in calendars.iter().zip(errors).zip(failed) {
+        if let Some(e) = error {
+            println!(
+                "{}",
+                format!(
+                    "Failed to create {} hold event(s) in {} ({}): {}",
+                    failed, cal.name, cal.account, e
                 )
-                .await?;
-                let permit = semaphore
-                    .clone()
-                    .acquire_owned()
-                    .await
-                    .expect("unable to acquire permit"); // Acquire a permit
-                let calendar_id = cal.id.to_owned();
-                let title = format!("HOLD - {}", event_title);
-                let start = avail.start;
-                let end = avail.end;
-
-                tasks.push(tokio::task::spawn(async move {
-                    let res = microsoft::MicrosoftGraph::create_event(
-                        &access_token,
-                        &calendar_id,
-                        &title,
-                        start,
-                        end,
-                    )
-                    .await;
-                    drop(permit);
-                    res?;
-                    Ok(())
-                }));
-            }
+                .red()
+            );
+        }
+    }
+
+    if created == 0 {
+        return Err(anyhow::anyhow!("Failed to create hold events"));
+    }
+
+    Ok(())
+}
+
+async fn create_event(
+    platform: Platform,
+    token: &str,
+    calendar_id: &str,
+    event: &NewEvent,
+) -> anyhow::Result<CreatedEvent> {
+    match platform {
+        Platform::Microsoft => {
+            microsoft::MicrosoftGraph::create_event(token, calendar_id, event).await
+        }
+        Platform::Google => google::GoogleAPI::create_event(token, calendar_id, event).await,
+        _ => Err(anyhow::anyhow!("Unsupported platform")),
+    }
+}
+
+async fn delete_event(
+    platform: Platform,
+    token: &str,
+    calendar_id: &str,
+    event_id: &str,
+) -> anyhow::Result<()> {
+    match platform {
+        Platform::Microsoft => {
+            microsoft::MicrosoftGraph::delete_event(token, calendar_id, event_id).await
+        }
+        Platform::Google => google::GoogleAPI::delete_event(token, calendar_id, event_id).await,
+        _ => Err(anyhow::anyhow!("Unsupported platform")),
+    }
+}
+
+async fn update_event(
+    platform: Platform,
+    token: &str,
+    calendar_id: &str,
+    event_id: &str,
+    update: &EventUpdate,
+) -> anyhow::Result<()> {
+    match platform {
+        Platform::Microsoft => {
+            microsoft::MicrosoftGraph::update_event(token, calendar_id, event_id, update).await
         }
         Platform::Google => {
-            for avail in merged.iter() {
-                let refresh_token = crate::store::get_token(&account_name)?;
-                let access_token = google::refresh_access_token(
-                    &cfg.google.to_owned().unwrap_or_default(),
-                    &refresh_token,
-                )
-                .await?;
+            google::GoogleAPI::update_event(token, calendar_id, event_id, update).await
+        }
+        _ => Err(anyhow::anyhow!("Unsupported platform")),
+    }
+}
 
-                let calendar_id = cal.id.to_owned();
-                let title = format!("HOLD - {}", event_title);
-                let start = avail.start;
-                let end = avail.end;
-
-                tasks.push(tokio::task::spawn(async move {
-                    google::GoogleAPI::create_event(
-                        &access_token,
-                        &calendar_id,
-                        &title,
-                        start,
-                        end,
-                    )
-                    .await?;
-                    Ok(())
-                }));
+async fn get_event_etag(
+    platform: Platform,
+    token: &str,
+    calendar_id: &str,
+    event_id: &str,
+) -> anyhow::Result<Option<String>> {
+    match platform {
+        Platform::Microsoft => {
+            microsoft::MicrosoftGraph::get_event_etag(token, calendar_id, event_id).await
+        }
+        Platform::Google => google::GoogleAPI::get_event_etag(token, calendar_id, event_id).await,
+        _ => Err(anyhow::anyhow!("Unsupported platform")),
+    }
+}
+
+async fn get_event_created(
+    platform: Platform,
+    token: &str,
+    calendar_id: &str,
+    event_id: &str,
+) -> anyhow::Result<Option<DateTime<Local>>> {
+    match platform {
+        Platform::Microsoft => {
+            microsoft::MicrosoftGraph::get_event_created(token, calendar_id, event_id).await
+        }
+        Platform::Google => {
+            google::GoogleAPI::get_event_created(token, calendar_id, event_id).await
+        }
+        _ => Err(anyhow::anyhow!("Unsupported platform")),
+    }
+}
+
+/// Which holds to release.
+pub enum HoldSelection {
+    Ids(Vec<u32>),
+    Query(u32),
+    All,
+}
+
+fn format_slot(start: &DateTime<Local>, end: &DateTime<Local>, locale: &Locale) -> String {
+    format!(
+        "{} {} to {}",
+        locale.date(&start.date()),
+        locale.time(start),
+        locale.time(end)
+    )
+}
+
+#[allow(clippy::unnecessary_map_or)]
+pub async fn list_holds(db: Store, query: Option<u32>, locale: &Locale) -> anyhow::Result<()> {
+    let holds: Vec<HoldModel> = db
+        .execute(HoldModel::get_all)
+        .await?
+        .into_iter()
+        .filter(|h| query.map_or(true, |q| h.query_id == q))
+        .collect();
+
+    if holds.is_empty() {
+        println!("Hold events: None");
+        return Ok(());
+    }
+
+    println!("Hold events:");
+    for hold in holds {
+        println!(
+            "- {} {}: {} (query {})",
+            format!("#{}", hold.id.unwrap()).bold(),
+            format_slot(&hold.start, &hold.end, locale).blue(),
+            hold.title,
+            hold.query_id
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn show_hold(db: Store, id: u32, locale: &Locale) -> anyhow::Result<()> {
+    let hold = db
+        .execute(HoldModel::get_all)
+        .await?
+        .into_iter()
+        .find(|h| h.id == Some(id))
+        .ok_or_else(|| anyhow::anyhow!("No hold event has the id {}", id))?;
+    let accounts = db.execute(AccountModel::get).await?;
+    let account = accounts
+        .iter()
+        .find(|a| a.id == Some(hold.account_id))
+        .unwrap();
+
+    println!("Hold #{}", id);
+    println!("Title:    {}", hold.title);
+    println!("Slot:     {}", format_slot(&hold.start, &hold.end, locale));
+    println!(
+        "Account:  {} on {}",
+        account.name,
+        account.platform.unwrap()
+    );
+    println!("Calendar: {}", hold.calendar_id);
+    println!("Event id: {}", hold.event_id);
+    println!(
+        "Created:  {} {}",
+        locale.date(&hold.created.date()),
+        locale.time(&hold.created)
+    );
+    println!("Query:    {}", hold.query_id);
+    if let Some(expires) = hold.expires {
+        println!(
+            "Expires:  {} {}",
+            locale.date(&expires.date()),
+            locale.time(&expires)
+        );
+    }
+
+    Ok(())
+}
+
+/// Deletes the holds from their calendars and stops tracking them, returning how many
+/// couldn't be deleted. Those are kept so releasing them can be retried.
+async fn release(
+    db: &Store,
+    tokens: &TokenCache,
+    holds: Vec<HoldModel>,
+    locale: &Locale,
+) -> anyhow::Result<usize> {
+    let accounts = db.execute(AccountModel::get).await?;
+    let mut failed = 0;
+
+    for hold in holds {
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(hold.account_id))
+            .unwrap();
+        let slot = format_slot(&hold.start, &hold.end, locale);
+
+        let res = if account.needs_reauth {
+            Err(anyhow::anyhow!(reauth_error(&account.name)))
+        } else {
+            let platform = account.platform.unwrap();
+            tokens
+                .with_token(account, |token| {
+                    let (calendar_id, event_id) =
+                        (hold.calendar_id.to_owned(), hold.event_id.to_owned());
+                    async move { delete_event(platform, &token, &calendar_id, &event_id).await }
+                })
+                .await
+        };
+
+        match res {
+            Ok(()) => {
+                let title = hold.title.to_owned();
+                db.execute(move |conn| hold.delete(conn)).await?;
+                println!("Released {} ({}).", title, slot);
+            }
+            Err(e) => {
+                if mark_if_reauth_required(db, account, &e).await? {
+                    println!("{}", reauth_error(&account.name).yellow());
+                } else {
+                    println!(
+                        "{}",
+                        format!("Unable to release {} ({}): {}", hold.title, slot, e).red()
+                    );
+                }
+                failed += 1;
             }
         }
-        _ => return Err(anyhow::anyhow!("Unsupported platform")),
     }
 
-    let res = futures::future::join_all(tasks).await;
-    if res.iter().any(|r| r.is_err()) {
-        return Err(anyhow::anyhow!("Failed to create hold events."));
+    Ok(failed)
+}
+
+fn release_error(failed: usize) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Failed to release {} hold event(s), they're still tracked so releasing them can be retried.",
+        failed
+    )
+}
+
+pub async fn release_holds(
+    db: Store,
+    tokens: &TokenCache,
+    selection: HoldSelection,
+    locale: &Locale,
+) -> anyhow::Result<()> {
+    let holds = db.execute(HoldModel::get_all).await?;
+    let holds: Vec<HoldModel> = match &selection {
+        HoldSelection::Ids(ids) => {
+            if let Some(id) = ids
+                .iter()
+                .find(|id| !holds.iter().any(|h| h.id == Some(**id)))
+            {
+                return Err(anyhow::anyhow!("No hold event has the id {}", id));
+            }
+            holds
+                .into_iter()
+                .filter(|h| ids.contains(&h.id.unwrap()))
+                .collect()
+        }
+        HoldSelection::Query(query) => holds.into_iter().filter(|h| h.query_id == *query).collect(),
+        HoldSelection::All => holds,
+    };
+
+    if holds.is_empty() {
+        println!("No hold events to release.");
+        return Ok(());
     }
 
-    pb.finish_with_message("Created hold events.");
+    let failed = release(&db, tokens, holds, locale).await?;
+    if failed > 0 {
+        return Err(release_error(failed));
+    }
 
     Ok(())
 }
+
+/// Turns the hold into the meeting by updating its event, then releases the other holds
+/// created by the same search. Holds of the same slot in other calendars are kept as busy.
+pub async fn confirm_hold(
+    db: Store,
+    tokens: &TokenCache,
+    id: u32,
+    update: EventUpdate,
+    cfg: &HoldConfig,
+    locale: &Locale,
+) -> anyhow::Result<()> {
+    let holds = db.execute(HoldModel::get_all).await?;
+    let hold = holds
+        .iter()
+        .find(|h| h.id == Some(id))
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No hold event has the id {}", id))?;
+    let accounts = db.execute(AccountModel::get).await?;
+    let account = accounts
+        .iter()
+        .find(|a| a.id == Some(hold.account_id))
+        .unwrap();
+    if account.needs_reauth {
+        return Err(anyhow::anyhow!(reauth_error(&account.name)));
+    }
+
+    // Holds are titled after the meeting, which is kept unless a new title is given.
+    let update = EventUpdate {
+        title: update.title.or_else(|| {
+            Some(
+                hold.title
+                    .strip_prefix(cfg.title_prefix())
+                    .unwrap_or(&hold.title)
+                    .to_string(),
+            )
+        }),
+        show_as: Some(ShowAs::Busy),
+        // Marked as a meeting so it isn't mistaken for an untracked hold.
+        marker: Some(marker(MEETING_MARKER, hold.query_id)),
+        ..update
+    };
+    let title = update.title.to_owned().unwrap();
+
+    let platform = account.platform.unwrap();
+    let res = tokens
+        .with_token(account, |token| {
+            let (calendar_id, event_id) = (hold.calendar_id.to_owned(), hold.event_id.to_owned());
+            let update = &update;
+            async move { update_event(platform, &token, &calendar_id, &event_id, update).await }
+        })
+        .await;
+    if let Err(e) = res {
+        mark_if_reauth_required(&db, account, &e).await?;
+        return Err(anyhow::anyhow!("Failed to confirm hold #{}: {}", id, e));
+    }
+
+    // It's a meeting now, so it's no longer tracked as a hold.
+    let slot = format_slot(&hold.start, &hold.end, locale);
+    let (query_id, start, end) = (hold.query_id, hold.start, hold.end);
+    let meeting_marker = marker(MEETING_MARKER, query_id);
+    set_cached_marker(&db, &hold, &meeting_marker).await?;
+    db.execute(move |conn| hold.delete(conn)).await?;
+    println!("Confirmed {} ({}).", title.bold(), slot);
+
+    // Holds of the same slot in other calendars keep blocking the time for the meeting,
+    // only its title is copied so invitations aren't sent twice.
+    let (mirrors, siblings): (Vec<HoldModel>, Vec<HoldModel>) = holds
+        .into_iter()
+        .filter(|h| h.query_id == query_id && h.id != Some(id))
+        .partition(|h| h.start == start && h.end == end);
+    let mirror_update = EventUpdate {
+        title: Some(title.to_owned()),
+        show_as: Some(ShowAs::Busy),
+        marker: Some(meeting_marker.to_owned()),
+        ..Default::default()
+    };
+    let mut failed = 0;
+
+    for mirror in mirrors {
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(mirror.account_id))
+            .unwrap();
+        let res = if account.needs_reauth {
+            Err(anyhow::anyhow!(reauth_error(&account.name)))
+        } else {
+            let platform = account.platform.unwrap();
+            tokens
+                .with_token(account, |token| {
+                    let (calendar_id, event_id) =
+                        (mirror.calendar_id.to_owned(), mirror.event_id.to_owned());
+                    let update = &mirror_update;
+                    async move {
+                        update_event(platform, &token, &calendar_id, &event_id, update).await
+                    }
+                })
+                .await
+        };
+
+        match res {
+            Ok(()) => {
+                let calendar_id = mirror.calendar_id.to_owned();
+                set_cached_marker(&db, &mirror, &meeting_marker).await?;
+                db.execute(move |conn| mirror.delete(conn)).await?;
+                println!(
+                    "Kept the hold in {} ({}) as busy.",
+                    calendar_id, account.name
+                );
+            }
+            Err(e) => {
+                mark_if_reauth_required(&db, account, &e).await?;
+                println!(
+                    "{}",
+                    format!(
+                        "Unable to update the hold in {} ({}): {}",
+                        mirror.calendar_id, account.name, e
+                    )
+                    .red()
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    failed += release(&db, tokens, siblings, locale).await?;
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to update or release {} hold event(s), they're still tracked so they can be released with \"{}\".",
+            failed,
+            format!("holds release --query {}", query_id).bold()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Updates the marker of the hold's cached event, until the calendar is synced again.
+async fn set_cached_marker(db: &Store, hold: &HoldModel, marker: &str) -> anyhow::Result<()> {
+    let (account_id, calendar_id, event_id, marker) = (
+        hold.account_id,
+        hold.calendar_id.to_owned(),
+        hold.event_id.to_owned(),
+        marker.to_owned(),
+    );
+    db.execute(move |conn| {
+        EventModel::set_marker(conn, &account_id, &calendar_id, &event_id, &marker)
+    })
+    .await
+}
+
+/// Finds upcoming holds in the hold calendars that avail created but no longer tracks, e.g.
+/// because creating them was interrupted or the database was reset, by their marker. Holds
+/// that were renamed are left alone as they were probably turned into a meeting.
+async fn find_untracked_holds(
+    db: &Store,
+    tokens: &Arc<TokenCache>,
+    cfg: &HoldConfig,
+) -> anyhow::Result<Vec<EventModel>> {
+    let calendars: Vec<CalendarInfo> = db
+        .execute(CalendarInfo::get)
+        .await?
+        .into_iter()
+        .filter(|c| c.hold)
+        .collect();
+    if calendars.is_empty() {
+        return Ok(vec![]);
+    }
+    let accounts: Vec<AccountModel> = db
+        .execute(AccountModel::get)
+        .await?
+        .into_iter()
+        .filter(|a| calendars.iter().any(|c| Some(c.account_id) == a.id))
+        .collect();
+
+    let now = Local::now();
+    let end = now + Duration::weeks(CACHE_WEEKS);
+    let m = ProgressIndicator::default();
+    let synced = sync_calendars(db, tokens, accounts, &calendars, now, end, false, &m).await?;
+
+    let holds = db.execute(HoldModel::get_all).await?;
+    let events = db
+        .execute(move |conn| EventModel::get_marked(conn, HOLD_MARKER, now))
+        .await?;
+
+    Ok(events
+        .into_iter()
+        .filter(|e| synced.contains(&(e.account_id, e.calendar_id.to_owned())))
+        .filter(|e| {
+            !holds.iter().any(|h| {
+                h.account_id == e.account_id && h.calendar_id == e.calendar_id && h.event_id == e.id
+            })
+        })
+        .filter(|e| {
+            e.name
+                .as_deref()
+                .is_some_and(|name| name.starts_with(cfg.title_prefix()))
+        })
+        .collect())
+}
+
+/// Releases the holds that expired, skipping the ones that were edited since they were
+/// created so meetings scheduled over a hold aren't deleted. Doesn't prompt so it can run
+/// from cron.
+///
+/// Untracked holds are only listed, unless `release_untracked` is set to the minimum age
+/// they must have to be released, since they may belong to another machine sharing the
+/// hold calendars.
+pub async fn gc_holds(
+    db: Store,
+    tokens: &Arc<TokenCache>,
+    dry_run: bool,
+    release_untracked: Option<Duration>,
+    cfg: &HoldConfig,
+    locale: &Locale,
+) -> anyhow::Result<()> {
+    let now = Local::now();
+    let holds = db
+        .execute(move |conn| HoldModel::get_expired(conn, now))
+        .await?;
+
+    let accounts = db.execute(AccountModel::get).await?;
+    let (mut released, mut skipped, mut failed) = (0, 0, 0);
+
+    for hold in holds {
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(hold.account_id))
+            .unwrap();
+        let slot = format_slot(&hold.start, &hold.end, locale);
+        if account.needs_reauth {
+            println!("{}", reauth_error(&account.name).yellow());
+            failed += 1;
+            continue;
+        }
+
+        let platform = account.platform.unwrap();
+        let res = tokens
+            .with_token(account, |token| {
+                let (calendar_id, event_id, created_etag) = (
+                    hold.calendar_id.to_owned(),
+                    hold.event_id.to_owned(),
+                    hold.etag.to_owned(),
+                );
+                async move {
+                    let etag = get_event_etag(platform, &token, &calendar_id, &event_id).await?;
+                    // Holds from before etags were tracked are assumed to be unedited.
+                    let edited = match (&etag, &created_etag) {
+                        (Some(current), Some(created)) => current != created,
+                        _ => false,
+                    };
+                    if etag.is_some() && !edited && !dry_run {
+                        delete_event(platform, &token, &calendar_id, &event_id).await?;
+                    }
+                    Ok(edited)
+                }
+            })
+            .await;
+
+        match res {
+            Ok(true) => {
+                println!("Skipped {} ({}), it was edited.", hold.title, slot);
+                skipped += 1;
+            }
+            Ok(false) if dry_run => {
+                println!("Would release {} ({}).", hold.title, slot);
+                released += 1;
+            }
+            Ok(false) => {
+                let title = hold.title.to_owned();
+                db.execute(move |conn| hold.delete(conn)).await?;
+                println!("Released {} ({}).", title, slot);
+                released += 1;
+            }
+            Err(e) => {
+                if mark_if_reauth_required(&db, account, &e).await? {
+                    println!("{}", reauth_error(&account.name).yellow());
+                } else {
+                    println!(
+                        "{}",
+                        format!("Unable to release {} ({}): {}", hold.title, slot, e).red()
+                    );
+                }
+                failed += 1;
+            }
+        }
+    }
+
+    let untracked = match find_untracked_holds(&db, tokens, cfg).await {
+        Ok(untracked) => untracked,
+        Err(e) => {
+            println!(
+                "{}",
+                format!("Unable to look for untracked hold events: {}", e).yellow()
+            );
+            vec![]
+        }
+    };
+    let (mut released_untracked, mut kept_untracked) = (0, 0);
+
+    for event in untracked {
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(event.account_id))
+            .unwrap();
+        let title = event.name.to_owned().unwrap_or_default();
+        let slot = format_slot(&event.start, &event.end, locale);
+        let min_age = match release_untracked {
+            Some(min_age) => min_age,
+            None => {
+                println!("Found untracked {} ({}).", title, slot);
+                kept_untracked += 1;
+                continue;
+            }
+        };
+        if account.needs_reauth {
+            println!("{}", reauth_error(&account.name).yellow());
+            failed += 1;
+            continue;
+        }
+
+        let platform = account.platform.unwrap();
+        let res = tokens
+            .with_token(account, |token| {
+                let (calendar_id, event_id) = (event.calendar_id.to_owned(), event.id.to_owned());
+                async move {
+                    let created =
+                        get_event_created(platform, &token, &calendar_id, &event_id).await?;
+                    // Recent holds may belong to a search that's still running elsewhere,
+                    // deleted ones are released as deleting them again succeeds.
+                    let old_enough = match created {
+                        Some(created) => created <= Local::now() - min_age,
+                        None => true,
+                    };
+                    if old_enough && !dry_run {
+                        delete_event(platform, &token, &calendar_id, &event_id).await?;
+                    }
+                    Ok(old_enough)
+                }
+            })
+            .await;
+
+        match res {
+            Ok(false) => {
+                println!("Kept untracked {} ({}), it's too recent.", title, slot);
+                kept_untracked += 1;
+            }
+            Ok(true) if dry_run => {
+                println!("Would release untracked {} ({}).", title, slot);
+                released_untracked += 1;
+            }
+            Ok(true) => {
+                db.execute(move |conn| {
+                    EventModel::delete_many(
+                        conn,
+                        &event.account_id,
+                        &event.calendar_id,
+                        vec![event.id],
+                    )
+                })
+                .await?;
+                println!("Released untracked {} ({}).", title, slot);
+                released_untracked += 1;
+            }
+            Err(e) => {
+                if mark_if_reauth_required(&db, account, &e).await? {
+                    println!("{}", reauth_error(&account.name).yellow());
+                } else {
+                    println!(
+                        "{}",
+                        format!("Unable to release untracked {} ({}): {}", title, slot, e).red()
+                    );
+                }
+                failed += 1;
+            }
+        }
+    }
+
+    if released + skipped + released_untracked + kept_untracked + failed == 0 {
+        println!("No expired hold events.");
+        return Ok(());
+    }
+
+    println!(
+        "{} {} expired and {} untracked hold event(s), skipped {} that were edited.",
+        if dry_run { "Would release" } else { "Released" },
+        released,
+        released_untracked,
+        skipped
+    );
+    if kept_untracked > 0 && release_untracked.is_none() {
+        println!(
+            "Kept {} untracked hold event(s), pass {} to release the ones older than a day.",
+            kept_untracked,
+            "--release-untracked".bold()
+        );
+    }
+    if failed > 0 {
+        return Err(release_error(failed));
+    }
+
+    Ok(())
+}
+
+/// Finds the source and target calendars of a sync pair.
+async fn find_sync_pair(
+    db: &Store,
+    source: &str,
+    source_account: Option<&str>,
+    target: &str,
+    target_account: Option<&str>,
+) -> anyhow::Result<(CalendarInfo, CalendarInfo)> {
+    let calendars = db.execute(CalendarInfo::get).await?;
+    let source = match_calendar(&calendars, source, source_account)?;
+    let target = match_calendar(&calendars, target, target_account)?;
+    if source.account_id == target.account_id && source.id == target.id {
+        return Err(anyhow::anyhow!(
+            "A calendar can't be synced to itself, choose another target"
+        ));
+    }
+    Ok((source, target))
+}
+
+pub async fn add_sync_pair(
+    db: Store,
+    source: &str,
+    source_account: Option<&str>,
+    target: &str,
+    target_account: Option<&str>,
+) -> anyhow::Result<()> {
+    let (source, target) =
+        find_sync_pair(&db, source, source_account, target, target_account).await?;
+
+    let pair = SyncPairModel {
+        id: None,
+        source_account_id: source.account_id,
+        source_calendar_id: source.id.to_owned(),
+        target_account_id: target.account_id,
+        target_calendar_id: target.id.to_owned(),
+    };
+    let inserted = db.execute(move |conn| pair.insert(conn)).await?;
+    println!(
+        "{} {} ({}) to {} ({}), run \"{}\" to create busy blocks.",
+        if inserted {
+            "Syncing"
+        } else {
+            "Already syncing"
+        },
+        source.name.bold().blue(),
+        source.account,
+        target.name.bold().blue(),
+        target.account,
+        "sync run".bold()
+    );
+
+    Ok(())
+}
+
+/// Stops syncing the pair, deleting its busy blocks from the target calendar.
+pub async fn remove_sync_pair(
+    db: Store,
+    tokens: &TokenCache,
+    source: &str,
+    source_account: Option<&str>,
+    target: &str,
+    target_account: Option<&str>,
+) -> anyhow::Result<()> {
+    let (source, target) =
+        find_sync_pair(&db, source, source_account, target, target_account).await?;
+    let pair = db
+        .execute(SyncPairModel::get_all)
+        .await?
+        .into_iter()
+        .find(|p| {
+            p.source_account_id == source.account_id
+                && p.source_calendar_id == source.id
+                && p.target_account_id == target.account_id
+                && p.target_calendar_id == target.id
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} ({}) isn't synced to {} ({})",
+                source.name,
+                source.account,
+                target.name,
+                target.account
+            )
+        })?;
+
+    if let Err(e) = delete_busy_blocks(&db, tokens, &pair).await {
+        return Err(anyhow::anyhow!(
+            "Failed to delete the busy blocks in {} ({}), the pair is kept so removing it can be retried: {}",
+            target.name,
+            target.account,
+            e
+        ));
+    }
+
+    db.execute(move |conn| pair.delete(conn)).await?;
+    println!(
+        "Stopped syncing {} ({}) to {} ({}).",
+        source.name.bold().blue(),
+        source.account,
+        target.name.bold().blue(),
+        target.account
+    );
+
+    Ok(())
+}
+
+/// Deletes the busy blocks of the pair from its target calendar, each block is no longer
+/// tracked once it's deleted.
+async fn delete_busy_blocks(
+    db: &Store,
+    tokens: &TokenCache,
+    pair: &SyncPairModel,
+) -> anyhow::Result<()> {
+    let blocks: Vec<BusyBlockModel> = db
+        .execute(BusyBlockModel::get_all)
+        .await?
+        .into_iter()
+        .filter(|b| Some(b.pair_id) == pair.id)
+        .collect();
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let accounts = db.execute(AccountModel::get).await?;
+    let account = accounts
+        .iter()
+        .find(|a| a.id == Some(pair.target_account_id))
+        .unwrap();
+    if account.needs_reauth {
+        return Err(anyhow::anyhow!(reauth_error(&account.name)));
+    }
+    let platform = account.platform.unwrap();
+
+    for block in blocks {
+        let res = tokens
+            .with_token(account, |token| {
+                let (calendar_id, event_id) = (
+                    pair.target_calendar_id.to_owned(),
+                    block.target_event_id.to_owned(),
+                );
+                async move { delete_event(platform, &token, &calendar_id, &event_id).await }
+            })
+            .await;
+        if let Err(e) = res {
+            mark_if_reauth_required(db, account, &e).await?;
+            return Err(e);
+        }
+        db.execute(move |conn| block.delete(conn)).await?;
+    }
+
+    Ok(())
+}
+
+/// Deletes the busy blocks of the pairs whose source or target calendar is `removed`, before
+/// removing the calendar drops the pairs along with the blocks they track.
+async fn delete_busy_blocks_of(
+    db: &Store,
+    tokens: &TokenCache,
+    removed: impl Fn(u32, &str) -> bool,
+) -> anyhow::Result<()> {
+    let pairs: Vec<SyncPairModel> = db
+        .execute(SyncPairModel::get_all)
+        .await?
+        .into_iter()
+        .filter(|p| {
+            removed(p.source_account_id, &p.source_calendar_id)
+                || removed(p.target_account_id, &p.target_calendar_id)
+        })
+        .collect();
+
+    for pair in pairs.iter() {
+        delete_busy_blocks(db, tokens, pair).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn list_sync_pairs(db: Store) -> anyhow::Result<()> {
+    let pairs = db.execute(SyncPairModel::get_all).await?;
+    if pairs.is_empty() {
+        println!("Synced calendars: None");
+        return Ok(());
+    }
+
+    let calendars = db.execute(CalendarInfo::get).await?;
+    let blocks = db.execute(BusyBlockModel::get_all).await?;
+    let describe = |account_id: u32, id: &str| {
+        let cal = calendars
+            .iter()
+            .find(|c| c.account_id == account_id && c.id == id)
+            .unwrap();
+        format!("{} ({})", cal.name.bold().blue(), cal.account)
+    };
+
+    println!("Synced calendars:");
+    for pair in pairs {
+        println!(
+            "- {} -> {}: {} busy block(s)",
+            describe(pair.source_account_id, &pair.source_calendar_id),
+            describe(pair.target_account_id, &pair.target_calendar_id),
+            blocks.iter().filter(|b| Some(b.pair_id) == pair.id).count()
+        );
+    }
+
+    Ok(())
+}
+
+/// The private event that blocks the time of a source event in the target calendar.
+fn busy_block(event: &EventModel, pair_id: u32) -> NewEvent {
+    NewEvent {
+        title: "Busy".to_string(),
+        description: None,
+        start: event.start,
+        end: event.end,
+        show_as: ShowAs::Busy,
+        private: true,
+        reminders: false,
+        category: None,
+        color: None,
+        marker: Some(marker(BUSY_MARKER, pair_id)),
+    }
+}
+
+/// A change to a pair's busy blocks.
+enum BlockChange {
+    Create(EventModel),
+    Update(BusyBlockModel, EventModel),
+    Delete(BusyBlockModel),
+}
+
+/// Compares the source events with the busy blocks created for them. Blocks of events
+/// that ended are no longer tracked without being deleted, and blocks after `end` are kept
+/// since their events weren't retrieved.
+///
+/// Blocks missing from `target_ids`, the events of the target calendar between `start` and
+/// `end`, were deleted there and are created again.
+fn diff_busy_blocks(
+    events: Vec<EventModel>,
+    blocks: Vec<BusyBlockModel>,
+    target_ids: Option<&[String]>,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+) -> (Vec<BlockChange>, Vec<BusyBlockModel>) {
+    let mut changes = vec![];
+    let mut ended = vec![];
+
+    for block in blocks.iter() {
+        if events.iter().any(|e| e.id == block.source_event_id) {
+            continue;
+        }
+        if block.end <= start {
+            ended.push(block.clone());
+        } else if block.start < end {
+            changes.push(BlockChange::Delete(block.clone()));
+        }
+    }
+
+    for event in events {
+        match blocks.iter().find(|b| b.source_event_id == event.id) {
+            None => changes.push(BlockChange::Create(event)),
+            Some(block) if block.start != event.start || block.end != event.end => {
+                changes.push(BlockChange::Update(block.clone(), event))
+            }
+            Some(block) if target_ids.is_some_and(|ids| !ids.contains(&block.target_event_id)) => {
+                changes.push(BlockChange::Create(event))
+            }
+            Some(_) => {}
+        }
+    }
+
+    (changes, ended)
+}
+
+/// Mirrors the events of each source calendar between now and `window` from now as busy
+/// blocks in its target calendar, keeping track of the blocks so running it again only
+/// applies what changed.
+pub async fn sync_busy_blocks(
+    db: Store,
+    tokens: &Arc<TokenCache>,
+    window: Duration,
+    m: &ProgressIndicator,
+) -> anyhow::Result<()> {
+    let pairs = db.execute(SyncPairModel::get_all).await?;
+    if pairs.is_empty() {
+        println!(
+            "No calendars are synced, add one with \"{}\".",
+            "sync add <source> <target>".bold()
+        );
+        return Ok(());
+    }
+
+    let start = Local::now();
+    let end = start + window;
+    let calendars = db.execute(CalendarInfo::get).await?;
+    // Target calendars are synced too, to find the busy blocks that were deleted from them.
+    let paired: Vec<CalendarInfo> = calendars
+        .iter()
+        .filter(|c| {
+            pairs.iter().any(|p| {
+                (p.source_account_id == c.account_id && p.source_calendar_id == c.id)
+                    || (p.target_account_id == c.account_id && p.target_calendar_id == c.id)
+            })
+        })
+        .cloned()
+        .collect();
+    let paired_accounts: Vec<AccountModel> = db
+        .execute(AccountModel::get)
+        .await?
+        .into_iter()
+        .filter(|a| paired.iter().any(|c| Some(c.account_id) == a.id))
+        .collect();
+
+    let pb = m.add(ProgressBar::new(1));
+    pb.set_message("Retrieving events...");
+    pb.enable_steady_tick(Duration::milliseconds(250).to_std().unwrap());
+    let synced = sync_calendars(&db, tokens, paired_accounts, &paired, start, end, false, m).await;
+    pb.finish_and_clear();
+    let synced = synced?;
+
+    let accounts = db.execute(AccountModel::get).await?;
+    let blocks = db.execute(BusyBlockModel::get_all).await?;
+    let mut failed = 0;
+
+    for pair in pairs.iter() {
+        let pair_id = pair.id.unwrap();
+        let describe = |account_id: u32, id: &str| {
+            let cal = calendars
+                .iter()
+                .find(|c| c.account_id == account_id && c.id == id)
+                .unwrap();
+            format!("{} ({})", cal.name, cal.account)
+        };
+        let name = format!(
+            "{} -> {}",
+            describe(pair.source_account_id, &pair.source_calendar_id),
+            describe(pair.target_account_id, &pair.target_calendar_id)
+        );
+
+        // Stale events of sources that couldn't be retrieved aren't mirrored.
+        if !synced
+            .iter()
+            .any(|(a, c)| *a == pair.source_account_id && *c == pair.source_calendar_id)
+        {
+            println!("{}", format!("Skipped {}.", name).yellow());
+            failed += 1;
+            continue;
+        }
+        let account = accounts
+            .iter()
+            .find(|a| a.id == Some(pair.target_account_id))
+            .unwrap();
+        if account.needs_reauth {
+            println!("{}", reauth_error(&account.name).yellow());
+            failed += 1;
+            continue;
+        }
+        let platform = account.platform.unwrap();
+
+        // Holds and busy blocks created by avail aren't mirrored, so pairs in both directions
+        // don't copy each other's blocks. Neither are events shown as free, e.g. declined ones.
+        let (account_id, calendar_id) =
+            (pair.source_account_id, pair.source_calendar_id.to_owned());
+        let events: Vec<EventModel> = db
+            .execute(move |conn| {
+                EventModel::get_between(conn, &account_id, &calendar_id, start, end)
+            })
+            .await?
+            .into_iter()
+            .filter(|e| {
+                !is_marker(e.marker.as_deref(), HOLD_MARKER)
+                    && !is_marker(e.marker.as_deref(), BUSY_MARKER)
+                    && e.show_as != ShowAs::Free
+            })
+            .collect();
+        let pair_blocks: Vec<BusyBlockModel> = blocks
+            .iter()
+            .filter(|b| b.pair_id == pair_id)
+            .cloned()
+            .collect();
+
+        // Whether the blocks still exist can only be checked if the target was retrieved.
+        let target_ids: Option<Vec<String>> = if synced
+            .iter()
+            .any(|(a, c)| *a == pair.target_account_id && *c == pair.target_calendar_id)
+        {
+            let (account_id, calendar_id) =
+                (pair.target_account_id, pair.target_calendar_id.to_owned());
+            let events = db
+                .execute(move |conn| {
+                    EventModel::get_between(conn, &account_id, &calendar_id, start, end)
+                })
+                .await?;
+            Some(events.into_iter().map(|e| e.id).collect())
+        } else {
+            None
+        };
+
+        let (changes, ended) =
+            diff_busy_blocks(events, pair_blocks, target_ids.as_deref(), start, end);
+        for block in ended {
+            db.execute(move |conn| block.delete(conn)).await?;
+        }
+
+        let (mut created, mut updated, mut deleted) = (0, 0, 0);
+        for change in changes {
+            let calendar_id = pair.target_calendar_id.to_owned();
+            let res = tokens
+                .with_token(account, |token| {
+                    let calendar_id = calendar_id.to_owned();
+                    let change = &change;
+                    async move {
+                        match change {
+                            BlockChange::Create(event) => {
+                                let event = busy_block(event, pair_id);
+                                let created =
+                                    create_event(platform, &token, &calendar_id, &event).await?;
+                                Ok(Some(created.id))
+                            }
+                            BlockChange::Update(block, event) => {
+                                let update = EventUpdate {
+                                    start: Some(event.start),
+                                    end: Some(event.end),
+                                    ..Default::default()
+                                };
+                                let res = update_event(
+                                    platform,
+                                    &token,
+                                    &calendar_id,
+                                    &block.target_event_id,
+                                    &update,
+                                )
+                                .await;
+                                match res {
+                                    Ok(()) => Ok(None),
+                                    // The block was deleted from the target calendar, so it's
+                                    // created again.
+                                    Err(e) if e.is::<NotFound>() => {
+                                        let event = busy_block(event, pair_id);
+                                        let created =
+                                            create_event(platform, &token, &calendar_id, &event)
+                                                .await?;
+                                        Ok(Some(created.id))
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            }
+                            BlockChange::Delete(block) => {
+                                delete_event(
+                                    platform,
+                                    &token,
+                                    &calendar_id,
+                                    &block.target_event_id,
+                                )
+                                .await?;
+                                Ok(None)
+                            }
+                        }
+                    }
+                })
+                .await;
+
+            match (res, change) {
+                (Ok(target_event_id), BlockChange::Create(event)) => {
+                    let block = BusyBlockModel {
+                        pair_id,
+                        source_event_id: event.id,
+                        target_event_id: target_event_id.unwrap(),
+                        start: event.start,
+                        end: event.end,
+                    };
+                    db.execute(move |conn| block.upsert(conn)).await?;
+                    created += 1;
+                }
+                (Ok(target_event_id), BlockChange::Update(block, event)) => {
+                    let block = BusyBlockModel {
+                        target_event_id: target_event_id.unwrap_or(block.target_event_id),
+                        start: event.start,
+                        end: event.end,
+                        ..block
+                    };
+                    db.execute(move |conn| block.upsert(conn)).await?;
+                    updated += 1;
+                }
+                (Ok(_), BlockChange::Delete(block)) => {
+                    db.execute(move |conn| block.delete(conn)).await?;
+                    deleted += 1;
+                }
+                (Err(e), _) => {
+                    if mark_if_reauth_required(&db, account, &e).await? {
+                        println!("{}", reauth_error(&account.name).yellow());
+                    } else {
+                        println!("{}", format!("Unable to sync {}: {}", name, e).red());
+                    }
+                    failed += 1;
+                }
+            }
+        }
+
+        println!(
+            "Synced {}: {} created, {} updated, {} deleted.",
+            name.bold(),
+            created,
+            updated,
+            deleted
+        );
+    }
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to sync {} busy block(s) or calendar(s), run \"{}\" again to retry.",
+            failed,
+            "sync run".bold()
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn clear_cache(db: Store) -> anyhow::Result<()> {
+    db.execute(EventModel::delete_all).await?;
+    println!("Cleared cached events.");
+    Ok(())
+}
+
+pub async fn cache_stats(db: Store, locale: &Locale) -> anyhow::Result<()> {
+    let stats = db.execute(CacheStats::get).await?;
+
+    if stats.is_empty() {
+        println!("Cached events: None");
+        return Ok(());
+    }
+
+    println!("Cached events:");
+    for stat in stats {
+        let last_synced = match stat.last_synced {
+            Some(t) => format!("last synced {} {}", locale.date(&t.date()), locale.time(&t)),
+            None => "never synced".to_string(),
+        };
+        println!(
+            "- {} ({}): {} events, {}",
+            stat.calendar.bold().blue(),
+            stat.account,
+            stat.events,
+            last_synced
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn db_status(db: Store) -> anyhow::Result<()> {
+    let version = db.execute(migrations::current_version).await?;
+    let pending = db.execute(migrations::pending).await?;
+
+    println!("Database: {}", db.path());
+    println!(
+        "Schema version: {} (latest {})",
+        version,
+        migrations::latest_version()
+    );
+
+    if pending.is_empty() {
+        println!("No pending migrations.");
+        return Ok(());
+    }
+
+    println!("Pending migrations:");
+    for m in pending {
+        println!("- {}: {}", m.version, m.description);
+    }
+    Ok(())
+}
+
+pub async fn migrate_db(db: Store, dry_run: bool) -> anyhow::Result<()> {
+    if dry_run {
+        let pending = db.execute(migrations::pending).await?;
+        if pending.is_empty() {
+            println!("No pending migrations.");
+            return Ok(());
+        }
+
+        println!("Would apply:");
+        for m in pending {
+            println!("- {}: {}", m.version, m.description);
+        }
+        if let Some(backup) = db.backup_path().await? {
+            println!("The database would be backed up to {}", backup);
+        }
+        return Ok(());
+    }
+
+    let (applied, backup) = db.migrate().await?;
+    if applied.is_empty() {
+        println!("No pending migrations.");
+        return Ok(());
+    }
+
+    if let Some(backup) = backup {
+        println!("Backed up the database to {}", backup);
+    }
+    println!("Applied:");
+    for m in applied {
+        println!("- {}: {}", m.version, m.description);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: &str, start: DateTime<Local>, hours: i64) -> EventModel {
+        EventModel {
+            account_id: 1,
+            calendar_id: "source".to_string(),
+            id: id.to_string(),
+            name: None,
+            start,
+            end: start + Duration::hours(hours),
+            marker: None,
+            show_as: ShowAs::Busy,
+        }
+    }
+
+    fn block(source_event_id: &str, start: DateTime<Local>, hours: i64) -> BusyBlockModel {
+        BusyBlockModel {
+            pair_id: 1,
+            source_event_id: source_event_id.to_string(),
+            target_event_id: format!("busy-{}", source_event_id),
+            start,
+            end: start + Duration::hours(hours),
+        }
+    }
+
+    #[test]
+    fn test_diff_busy_blocks() {
+        let start = Local.ymd(2022, 9, 5).and_hms(9, 0, 0);
+        let end = start + Duration::days(7);
+
+        let events = vec![
+            event("new", start + Duration::hours(1), 1),
+            event("moved", start + Duration::hours(3), 2),
+            event("same", start + Duration::hours(6), 1),
+        ];
+        let blocks = vec![
+            block("moved", start + Duration::hours(2), 2),
+            block("same", start + Duration::hours(6), 1),
+            block("deleted", start + Duration::days(1), 1),
+            block("ended", start - Duration::hours(2), 1),
+            block("later", end + Duration::days(1), 1),
+        ];
+
+        let (changes, ended) = diff_busy_blocks(events, blocks, None, start, end);
+
+        let changes: Vec<(&str, &str)> = changes
+            .iter()
+            .map(|c| match c {
+                BlockChange::Create(e) => ("create", e.id.as_str()),
+                BlockChange::Update(b, e) => {
+                    assert_eq!(b.start, start + Duration::hours(2));
+                    assert_eq!(e.start, start + Duration::hours(3));
+                    ("update", b.source_event_id.as_str())
+                }
+                BlockChange::Delete(b) => ("delete", b.source_event_id.as_str()),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("delete", "deleted"),
+                ("create", "new"),
+                ("update", "moved")
+            ]
+        );
+        let ended: Vec<&str> = ended.iter().map(|b| b.source_event_id.as_str()).collect();
+        assert_eq!(ended, vec!["ended"]);
+    }
+
+    #[test]
+    fn test_diff_busy_blocks_unchanged() {
+        let start = Local.ymd(2022, 9, 5).and_hms(9, 0, 0);
+        let end = start + Duration::days(7);
+
+        let events = vec![
+            event("a", start, 1),
+            event("b", start + Duration::days(2), 3),
+        ];
+        let blocks = vec![
+            block("a", start, 1),
+            block("b", start + Duration::days(2), 3),
+        ];
+        let target_ids = vec!["busy-a".to_string(), "busy-b".to_string()];
+
+        let (changes, ended) =
+            diff_busy_blocks(events, blocks, Some(target_ids.as_slice()), start, end);
+        assert!(changes.is_empty());
+        assert!(ended.is_empty());
+    }
+
+    #[test]
+    fn test_diff_busy_blocks_deleted_from_target() {
+        let start = Local.ymd(2022, 9, 5).and_hms(9, 0, 0);
+        let end = start + Duration::days(7);
+
+        let events = || {
+            vec![
+                event("a", start, 1),
+                event("b", start + Duration::days(2), 3),
+            ]
+        };
+        let blocks = vec![
+            block("a", start, 1),
+            block("b", start + Duration::days(2), 3),
+        ];
+        let target_ids = vec!["busy-b".to_string()];
+
+        // The source is unchanged but the block of "a" was deleted from the target.
+        let (changes, _) = diff_busy_blocks(
+            events(),
+            blocks.clone(),
+            Some(target_ids.as_slice()),
+            start,
+            end,
+        );
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(&changes[0], BlockChange::Create(e) if e.id == "a"));
+
+        // Without the target's events the blocks are assumed to exist.
+        let (changes, _) = diff_busy_blocks(events(), blocks, None, start, end);
+        assert!(changes.is_empty());
+    }
+}
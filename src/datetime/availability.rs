@@ -1,8 +1,9 @@
 use chrono::{prelude::*, Duration};
-use itertools::Itertools;
 
 use std::fmt::Write as _;
 
+use super::locale::Locale;
+
 #[derive(Clone, Copy, Debug)]
 pub struct Availability<T: TimeZone>
 where
@@ -18,31 +19,31 @@ impl PartialEq for Availability<Local> {
     }
 }
 
-impl std::fmt::Display for Availability<Local> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let duration = self.end - self.start;
+impl Availability<Local> {
+    pub fn format(&self, locale: &Locale) -> String {
+        format!(
+            "{} - {} to {} ({})",
+            locale.short_day(&self.start.date()),
+            locale.time(&self.start),
+            locale.time(&self.end),
+            format_duration(self.end - self.start)
+        )
+    }
+}
 
-        let mut duration_str = String::new();
+pub fn format_duration(duration: Duration) -> String {
+    let mut duration_str = String::new();
 
-        if duration.num_hours() >= 1 {
-            let _ = write!(duration_str, "{}h", duration.num_hours());
-            if duration.num_minutes() % 60 >= 1 {
-                let _ = write!(duration_str, "{}m", duration.num_minutes() % 60);
-            }
-        } else if duration.num_minutes() >= 1 {
-            let _ = write!(duration_str, "{}m", duration.num_minutes());
+    if duration.num_hours() >= 1 {
+        let _ = write!(duration_str, "{}h", duration.num_hours());
+        if duration.num_minutes() % 60 >= 1 {
+            let _ = write!(duration_str, "{}m", duration.num_minutes() % 60);
         }
-
-        let day = self.start.format("%a %b %d");
-        write!(
-            f,
-            "{} - {} to {} ({})",
-            day,
-            self.start.format("%I:%M %p"),
-            self.end.format("%I:%M %p"),
-            duration_str
-        )
+    } else if duration.num_minutes() >= 1 {
+        let _ = write!(duration_str, "{}m", duration.num_minutes());
     }
+
+    duration_str
 }
 
 impl<T: TimeZone> Availability<T>
@@ -103,32 +104,6 @@ where
     res
 }
 
-pub fn format_availability(avails: &[Availability<Local>]) -> String {
-    let avail_days = avails.iter().group_by(|e| (e.start.date()));
-
-    let mut iter = avail_days.into_iter().peekable();
-
-    let mut s = String::new();
-
-    while iter.peek().is_some() {
-        let i = iter.next();
-        let (day, avails) = i.unwrap();
-
-        let _ = writeln!(s, "{}", day.format("%a %b %d %Y"));
-
-        for avail in avails {
-            let _ = writeln!(
-                s,
-                "- {} to {}",
-                avail.start.format("%I:%M %p"),
-                avail.end.format("%I:%M %p")
-            );
-        }
-    }
-
-    s
-}
-
 #[cfg(test)]
 mod tests {
     use chrono::DateTime;
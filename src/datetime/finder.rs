@@ -28,7 +28,7 @@ impl AvailabilityFinder {
 
         events.sort_by_key(|e| e.start);
 
-        let days = events.into_iter().group_by(|e| (e.start.date()));
+        let days = events.into_iter().group_by(|e| e.start.date());
 
         let mut iter = days.into_iter();
 
@@ -200,6 +200,7 @@ impl<T: TimeZone> Round for DateTime<T> {
 
         let round_to_minute = 30;
 
+        #[allow(clippy::manual_is_multiple_of)]
         if minute % round_to_minute == 0 {
             return time;
         }
@@ -216,6 +217,7 @@ impl<T: TimeZone> Round for DateTime<T> {
 
         let minute: i64 = self.minute().into();
 
+        #[allow(clippy::manual_is_multiple_of)]
         if minute % round_to_minute == 0 {
             return time;
         }
@@ -229,9 +231,12 @@ impl<T: TimeZone> Round for DateTime<T> {
 }
 
 #[cfg(test)]
+#[allow(clippy::get_first)]
 mod tests {
     use chrono::DateTime;
 
+    use crate::events::ShowAs;
+
     use super::*;
 
     fn create_local_datetime(dt_str: &str) -> DateTime<Local> {
@@ -286,6 +291,8 @@ mod tests {
             start: create_local_datetime(start),
             // 2 PM
             end: create_local_datetime(end),
+            marker: None,
+            show_as: ShowAs::Busy,
         }
     }
 
@@ -0,0 +1,221 @@
+use std::env;
+
+use chrono::{Date, DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Clock {
+    #[serde(rename = "12h")]
+    TwelveHour,
+    #[serde(rename = "24h")]
+    TwentyFourHour,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateOrder {
+    Mdy,
+    Dmy,
+    Ymd,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct LocaleConfig {
+    /// Locale used for weekday and month names, e.g. fr_FR (default from LC_ALL, LC_TIME or LANG)
+    pub language: Option<String>,
+    /// Either 12h or 24h (default depends on language)
+    pub clock: Option<Clock>,
+    /// One of mdy, dmy or ymd (default depends on language)
+    pub date_order: Option<DateOrder>,
+}
+
+// Languages that conventionally use a 12 hour clock.
+const TWELVE_HOUR_LOCALES: [&str; 8] = [
+    "en_US", "en_CA", "en_AU", "en_NZ", "en_PH", "en_IN", "es_US", "es_MX",
+];
+const MDY_LOCALES: [&str; 3] = ["en_US", "en_PH", "es_US"];
+const YMD_LOCALES: [&str; 6] = ["ja_JP", "ko_KR", "zh_CN", "zh_TW", "hu_HU", "lt_LT"];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Locale {
+    inner: chrono::Locale,
+    clock: Clock,
+    date_order: DateOrder,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale {
+            inner: chrono::Locale::en_US,
+            clock: Clock::TwelveHour,
+            date_order: DateOrder::Mdy,
+        }
+    }
+}
+
+impl Locale {
+    /// Builds the locale from the config, falling back to the environment.
+    ///
+    /// An unsupported language in the config is an error, one from the environment only
+    /// warns and uses the default locale.
+    pub fn from_config(cfg: &LocaleConfig) -> anyhow::Result<Locale> {
+        let mut locale = match &cfg.language {
+            Some(language) => Locale::from_language(language).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unsupported locale \"{}\", expected a locale such as en_US or fr_FR",
+                    language
+                )
+            })?,
+            None => match ["LC_ALL", "LC_TIME", "LANG"]
+                .iter()
+                .filter_map(|var| env::var(var).ok())
+                .find(|v| !v.is_empty())
+            {
+                Some(language) => Locale::from_language(&language).unwrap_or_else(|| {
+                    eprintln!(
+                        "Warning: unsupported locale \"{}\" in the environment, using en_US. Set locale.language in the config to override it.\n",
+                        language
+                    );
+                    Locale::default()
+                }),
+                None => Locale::default(),
+            },
+        };
+
+        if let Some(clock) = cfg.clock {
+            locale.clock = clock;
+        }
+        if let Some(date_order) = cfg.date_order {
+            locale.date_order = date_order;
+        }
+
+        Ok(locale)
+    }
+
+    fn from_language(language: &str) -> Option<Locale> {
+        // Strip the codeset and modifier, e.g. fr_FR.UTF-8 or de_DE@euro.
+        let name = language
+            .split(['.', '@'])
+            .next()
+            .unwrap_or_default()
+            .replace('-', "_");
+
+        if name == "C" || name == "POSIX" {
+            return Some(Locale::default());
+        }
+
+        let inner = chrono::Locale::try_from(name.as_str()).ok()?;
+
+        let clock = if TWELVE_HOUR_LOCALES.contains(&name.as_str()) {
+            Clock::TwelveHour
+        } else {
+            Clock::TwentyFourHour
+        };
+
+        let date_order = if MDY_LOCALES.contains(&name.as_str()) {
+            DateOrder::Mdy
+        } else if YMD_LOCALES.contains(&name.as_str()) {
+            DateOrder::Ymd
+        } else {
+            DateOrder::Dmy
+        };
+
+        Some(Locale {
+            inner,
+            clock,
+            date_order,
+        })
+    }
+
+    fn time_format(&self) -> &'static str {
+        match self.clock {
+            Clock::TwelveHour => "%I:%M %p",
+            Clock::TwentyFourHour => "%H:%M",
+        }
+    }
+
+    /// Formats the time of day, e.g. 09:00 AM or 09:00.
+    pub fn time(&self, dt: &DateTime<Local>) -> String {
+        dt.format_localized(self.time_format(), self.inner)
+            .to_string()
+    }
+
+    /// Formats a day with its weekday, e.g. Mon Nov 07 2022 or lun. 07 nov. 2022.
+    pub fn day(&self, date: &Date<Local>) -> String {
+        let fmt = match self.date_order {
+            DateOrder::Mdy => "%a %b %d %Y",
+            DateOrder::Dmy => "%a %d %b %Y",
+            DateOrder::Ymd => "%Y %b %d %a",
+        };
+        date.format_localized(fmt, self.inner).to_string()
+    }
+
+    /// Formats a day with its weekday without the year, e.g. Mon Nov 07.
+    pub fn short_day(&self, date: &Date<Local>) -> String {
+        let fmt = match self.date_order {
+            DateOrder::Mdy => "%a %b %d",
+            DateOrder::Dmy => "%a %d %b",
+            DateOrder::Ymd => "%b %d %a",
+        };
+        date.format_localized(fmt, self.inner).to_string()
+    }
+
+    /// Formats a date without the weekday, e.g. Nov 7 2022.
+    pub fn date(&self, date: &Date<Local>) -> String {
+        let fmt = match self.date_order {
+            DateOrder::Mdy => "%b %-d %Y",
+            DateOrder::Dmy => "%-d %b %Y",
+            DateOrder::Ymd => "%Y %b %-d",
+        };
+        date.format_localized(fmt, self.inner).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDateTime, TimeZone};
+
+    use super::*;
+
+    fn create_local_datetime(dt_str: &str) -> DateTime<Local> {
+        let ndt = NaiveDateTime::parse_from_str(dt_str, "%m-%d-%Y %H:%M").unwrap();
+        Local.from_local_datetime(&ndt).unwrap()
+    }
+
+    #[test]
+    fn test_default_locale() {
+        let locale = Locale::default();
+        let dt = create_local_datetime("11-07-2022 13:30");
+
+        assert_eq!(locale.day(&dt.date()), "Mon Nov 07 2022");
+        assert_eq!(locale.date(&dt.date()), "Nov 7 2022");
+        assert_eq!(locale.time(&dt), "01:30 PM");
+    }
+
+    #[test]
+    fn test_locale_from_config() {
+        let cfg = LocaleConfig {
+            language: Some("de_DE.UTF-8".to_string()),
+            ..Default::default()
+        };
+        let locale = Locale::from_config(&cfg).unwrap();
+        let dt = create_local_datetime("11-07-2022 13:30");
+
+        assert_eq!(locale.day(&dt.date()), "Mo 07 Nov 2022");
+        assert_eq!(locale.time(&dt), "13:30");
+
+        let cfg = LocaleConfig {
+            language: Some("fr_FR".to_string()),
+            clock: Some(Clock::TwelveHour),
+            date_order: Some(DateOrder::Ymd),
+        };
+        let locale = Locale::from_config(&cfg).unwrap();
+        assert_eq!(locale.date(&dt.date()), "2022 nov. 7");
+
+        let cfg = LocaleConfig {
+            language: Some("xx_YY".to_string()),
+            ..Default::default()
+        };
+        assert!(Locale::from_config(&cfg).is_err());
+    }
+}
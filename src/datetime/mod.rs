@@ -1,2 +1,4 @@
 pub mod availability;
 pub mod finder;
+pub mod locale;
+pub mod parse;
@@ -0,0 +1,432 @@
+use chrono::{prelude::*, Duration};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use super::finder::Round;
+
+pub const DATE_FORMS: &str = "MM/DD/YYYY, YYYY-MM-DD, YYYY-MM-DDTHH:MM, today, tomorrow, <weekday>, next <weekday>, in <n> days|weeks, end of week|month";
+pub const TIME_FORMS: &str = "9:00am, 9am, 9:30 PM, 17:30, noon, midnight";
+pub const DURATION_FORMS: &str =
+    "<int>(w|d|h|m) combined in any order (e.g. 30m, 1h30m, 1d12h), ISO 8601 (e.g. PT90M) or minutes (e.g. 90)";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Week {
+    This,
+    Next,
+}
+
+fn start_of_day(date: NaiveDate) -> anyhow::Result<DateTime<Local>> {
+    Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("{} does not exist in the local time zone", date))
+}
+
+fn next_weekday(from: NaiveDate, weekday: Weekday, include_today: bool) -> NaiveDate {
+    let days_ahead = (7 + weekday.num_days_from_monday() as i64
+        - from.weekday().num_days_from_monday() as i64)
+        % 7;
+    if days_ahead == 0 && !include_today {
+        from + Duration::days(7)
+    } else {
+        from + Duration::days(days_ahead)
+    }
+}
+
+/// Returns `num` times a unit of `seconds`, or None if it's negative or doesn't fit in a
+/// Duration.
+fn checked_duration(num: i64, seconds: i64) -> Option<Duration> {
+    if num < 0 {
+        return None;
+    }
+    num.checked_mul(seconds)?
+        .checked_mul(1000)
+        .map(Duration::milliseconds)
+}
+
+/// Parses an absolute or relative date, relative dates are resolved against `now`.
+///
+/// Dates resolve to the start of the day, except for "end of" expressions which resolve
+/// to the end of the last day (e.g. "end of month" is midnight on the first of next month).
+pub fn parse_date(arg: &str, now: DateTime<Local>) -> anyhow::Result<DateTime<Local>> {
+    let s = arg.trim().to_lowercase();
+    let today = now.date().naive_local();
+
+    for fmt in ["%m/%d/%Y", "%Y-%m-%d"] {
+        if let Ok(date) = NaiveDate::parse_from_str(&s, fmt) {
+            return start_of_day(date);
+        }
+    }
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(arg.trim()) {
+        return Ok(dt.with_timezone(&Local));
+    }
+    for fmt in ["%Y-%m-%dt%H:%M", "%Y-%m-%dt%H:%M:%S"] {
+        if let Ok(ndt) = NaiveDateTime::parse_from_str(&s, fmt) {
+            return Local
+                .from_local_datetime(&ndt)
+                .earliest()
+                .ok_or_else(|| anyhow::anyhow!("{} does not exist in the local time zone", arg));
+        }
+    }
+
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let date = match words[..] {
+        ["today"] => Some(today),
+        ["tomorrow"] => Some(today + Duration::days(1)),
+        ["in", n, unit] => {
+            let n = n.parse::<i64>().ok();
+            match (n, unit) {
+                (Some(n), "day" | "days") => {
+                    checked_duration(n, 24 * 60 * 60).and_then(|d| today.checked_add_signed(d))
+                }
+                (Some(n), "week" | "weeks") => {
+                    checked_duration(n, 7 * 24 * 60 * 60).and_then(|d| today.checked_add_signed(d))
+                }
+                _ => None,
+            }
+        }
+        ["end", "of", "week"] => Some(next_weekday(today, Weekday::Mon, false)),
+        ["end", "of", "month"] => {
+            let (year, month) = if today.month() == 12 {
+                (today.year() + 1, 1)
+            } else {
+                (today.year(), today.month() + 1)
+            };
+            NaiveDate::from_ymd_opt(year, month, 1)
+        }
+        ["next", weekday] => weekday
+            .parse::<Weekday>()
+            .ok()
+            .map(|w| next_weekday(today, w, false)),
+        [weekday] => weekday
+            .parse::<Weekday>()
+            .ok()
+            .map(|w| next_weekday(today, w, true)),
+        _ => None,
+    };
+
+    match date {
+        Some(date) => start_of_day(date),
+        None => Err(anyhow::anyhow!(
+            "invalid date \"{}\", expected one of: {}",
+            arg,
+            DATE_FORMS
+        )),
+    }
+}
+
+/// Parses a time of day in either 12 or 24 hour form.
+pub fn parse_time(arg: &str) -> anyhow::Result<NaiveTime> {
+    let s = arg.trim().to_lowercase().replace(' ', "");
+
+    match s.as_str() {
+        "noon" => return Ok(NaiveTime::from_hms(12, 0, 0)),
+        "midnight" => return Ok(NaiveTime::from_hms(0, 0, 0)),
+        _ => {}
+    }
+
+    // Minutes are optional for 12 hour times, e.g. 9am.
+    let s = match s.find(['a', 'p']) {
+        Some(i) if !s[..i].contains(':') => format!("{}:00{}", &s[..i], &s[i..]),
+        _ => s,
+    };
+
+    for fmt in ["%l:%M%P", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(&s, fmt) {
+            return Ok(time);
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "invalid time \"{}\", expected one of: {}",
+        arg,
+        TIME_FORMS
+    ))
+}
+
+/// Parses a duration, either compound units, ISO 8601 or a plain number of minutes.
+pub fn parse_duration(arg: &str) -> anyhow::Result<Duration> {
+    let s = arg.trim().to_lowercase();
+
+    let duration = if let Ok(minutes) = s.parse::<i64>() {
+        checked_duration(minutes, 60)
+    } else if let Some(iso) = s.strip_prefix('p') {
+        parse_iso_duration(iso)
+    } else {
+        parse_compound_duration(&s)
+    };
+
+    match duration {
+        Some(d) if d > Duration::zero() => Ok(d),
+        Some(_) => Err(anyhow::anyhow!(
+            "invalid duration \"{}\", must be greater than zero",
+            arg
+        )),
+        None => Err(anyhow::anyhow!(
+            "invalid duration \"{}\", expected {}",
+            arg,
+            DURATION_FORMS
+        )),
+    }
+}
+
+/// Splits a string like "1h30m" into its (number, unit) components.
+fn duration_components(s: &str) -> Option<Vec<(i64, String)>> {
+    let mut components = vec![];
+    let mut chars = s.chars().filter(|c| !c.is_whitespace()).peekable();
+
+    while chars.peek().is_some() {
+        let mut num = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+            num.push(c);
+        }
+        let mut unit = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+            unit.push(c);
+        }
+
+        if num.is_empty() || unit.is_empty() {
+            return None;
+        }
+        components.push((num.parse().ok()?, unit));
+    }
+
+    Some(components)
+}
+
+fn parse_compound_duration(s: &str) -> Option<Duration> {
+    let components = duration_components(s)?;
+    if components.is_empty() {
+        return None;
+    }
+
+    let mut duration = Duration::zero();
+    for (num, unit) in components {
+        let seconds = match unit.as_str() {
+            "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+            "d" | "day" | "days" => 24 * 60 * 60,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 60 * 60,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            _ => return None,
+        };
+        duration = duration.checked_add(&checked_duration(num, seconds)?)?;
+    }
+
+    Some(duration)
+}
+
+// e.g. P1W, P1DT12H, PT90M (without the leading P)
+fn parse_iso_duration(s: &str) -> Option<Duration> {
+    let (date, time) = match s.split_once('t') {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let mut duration = Duration::zero();
+    let mut empty = true;
+
+    for (num, unit) in duration_components(date)? {
+        let seconds = match unit.as_str() {
+            "w" => 7 * 24 * 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return None,
+        };
+        duration = duration.checked_add(&checked_duration(num, seconds)?)?;
+        empty = false;
+    }
+
+    if let Some(time) = time {
+        let components = duration_components(time)?;
+        if components.is_empty() {
+            return None;
+        }
+        for (num, unit) in components {
+            let seconds = match unit.as_str() {
+                "h" => 60 * 60,
+                "m" => 60,
+                "s" => 1,
+                _ => return None,
+            };
+            duration = duration.checked_add(&checked_duration(num, seconds)?)?;
+        }
+        empty = false;
+    }
+
+    if empty {
+        None
+    } else {
+        Some(duration)
+    }
+}
+
+/// Returns the search window for the current or next week, weeks start on Monday.
+pub fn week_bounds(
+    week: Week,
+    now: DateTime<Local>,
+) -> anyhow::Result<(DateTime<Local>, DateTime<Local>)> {
+    let next_monday = start_of_day(next_weekday(now.date().naive_local(), Weekday::Mon, false))?;
+    match week {
+        Week::This => Ok((now.ceil(), next_monday)),
+        Week::Next => {
+            let following_monday =
+                start_of_day(next_monday.date().naive_local() + Duration::weeks(1))?;
+            Ok((next_monday, following_monday))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_local_datetime(dt_str: &str) -> DateTime<Local> {
+        let ndt = NaiveDateTime::parse_from_str(dt_str, "%m-%d-%Y %H:%M").unwrap();
+        Local.from_local_datetime(&ndt).unwrap()
+    }
+
+    #[test]
+    fn test_parse_absolute_date() {
+        let now = create_local_datetime("11-09-2022 10:15");
+        let expected = create_local_datetime("11-21-2022 00:00");
+
+        assert_eq!(parse_date("11/21/2022", now).unwrap(), expected);
+        assert_eq!(parse_date("2022-11-21", now).unwrap(), expected);
+        assert_eq!(
+            parse_date("2022-11-21T09:30", now).unwrap(),
+            create_local_datetime("11-21-2022 09:30")
+        );
+    }
+
+    #[test]
+    fn test_parse_relative_date() {
+        // Wednesday
+        let now = create_local_datetime("11-09-2022 10:15");
+
+        let cases = [
+            ("today", "11-09-2022 00:00"),
+            ("Tomorrow", "11-10-2022 00:00"),
+            ("in 3 days", "11-12-2022 00:00"),
+            ("in 2 weeks", "11-23-2022 00:00"),
+            ("wednesday", "11-09-2022 00:00"),
+            ("next wednesday", "11-16-2022 00:00"),
+            ("next mon", "11-14-2022 00:00"),
+            ("friday", "11-11-2022 00:00"),
+            ("end of week", "11-14-2022 00:00"),
+            ("end of month", "12-01-2022 00:00"),
+        ];
+
+        for (arg, expected) in cases {
+            assert_eq!(
+                parse_date(arg, now).unwrap(),
+                create_local_datetime(expected),
+                "{}",
+                arg
+            );
+        }
+
+        let now = create_local_datetime("12-20-2022 10:15");
+        assert_eq!(
+            parse_date("end of month", now).unwrap(),
+            create_local_datetime("01-01-2023 00:00")
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_date() {
+        let now = create_local_datetime("11-09-2022 10:15");
+        for arg in [
+            "",
+            "someday",
+            "in x days",
+            "in -3 days",
+            "in -1 week",
+            "in 99999999999 days",
+            "in 9223372036854775807 weeks",
+            "13/01/2022",
+            "next year",
+        ] {
+            let err = parse_date(arg, now).unwrap_err().to_string();
+            assert!(err.contains(DATE_FORMS), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let cases = [
+            ("9:00am", (9, 0)),
+            ("9am", (9, 0)),
+            ("12pm", (12, 0)),
+            ("9:30 PM", (21, 30)),
+            ("17:30", (17, 30)),
+            ("09:05", (9, 5)),
+            ("noon", (12, 0)),
+            ("midnight", (0, 0)),
+        ];
+
+        for (arg, (hour, minute)) in cases {
+            assert_eq!(
+                parse_time(arg).unwrap(),
+                NaiveTime::from_hms(hour, minute, 0),
+                "{}",
+                arg
+            );
+        }
+
+        assert!(parse_time("25:00").is_err());
+        assert!(parse_time("9").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let cases = [
+            ("30m", Duration::minutes(30)),
+            ("1h30m", Duration::minutes(90)),
+            ("1h 30m", Duration::minutes(90)),
+            ("1d12h", Duration::hours(36)),
+            ("2w", Duration::weeks(2)),
+            ("45 mins", Duration::minutes(45)),
+            ("90", Duration::minutes(90)),
+            ("PT90M", Duration::minutes(90)),
+            ("P1DT12H", Duration::hours(36)),
+            ("P1W", Duration::weeks(1)),
+        ];
+
+        for (arg, expected) in cases {
+            assert_eq!(parse_duration(arg).unwrap(), expected, "{}", arg);
+        }
+
+        for arg in [
+            "",
+            "abc",
+            "h",
+            "1x",
+            "30m1",
+            "PT",
+            "P1H",
+            "0m",
+            "-5",
+            "9223372036854775807",
+            "999999999999999w",
+            "PT9223372036854775807S",
+            "100000000000000d100000000000000d",
+        ] {
+            assert!(parse_duration(arg).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_week_bounds() {
+        // Wednesday
+        let now = create_local_datetime("11-09-2022 10:15");
+
+        let (start, end) = week_bounds(Week::This, now).unwrap();
+        assert_eq!(start, create_local_datetime("11-09-2022 10:30"));
+        assert_eq!(end, create_local_datetime("11-14-2022 00:00"));
+
+        let (start, end) = week_bounds(Week::Next, now).unwrap();
+        assert_eq!(start, create_local_datetime("11-14-2022 00:00"));
+        assert_eq!(end, create_local_datetime("11-21-2022 00:00"));
+    }
+}
@@ -1,11 +1,19 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use chrono::prelude::*;
-use reqwest::Response;
+use reqwest::{Response, StatusCode, Url};
 use serde::Deserialize;
 use serde_json;
 
-use super::{Calendar, Event, GetResources};
-use crate::{oauth::google, util::OAuthConfig};
+use super::{
+    check_authorized, Calendar, CreatedEvent, Event, EventChanges, EventUpdate, GetResources,
+    NewEvent, NotFound, ShowAs, MARKER_PROPERTY,
+};
+use crate::{
+    oauth::{google, AccessToken},
+    util::OAuthConfig,
+};
 
 #[derive(serde::Deserialize, Clone)]
 struct GoogleCalendar {
@@ -19,40 +27,175 @@ struct GoogleEvent {
     id: String,
     #[serde(rename(deserialize = "summary"))]
     name: Option<String>,
+    status: Option<String>,
+    etag: Option<String>,
+    created: Option<String>,
+
+    // Cancelled events returned when syncing don't have a start or end.
+    #[serde(default, deserialize_with = "deserialize_json_time")]
+    start: Option<DateTime<Local>>,
+    #[serde(default, deserialize_with = "deserialize_json_time")]
+    end: Option<DateTime<Local>>,
+    #[serde(rename(deserialize = "extendedProperties"))]
+    extended_properties: Option<ExtendedProperties>,
+    transparency: Option<String>,
+    #[serde(default)]
+    attendees: Vec<GoogleEventAttendee>,
+}
 
-    #[serde(deserialize_with = "deserialize_json_time")]
-    start: DateTime<Local>,
-    #[serde(deserialize_with = "deserialize_json_time")]
-    end: DateTime<Local>,
+#[derive(serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct GoogleEventAttendee {
+    #[serde(default, rename(deserialize = "self"))]
+    is_self: bool,
+    response_status: Option<String>,
 }
 
-fn deserialize_json_time<'de, D>(deserializer: D) -> Result<DateTime<Local>, D::Error>
+impl GoogleEvent {
+    fn show_as(&self) -> ShowAs {
+        let response = self
+            .attendees
+            .iter()
+            .find(|a| a.is_self)
+            .and_then(|a| a.response_status.as_deref());
+        match (self.transparency.as_deref(), response) {
+            (Some("transparent"), _) | (_, Some("declined")) => ShowAs::Free,
+            (_, Some("tentative")) => ShowAs::Tentative,
+            _ => ShowAs::Busy,
+        }
+    }
+
+    fn into_event(self) -> Option<Event> {
+        let show_as = self.show_as();
+        Some(Event {
+            id: self.id,
+            name: self.name,
+            start: self.start?,
+            end: self.end?,
+            marker: self
+                .extended_properties
+                .and_then(|mut p| p.private.remove(MARKER_PROPERTY)),
+            show_as,
+        })
+    }
+}
+
+fn deserialize_json_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Local>>, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
     let json: serde_json::value::Value = serde_json::value::Value::deserialize(deserializer)?;
-    let time_str = json.get("dateTime").expect("datetime").as_str().unwrap();
-    let _tz_str = json.get("timeZone").expect("timeZone").as_str().unwrap();
 
-    // 2022-10-22T20:30:00.0000000
-    let datetime = DateTime::parse_from_rfc3339(time_str)
-        .unwrap_or_else(|_| panic!("failed to parse datetime {}", time_str));
+    // 2022-10-22T20:30:00-04:00
+    if let Some(time_str) = json.get("dateTime").and_then(|t| t.as_str()) {
+        let datetime = DateTime::parse_from_rfc3339(time_str).map_err(serde::de::Error::custom)?;
+        return Ok(Some(datetime.with_timezone(&Local)));
+    }
+
+    // All day events only have a date, e.g. 2022-10-22
+    if let Some(date_str) = json.get("date").and_then(|t| t.as_str()) {
+        let date =
+            NaiveDate::parse_from_str(date_str, "%Y-%m-%d").map_err(serde::de::Error::custom)?;
+        return Ok(Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest());
+    }
 
-    Ok(datetime.with_timezone(&Local))
+    Ok(None)
 }
 
 #[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct GoogleResponse<T> {
     items: Option<Vec<T>>,
     error: Option<GoogleError>,
+    next_page_token: Option<String>,
+    next_sync_token: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
 struct GoogleError {
-    code: String,
+    code: u16,
     message: String,
 }
 
+fn events_url(calendar_id: &str) -> Url {
+    let mut url = Url::parse("https://www.googleapis.com/calendar/v3/calendars").unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .push(calendar_id)
+        .push("events");
+    url
+}
+
+fn event_url(calendar_id: &str, event_id: &str) -> Url {
+    let mut url = events_url(calendar_id);
+    url.path_segments_mut().unwrap().push(event_id);
+    url
+}
+
+/// Lists events across all pages, returns None if the sync token has expired.
+async fn list_events(
+    token: &str,
+    calendar_id: &str,
+    params: &[(&str, String)],
+) -> anyhow::Result<Option<EventChanges>> {
+    let mut changes = EventChanges {
+        full: false,
+        events: vec![],
+        deleted: vec![],
+        sync_token: String::new(),
+    };
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut url = events_url(calendar_id);
+        url.query_pairs_mut()
+            .append_pair("singleEvents", "true")
+            .extend_pairs(params.iter().map(|(k, v)| (k, v.as_str())));
+        if let Some(page_token) = &page_token {
+            url.query_pairs_mut().append_pair("pageToken", page_token);
+        }
+
+        let resp: Response = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::GONE {
+            return Ok(None);
+        }
+
+        let data: GoogleResponse<GoogleEvent> = resp.json().await?;
+        if let Some(err) = data.error {
+            return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
+        }
+
+        for event in data.items.unwrap_or_default() {
+            if event.status.as_deref() == Some("cancelled") {
+                changes.deleted.push(event.id);
+            } else if let Some(event) = event.into_event() {
+                changes.events.push(event);
+            }
+        }
+
+        if let Some(sync_token) = data.next_sync_token {
+            changes.sync_token = sync_token;
+            return Ok(Some(changes));
+        }
+
+        match data.next_page_token {
+            Some(next) => page_token = Some(next),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "No sync token was returned for calendar {}",
+                    calendar_id
+                ))
+            }
+        }
+    }
+}
+
 pub async fn get_authorization_code(
     cfg: &OAuthConfig,
     shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
@@ -61,15 +204,29 @@ pub async fn get_authorization_code(
         return Err(anyhow::anyhow!("Google OAuth is not configured. Please set the client_id and client_secret in the config file."));
     }
     let client = google::new_client(&cfg.client_id, &cfg.client_secret);
-    Ok(client.get_authorization_code(shutdown_receiver).await)
+    client
+        .get_authorization_code(
+            cfg.redirect_port(),
+            cfg.redirect_timeout(),
+            shutdown_receiver,
+        )
+        .await
+}
+
+pub async fn get_device_authorization(cfg: &OAuthConfig) -> anyhow::Result<(String, String)> {
+    if cfg.is_unconfigured() {
+        return Err(anyhow::anyhow!("Google OAuth is not configured. Please set the client_id and client_secret in the config file."));
+    }
+    let client = google::new_client(&cfg.client_id, &cfg.client_secret);
+    client.get_device_authorization().await
 }
 
 pub async fn refresh_access_token(
     cfg: &OAuthConfig,
     refresh_token: &str,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<AccessToken> {
     let client = google::new_client(&cfg.client_id, &cfg.client_secret);
-    Ok(client.refresh_access_token(refresh_token.to_owned()).await)
+    client.refresh_access_token(refresh_token.to_owned()).await
 }
 
 pub struct GoogleAPI {}
@@ -77,15 +234,13 @@ pub struct GoogleAPI {}
 #[async_trait]
 impl GetResources for GoogleAPI {
     async fn get_calendars(token: &str) -> anyhow::Result<Vec<Calendar>> {
-        let resp: GoogleResponse<GoogleCalendar> = reqwest::Client::new()
+        let resp = reqwest::Client::new()
             .get("https://www.googleapis.com/calendar/v3/users/me/calendarList")
             .bearer_auth(token)
             .header("Content-Type", "application/json")
             .send()
-            .await
-            .unwrap()
-            .json()
             .await?;
+        let resp: GoogleResponse<GoogleCalendar> = check_authorized(resp)?.json().await?;
 
         if let Some(err) = resp.error {
             return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
@@ -99,108 +254,275 @@ impl GetResources for GoogleAPI {
                 id: c.id,
                 name: c.name,
                 selected: false,
-                account_id: 0,
             })
             .collect();
         Ok(calendars)
     }
 
-    async fn get_calendar_events(
+    async fn sync_calendar_events(
         token: &str,
         calendar_id: &str,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
-    ) -> anyhow::Result<Vec<Event>> {
-        let start_time_str = str::replace(&start_time.format("%+").to_string(), "+", "-");
-        let end_time_str = str::replace(&end_time.format("%+").to_string(), "+", "-");
-
-        let url = format!(
-            "https://www.googleapis.com/calendar/v3/calendars/{}/events?singleEvents=true&orderBy=startTime&timeMin={}&timeMax={}",
-            calendar_id, start_time_str, end_time_str
-        );
-
-        let resp: Response = reqwest::Client::new()
-            .get(url)
-            .bearer_auth(token)
-            .header("Content-Type", "application/json")
-            .send()
-            .await
-            .unwrap();
-
-        let data: reqwest::Result<GoogleResponse<GoogleEvent>> = resp.json().await;
-
-        match data {
-            Ok(v) => {
-                if let Some(err) = v.error {
-                    return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
-                }
-
-                let events = v
-                    .items
-                    .unwrap()
-                    .into_iter()
-                    .map(|e| Event {
-                        id: e.id,
-                        name: e.name,
-                        start: e.start,
-                        end: e.end,
-                    })
-                    .collect();
-
-                Ok(events)
-            }
-            Err(e) => {
-                println!(
-                    "Failed to parse JSON response of calendar events for {}, {}",
-                    calendar_id, e
-                );
-                return Ok(vec![]);
+        sync_token: Option<String>,
+    ) -> anyhow::Result<EventChanges> {
+        if let Some(sync_token) = sync_token {
+            if let Some(changes) =
+                list_events(token, calendar_id, &[("syncToken", sync_token)]).await?
+            {
+                return Ok(changes);
             }
         }
+
+        // Full sync, either there was no sync token or it expired.
+        let params = [
+            ("timeMin", start_time.to_rfc3339()),
+            ("timeMax", end_time.to_rfc3339()),
+        ];
+        let changes = list_events(token, calendar_id, &params)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Failed to sync calendar {}", calendar_id))?;
+
+        Ok(EventChanges {
+            full: true,
+            ..changes
+        })
     }
 
     async fn create_event(
         token: &str,
         calendar_id: &str,
-        title: &str,
-        start_time: DateTime<Local>,
-        end_time: DateTime<Local>,
-    ) -> anyhow::Result<()> {
-        let url = format!(
-            "https://www.googleapis.com/calendar/v3/calendars/{}/events",
-            calendar_id
-        );
+        event: &NewEvent,
+    ) -> anyhow::Result<CreatedEvent> {
+        let url = events_url(calendar_id);
 
         let body = CreateEventBody {
-            summary: title.to_owned(),
+            summary: event.title.to_owned(),
+            description: event.description.to_owned(),
             start: GoogleDateTime {
-                date_time: start_time.to_rfc3339(),
+                date_time: event.start.to_rfc3339(),
             },
             end: GoogleDateTime {
-                date_time: end_time.to_rfc3339(),
+                date_time: event.end.to_rfc3339(),
             },
+            transparency: transparency(event.show_as).to_string(),
+            visibility: event.private.then(|| "private".to_string()),
+            reminders: (!event.reminders).then_some(GoogleReminders {
+                use_default: false,
+                overrides: vec![],
+            }),
+            color_id: event.color.to_owned(),
+            extended_properties: event.marker.as_ref().map(|marker| ExtendedProperties {
+                private: HashMap::from([(MARKER_PROPERTY.to_string(), marker.to_owned())]),
+            }),
         };
 
         let client = reqwest::Client::new();
-        let _event: GoogleEvent = client
+        let resp = client
             .post(url)
             .body(serde_json::to_string(&body).unwrap())
+            .header("Content-Type", "application/json")
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let event: GoogleEvent = check_authorized(resp)?.error_for_status()?.json().await?;
+
+        Ok(CreatedEvent {
+            id: event.id,
+            etag: event.etag.unwrap_or_default(),
+        })
+    }
+
+    async fn get_event_etag(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::GONE || resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let event: GoogleEvent = resp.error_for_status()?.json().await?;
+
+        // Deleted events can still be retrieved, they're cancelled.
+        if event.status.as_deref() == Some("cancelled") {
+            return Ok(None);
+        }
+        Ok(event.etag)
+    }
+
+    async fn get_event_created(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<DateTime<Local>>> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .get(url)
             .bearer_auth(token)
             .send()
-            .await
-            .unwrap()
-            .json()
             .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::GONE || resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let event: GoogleEvent = resp.error_for_status()?.json().await?;
+
+        if event.status.as_deref() == Some("cancelled") {
+            return Ok(None);
+        }
+        let created = event
+            .created
+            .ok_or_else(|| anyhow::anyhow!("No creation time was returned"))?;
+        Ok(Some(
+            DateTime::parse_from_rfc3339(&created)?.with_timezone(&Local),
+        ))
+    }
+
+    async fn update_event(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+        update: &EventUpdate,
+    ) -> anyhow::Result<()> {
+        let url = event_url(calendar_id, event_id);
+
+        let body = UpdateEventBody {
+            summary: update.title.to_owned(),
+            start: update.start.map(|start| GoogleDateTime {
+                date_time: start.to_rfc3339(),
+            }),
+            end: update.end.map(|end| GoogleDateTime {
+                date_time: end.to_rfc3339(),
+            }),
+            transparency: update.show_as.map(|s| transparency(s).to_string()),
+            description: update.description.to_owned(),
+            attendees: update
+                .attendees
+                .iter()
+                .map(|email| GoogleAttendee {
+                    email: email.to_owned(),
+                })
+                .collect(),
+            extended_properties: update.marker.as_ref().map(|marker| ExtendedProperties {
+                private: HashMap::from([(MARKER_PROPERTY.to_string(), marker.to_owned())]),
+            }),
+        };
+
+        let resp = reqwest::Client::new()
+            .patch(url)
+            // Attendees are only notified if requested.
+            .query(&[("sendUpdates", "all")])
+            .body(serde_json::to_string(&body).unwrap())
+            .header("Content-Type", "application/json")
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::GONE || resp.status() == StatusCode::NOT_FOUND {
+            return Err(NotFound.into());
+        }
+        let event: GoogleEvent = resp.error_for_status()?.json().await?;
+
+        // Deleted events can still be updated, they stay cancelled.
+        if event.status.as_deref() == Some("cancelled") {
+            return Err(NotFound.into());
+        }
+        Ok(())
+    }
+
+    async fn delete_event(token: &str, calendar_id: &str, event_id: &str) -> anyhow::Result<()> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .delete(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        // Events that were already deleted return 410 Gone.
+        if resp.status() == StatusCode::GONE || resp.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        resp.error_for_status()?;
 
         Ok(())
     }
 }
 
+// Google Calendar has no tentative status, tentative events are shown as busy.
+fn transparency(show_as: ShowAs) -> &'static str {
+    match show_as {
+        ShowAs::Busy | ShowAs::Tentative => "opaque",
+        ShowAs::Free => "transparent",
+    }
+}
+
 #[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
 struct CreateEventBody {
     summary: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
     start: GoogleDateTime,
     end: GoogleDateTime,
+    transparency: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visibility: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reminders: Option<GoogleReminders>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extended_properties: Option<ExtendedProperties>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleReminders {
+    use_default: bool,
+    overrides: Vec<serde_json::Value>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct ExtendedProperties {
+    #[serde(default)]
+    private: HashMap<String, String>,
+}
+
+#[derive(serde::Serialize)]
+struct UpdateEventBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<GoogleDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<GoogleDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transparency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attendees: Vec<GoogleAttendee>,
+    #[serde(rename = "extendedProperties", skip_serializing_if = "Option::is_none")]
+    extended_properties: Option<ExtendedProperties>,
+}
+
+#[derive(serde::Serialize)]
+struct GoogleAttendee {
+    email: String,
 }
 
 #[derive(serde::Serialize)]
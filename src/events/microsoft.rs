@@ -1,11 +1,47 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use chrono::prelude::*;
-use reqwest::Response;
+use reqwest::{Response, StatusCode, Url};
 use serde::Deserialize;
 use serde_json;
 
-use super::{Calendar, Event, GetResources};
-use crate::{oauth::microsoft, util::OAuthConfig};
+use super::{
+    check_authorized, Calendar, CreatedEvent, Event, EventChanges, EventUpdate, GetResources,
+    NewEvent, NotFound, ShowAs, MARKER_PROPERTY,
+};
+use crate::{
+    oauth::{microsoft, AccessToken},
+    util::OAuthConfig,
+};
+
+// Markers of changed events are retrieved with this many requests at a time.
+const MARKER_REQUESTS: usize = 8;
+
+// Extended properties are named within a property set, identified by a GUID.
+const MARKER_PROPERTY_SET: &str = "7a1b6c2e-4d3f-4e8a-9b5c-0f2d6e8a1c34";
+
+fn marker_property_id() -> String {
+    format!(
+        "String {{{}}} Name {}",
+        MARKER_PROPERTY_SET, MARKER_PROPERTY
+    )
+}
+
+fn calendar_url(calendar_id: &str) -> Url {
+    let mut url = Url::parse("https://graph.microsoft.com/v1.0/me/calendars").unwrap();
+    url.path_segments_mut().unwrap().push(calendar_id);
+    url
+}
+
+fn event_url(calendar_id: &str, event_id: &str) -> Url {
+    let mut url = calendar_url(calendar_id);
+    url.path_segments_mut()
+        .unwrap()
+        .push("events")
+        .push(event_id);
+    url
+}
 
 #[derive(serde::Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -21,36 +57,79 @@ struct GraphEvent {
     id: String,
     #[serde(rename(deserialize = "subject"))]
     name: Option<String>,
+    #[serde(rename(deserialize = "@removed"))]
+    removed: Option<serde_json::value::Value>,
+
+    // Removed events returned when syncing only have an id.
+    #[serde(default, deserialize_with = "deserialize_json_time")]
+    start: Option<DateTime<Local>>,
+    #[serde(default, deserialize_with = "deserialize_json_time")]
+    end: Option<DateTime<Local>>,
+    // Only returned when expanded, which delta queries don't support.
+    #[serde(default, rename(deserialize = "singleValueExtendedProperties"))]
+    extended_properties: Vec<ExtendedProperty>,
+    #[serde(rename(deserialize = "showAs"))]
+    show_as: Option<String>,
+    #[serde(rename(deserialize = "responseStatus"))]
+    response_status: Option<GraphResponseStatus>,
+}
 
-    #[serde(deserialize_with = "deserialize_json_time")]
-    start: DateTime<Local>,
-    #[serde(deserialize_with = "deserialize_json_time")]
-    end: DateTime<Local>,
+#[derive(serde::Deserialize, Clone)]
+struct GraphResponseStatus {
+    response: Option<String>,
+}
+
+impl GraphEvent {
+    fn into_event(self) -> Option<Event> {
+        let declined = self
+            .response_status
+            .and_then(|r| r.response)
+            .is_some_and(|r| r == "declined");
+        let show_as = match self.show_as.as_deref() {
+            _ if declined => ShowAs::Free,
+            Some("free" | "workingElsewhere") => ShowAs::Free,
+            Some("tentative") => ShowAs::Tentative,
+            _ => ShowAs::Busy,
+        };
+        Some(Event {
+            id: self.id,
+            name: self.name,
+            start: self.start?,
+            end: self.end?,
+            marker: None,
+            show_as,
+        })
+    }
 }
 
-fn deserialize_json_time<'de, D>(deserializer: D) -> Result<DateTime<Local>, D::Error>
+fn deserialize_json_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Local>>, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
     let json: serde_json::value::Value = serde_json::value::Value::deserialize(deserializer)?;
-    let time_str = json.get("dateTime").expect("datetime").as_str().unwrap();
-    let tz_str = json.get("timeZone").expect("timeZone").as_str().unwrap();
+    let time_str = match json.get("dateTime").and_then(|t| t.as_str()) {
+        Some(time_str) => time_str,
+        None => return Ok(None),
+    };
 
     // 2022-10-22T20:30:00.0000000
-    let naive_time = NaiveDateTime::parse_from_str(time_str, "%Y-%m-%dT%H:%M:%S.%f").unwrap();
+    let naive_time = NaiveDateTime::parse_from_str(time_str, "%Y-%m-%dT%H:%M:%S.%f")
+        .map_err(serde::de::Error::custom)?;
 
-    let utc_datetime = match tz_str {
-        "UTC" => DateTime::from_utc(naive_time, Utc),
-        _ => DateTime::<Utc>::from_utc(naive_time, Utc),
-    };
+    // Times are returned in UTC unless another time zone is preferred.
+    let utc_datetime = DateTime::<Utc>::from_utc(naive_time, Utc);
 
-    Ok(utc_datetime.with_timezone(&Local))
+    Ok(Some(utc_datetime.with_timezone(&Local)))
 }
 
 #[derive(serde::Deserialize)]
 struct GraphResponse<T> {
     value: Option<Vec<T>>,
     error: Option<GraphError>,
+    #[serde(rename(deserialize = "@odata.nextLink"))]
+    next_link: Option<String>,
+    #[serde(rename(deserialize = "@odata.deltaLink"))]
+    delta_link: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -59,6 +138,135 @@ struct GraphError {
     message: String,
 }
 
+/// Follows a delta query across all pages, returns None if the delta link has expired.
+async fn get_delta(token: &str, url: &str) -> anyhow::Result<Option<EventChanges>> {
+    let mut changes = EventChanges {
+        full: false,
+        events: vec![],
+        deleted: vec![],
+        sync_token: String::new(),
+    };
+    let mut url = url.to_owned();
+
+    loop {
+        let resp: Response = reqwest::Client::new()
+            .get(&url)
+            .bearer_auth(token)
+            .header("Prefer", "odata.maxpagesize=100")
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::GONE {
+            return Ok(None);
+        }
+
+        let data: GraphResponse<GraphEvent> = resp.json().await?;
+        if let Some(err) = data.error {
+            if err.code == "SyncStateNotFound" || err.code == "SyncStateInvalid" {
+                return Ok(None);
+            }
+            return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
+        }
+
+        for event in data.value.unwrap_or_default() {
+            if event.removed.is_some() {
+                changes.deleted.push(event.id);
+            } else if let Some(event) = event.into_event() {
+                changes.events.push(event);
+            }
+        }
+
+        // The delta link is used as the sync token.
+        if let Some(delta_link) = data.delta_link {
+            changes.sync_token = delta_link;
+            return Ok(Some(changes));
+        }
+
+        match data.next_link {
+            Some(next) => url = next,
+            None => return Err(anyhow::anyhow!("No delta link was returned")),
+        }
+    }
+}
+
+fn marker_expand() -> String {
+    format!(
+        "singleValueExtendedProperties($filter=id eq '{}')",
+        marker_property_id()
+    )
+}
+
+/// Returns avail's markers of the calendar's events between the start and end time by
+/// event id, delta queries can't expand extended properties so they're retrieved separately.
+async fn get_markers(
+    token: &str,
+    calendar_id: &str,
+    start_time: DateTime<Local>,
+    end_time: DateTime<Local>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut url = calendar_url(calendar_id);
+    url.path_segments_mut().unwrap().push("calendarView");
+    url.query_pairs_mut()
+        .append_pair("startDateTime", &start_time.to_rfc3339())
+        .append_pair("endDateTime", &end_time.to_rfc3339())
+        .append_pair("$select", "id")
+        .append_pair("$expand", &marker_expand());
+
+    let mut markers = HashMap::new();
+    let mut url = url.to_string();
+
+    loop {
+        let resp: Response = reqwest::Client::new()
+            .get(&url)
+            .bearer_auth(token)
+            .header("Prefer", "odata.maxpagesize=100")
+            .send()
+            .await?;
+        let data: GraphResponse<GraphEvent> = check_authorized(resp)?.json().await?;
+        if let Some(err) = data.error {
+            return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
+        }
+
+        for mut event in data.value.unwrap_or_default() {
+            if let Some(property) = event.extended_properties.pop() {
+                markers.insert(event.id, property.value);
+            }
+        }
+
+        match data.next_link {
+            Some(next) => url = next,
+            None => return Ok(markers),
+        }
+    }
+}
+
+/// Returns avail's marker of a single event, or None if it has none or was deleted since.
+async fn get_marker(
+    token: &str,
+    calendar_id: &str,
+    event_id: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut url = event_url(calendar_id, event_id);
+    url.query_pairs_mut()
+        .append_pair("$select", "id")
+        .append_pair("$expand", &marker_expand());
+
+    let resp = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?;
+    let resp = check_authorized(resp)?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let resp = resp.error_for_status()?;
+    let mut event: GraphEvent = resp.json().await?;
+    Ok(event.extended_properties.pop().map(|p| p.value))
+}
+
 pub async fn get_authorization_code(
     cfg: &OAuthConfig,
     shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
@@ -67,18 +275,32 @@ pub async fn get_authorization_code(
         return Err(anyhow::anyhow!("Microsoft OAuth is not configured. Please set the client_id and client_secret in the config file."));
     }
     let client = microsoft::new_client(&cfg.client_id, &cfg.client_secret);
-    Ok(client.get_authorization_code(shutdown_receiver).await)
+    client
+        .get_authorization_code(
+            cfg.redirect_port(),
+            cfg.redirect_timeout(),
+            shutdown_receiver,
+        )
+        .await
+}
+
+pub async fn get_device_authorization(cfg: &OAuthConfig) -> anyhow::Result<(String, String)> {
+    if cfg.is_unconfigured() {
+        return Err(anyhow::anyhow!("Microsoft OAuth is not configured. Please set the client_id and client_secret in the config file."));
+    }
+    let client = microsoft::new_client(&cfg.client_id, &cfg.client_secret);
+    client.get_device_authorization().await
 }
 
 pub async fn refresh_access_token(
     cfg: &OAuthConfig,
     refresh_token: &str,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<AccessToken> {
     if cfg.is_unconfigured() {
         return Err(anyhow::anyhow!("Microsoft OAuth is not configured. Please set the client_id and client_secret in the config file."));
     }
     let client = microsoft::new_client(&cfg.client_id, &cfg.client_secret);
-    Ok(client.refresh_access_token(refresh_token.to_owned()).await)
+    client.refresh_access_token(refresh_token.to_owned()).await
 }
 
 pub struct MicrosoftGraph {}
@@ -86,15 +308,13 @@ pub struct MicrosoftGraph {}
 #[async_trait]
 impl GetResources for MicrosoftGraph {
     async fn get_calendars(token: &str) -> anyhow::Result<Vec<Calendar>> {
-        let resp: GraphResponse<GraphCalendar> = reqwest::Client::new()
+        let resp = reqwest::Client::new()
             .get("https://graph.microsoft.com/v1.0/me/calendars")
             .bearer_auth(token)
             .header("Content-Type", "application/json")
             .send()
-            .await
-            .unwrap()
-            .json()
             .await?;
+        let resp: GraphResponse<GraphCalendar> = check_authorized(resp)?.json().await?;
 
         if let Some(err) = resp.error {
             return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
@@ -108,110 +328,338 @@ impl GetResources for MicrosoftGraph {
                 id: c.id,
                 name: c.name,
                 selected: false,
-                account_id: 0,
             })
             .collect();
         Ok(calendars)
     }
 
-    async fn get_calendar_events(
+    async fn sync_calendar_events(
         token: &str,
         calendar_id: &str,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
-    ) -> anyhow::Result<Vec<Event>> {
-        let start_time_str = str::replace(&start_time.format("%+").to_string(), "+", "-");
-        let end_time_str = str::replace(&end_time.format("%+").to_string(), "+", "-");
-
-        let url = format!("https://graph.microsoft.com/v1.0/me/calendars/{}/calendarView?startDateTime={}&endDateTime={}", calendar_id, start_time_str, end_time_str);
-
-        let resp: Response = reqwest::Client::new()
-            .get(url)
-            .bearer_auth(token)
-            .header("Content-Type", "application/json")
-            .send()
-            .await
-            .unwrap();
+        sync_token: Option<String>,
+    ) -> anyhow::Result<EventChanges> {
+        let changes = match sync_token {
+            Some(delta_link) => get_delta(token, &delta_link).await?,
+            None => None,
+        };
 
-        let data: reqwest::Result<GraphResponse<GraphEvent>> = resp.json().await;
+        let mut changes = match changes {
+            Some(changes) => changes,
+            None => {
+                // Full sync, either there was no delta link or it expired.
+                let mut url = calendar_url(calendar_id);
+                url.path_segments_mut()
+                    .unwrap()
+                    .push("calendarView")
+                    .push("delta");
+                url.query_pairs_mut()
+                    .append_pair("startDateTime", &start_time.to_rfc3339())
+                    .append_pair("endDateTime", &end_time.to_rfc3339());
 
-        match data {
-            Ok(v) => {
-                if let Some(err) = v.error {
-                    return Err(anyhow::anyhow!("{}: {}", err.code, err.message));
+                let changes = get_delta(token, url.as_str())
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Failed to sync calendar {}", calendar_id))?;
+                EventChanges {
+                    full: true,
+                    ..changes
                 }
+            }
+        };
 
-                let events = v
-                    .value
-                    .unwrap()
-                    .into_iter()
-                    .map(|e| Event {
-                        id: e.id,
-                        name: e.name,
-                        start: e.start,
-                        end: e.end,
-                    })
-                    .collect();
-
-                Ok(events)
+        if changes.full {
+            if !changes.events.is_empty() {
+                let mut markers = get_markers(token, calendar_id, start_time, end_time).await?;
+                for event in changes.events.iter_mut() {
+                    event.marker = markers.remove(&event.id);
+                }
             }
-            Err(e) => {
-                println!(
-                    "Failed to parse JSON response of calendar events for {}, {}",
-                    calendar_id, e
-                );
-                return Ok(vec![]);
+        } else {
+            // Only the changed events are looked up, the rest of the window is unchanged.
+            for events in changes.events.chunks_mut(MARKER_REQUESTS) {
+                let requests: Vec<_> = events
+                    .iter()
+                    .map(|e| get_marker(token, calendar_id, &e.id))
+                    .collect();
+                let markers = futures::future::try_join_all(requests).await?;
+                for (event, marker) in events.iter_mut().zip(markers) {
+                    event.marker = marker;
+                }
             }
         }
+
+        Ok(changes)
     }
 
     async fn create_event(
         token: &str,
         calendar_id: &str,
-        title: &str,
-        start_time: DateTime<Local>,
-        end_time: DateTime<Local>,
-    ) -> anyhow::Result<()> {
-        let url = format!(
-            "https://graph.microsoft.com/v1.0/me/calendars/{}/events",
-            calendar_id
-        );
+        event: &NewEvent,
+    ) -> anyhow::Result<CreatedEvent> {
+        let mut url = calendar_url(calendar_id);
+        url.path_segments_mut().unwrap().push("events");
 
-        let tz_str = start_time.format("%Z");
+        let tz_str = event.start.format("%Z");
 
         let body = CreateEventBody {
-            subject: title.to_owned(),
+            subject: event.title.to_owned(),
+            body: event.description.as_ref().map(|description| ItemBody {
+                content_type: "text".to_string(),
+                content: description.to_owned(),
+            }),
             start: MicrosoftDateTime {
-                date_time: start_time.to_rfc3339(),
+                date_time: event.start.to_rfc3339(),
                 time_zone: tz_str.to_string(),
             },
             end: MicrosoftDateTime {
-                date_time: end_time.to_rfc3339(),
+                date_time: event.end.to_rfc3339(),
                 time_zone: tz_str.to_string(),
             },
+            show_as: show_as(event.show_as).to_string(),
+            sensitivity: event.private.then(|| "private".to_string()),
+            is_reminder_on: event.reminders,
+            categories: event.category.iter().cloned().collect(),
+            single_value_extended_properties: event
+                .marker
+                .iter()
+                .map(|marker| ExtendedProperty {
+                    id: marker_property_id(),
+                    value: marker.to_owned(),
+                })
+                .collect(),
         };
 
         let client = reqwest::Client::new();
-        let _event: String = client
+        let resp = client
             .post(url)
             .body(serde_json::to_string(&body).unwrap())
             .header("Content-Type", "application/json")
             .bearer_auth(token)
             .send()
-            .await
-            .unwrap()
-            .text()
             .await?;
+        let event: EventResponse = check_authorized(resp)?.json().await?;
+
+        match (event.id, event.error) {
+            (Some(id), _) => Ok(CreatedEvent {
+                id,
+                etag: event.change_key.unwrap_or_default(),
+            }),
+            (None, Some(err)) => Err(anyhow::anyhow!("{}: {}", err.code, err.message)),
+            (None, None) => Err(anyhow::anyhow!("No event id was returned")),
+        }
+    }
+
+    async fn get_event_etag(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .get(url)
+            .query(&[("$select", "id,changeKey")])
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let event: EventResponse = resp.error_for_status()?.json().await?;
+
+        Ok(event.change_key)
+    }
+
+    async fn get_event_created(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<DateTime<Local>>> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .get(url)
+            .query(&[("$select", "id,createdDateTime")])
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let event: EventResponse = resp.error_for_status()?.json().await?;
+
+        // e.g. 2022-10-22T20:30:00.1234567Z
+        let created = event
+            .created_date_time
+            .ok_or_else(|| anyhow::anyhow!("No creation time was returned"))?;
+        Ok(Some(
+            DateTime::parse_from_rfc3339(&created)?.with_timezone(&Local),
+        ))
+    }
+
+    async fn update_event(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+        update: &EventUpdate,
+    ) -> anyhow::Result<()> {
+        let url = event_url(calendar_id, event_id);
+
+        let body = UpdateEventBody {
+            subject: update.title.to_owned(),
+            start: update.start.map(|start| MicrosoftDateTime {
+                date_time: start.to_rfc3339(),
+                time_zone: start.format("%Z").to_string(),
+            }),
+            end: update.end.map(|end| MicrosoftDateTime {
+                date_time: end.to_rfc3339(),
+                time_zone: end.format("%Z").to_string(),
+            }),
+            show_as: update.show_as.map(|s| show_as(s).to_string()),
+            body: update.description.as_ref().map(|description| ItemBody {
+                content_type: "text".to_string(),
+                content: description.to_owned(),
+            }),
+            attendees: update
+                .attendees
+                .iter()
+                .map(|email| Attendee {
+                    email_address: EmailAddress {
+                        address: email.to_owned(),
+                    },
+                    attendee_type: "required".to_string(),
+                })
+                .collect(),
+            single_value_extended_properties: update
+                .marker
+                .iter()
+                .map(|marker| ExtendedProperty {
+                    id: marker_property_id(),
+                    value: marker.to_owned(),
+                })
+                .collect(),
+        };
+
+        let resp = reqwest::Client::new()
+            .patch(url)
+            .body(serde_json::to_string(&body).unwrap())
+            .header("Content-Type", "application/json")
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(NotFound.into());
+        }
+        resp.error_for_status()?;
 
         Ok(())
     }
+
+    async fn delete_event(token: &str, calendar_id: &str, event_id: &str) -> anyhow::Result<()> {
+        let url = event_url(calendar_id, event_id);
+
+        let resp = reqwest::Client::new()
+            .delete(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        let resp = check_authorized(resp)?;
+
+        // The event was already deleted.
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        resp.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateEventBody {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<MicrosoftDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<MicrosoftDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    show_as: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<ItemBody>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attendees: Vec<Attendee>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    single_value_extended_properties: Vec<ExtendedProperty>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ItemBody {
+    content_type: String,
+    content: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Attendee {
+    email_address: EmailAddress,
+    #[serde(rename = "type")]
+    attendee_type: String,
+}
+
+#[derive(serde::Serialize)]
+struct EmailAddress {
+    address: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventResponse {
+    id: Option<String>,
+    change_key: Option<String>,
+    created_date_time: Option<String>,
+    error: Option<GraphError>,
+}
+
+fn show_as(show_as: ShowAs) -> &'static str {
+    match show_as {
+        ShowAs::Busy => "busy",
+        ShowAs::Tentative => "tentative",
+        ShowAs::Free => "free",
+    }
 }
 
 #[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
 struct CreateEventBody {
     subject: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<ItemBody>,
     start: MicrosoftDateTime,
     end: MicrosoftDateTime,
+    show_as: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensitivity: Option<String>,
+    is_reminder_on: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    categories: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    single_value_extended_properties: Vec<ExtendedProperty>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct ExtendedProperty {
+    id: String,
+    value: String,
 }
 
 #[derive(serde::Serialize)]
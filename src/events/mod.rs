@@ -3,9 +3,9 @@ pub mod microsoft;
 
 use async_trait::async_trait;
 use chrono::prelude::*;
+use reqwest::{Response, StatusCode};
 
 pub struct Calendar {
-    pub account_id: u32,
     pub id: String,
     pub name: String,
     pub selected: bool,
@@ -22,22 +22,186 @@ pub struct Event {
     pub name: Option<String>,
     pub start: DateTime<Local>,
     pub end: DateTime<Local>,
+    /// Set on events avail created, see `NewEvent::marker`.
+    pub marker: Option<String>,
+    /// How the event's time is shown, events the user declined are shown as free.
+    pub show_as: ShowAs,
+}
+
+/// Changes to a calendar's events since it was last synced.
+pub struct EventChanges {
+    /// Whether `events` contains every event, e.g. when the sync token has expired.
+    /// Previously synced events should be discarded if so.
+    pub full: bool,
+    /// Events that were created or updated.
+    pub events: Vec<Event>,
+    /// Ids of events that were deleted.
+    pub deleted: Vec<String>,
+    /// Token to pass to the next sync.
+    pub sync_token: String,
+}
+
+/// How an event's time is shown to others.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShowAs {
+    #[default]
+    Busy,
+    /// Shown as busy by Google Calendar, which has no tentative status.
+    Tentative,
+    Free,
+}
+
+impl ShowAs {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShowAs::Busy => "busy",
+            ShowAs::Tentative => "tentative",
+            ShowAs::Free => "free",
+        }
+    }
+}
+
+impl From<&str> for ShowAs {
+    fn from(s: &str) -> Self {
+        match s {
+            "tentative" => ShowAs::Tentative,
+            "free" => ShowAs::Free,
+            _ => ShowAs::Busy,
+        }
+    }
+}
+
+/// Name of the private property avail stores its marker in.
+pub const MARKER_PROPERTY: &str = "avail";
+
+/// Markers are `<kind>:<id>`, holds are marked with the id of the search that created them.
+pub const HOLD_MARKER: &str = "hold";
+/// Busy blocks created by sync, marked with the id of their sync pair.
+pub const BUSY_MARKER: &str = "busy";
+/// Holds that were confirmed into a meeting, marked with the id of the search.
+pub const MEETING_MARKER: &str = "meeting";
+
+pub fn marker(kind: &str, id: u32) -> String {
+    format!("{}:{}", kind, id)
+}
+
+/// Whether the marker is of the given kind, e.g. `HOLD_MARKER`.
+pub fn is_marker(marker: Option<&str>, kind: &str) -> bool {
+    marker
+        .and_then(|m| m.split_once(':'))
+        .is_some_and(|(k, _)| k == kind)
+}
+
+/// An event to create.
+#[derive(Clone)]
+pub struct NewEvent {
+    pub title: String,
+    pub description: Option<String>,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub show_as: ShowAs,
+    pub private: bool,
+    pub reminders: bool,
+    /// Outlook category, ignored by Google Calendar.
+    pub category: Option<String>,
+    /// Google Calendar color id, ignored by Outlook.
+    pub color: Option<String>,
+    /// Stored in a private property of the event so avail can recognise the events it
+    /// created.
+    pub marker: Option<String>,
+}
+
+/// An event that was just created.
+pub struct CreatedEvent {
+    pub id: String,
+    /// Changes whenever the event is modified, the etag for Google and the change key for
+    /// Microsoft.
+    pub etag: String,
+}
+
+/// Changes to an existing event, fields that aren't set are kept.
+#[derive(Default)]
+pub struct EventUpdate {
+    pub title: Option<String>,
+    pub start: Option<DateTime<Local>>,
+    pub end: Option<DateTime<Local>>,
+    pub show_as: Option<ShowAs>,
+    pub description: Option<String>,
+    /// Emails of the people to invite, they're sent an invitation by the provider.
+    pub attendees: Vec<String>,
+    /// Replaces the marker set when the event was created.
+    pub marker: Option<String>,
+}
+
+/// The access token was rejected, e.g. because it expired early or was revoked.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl std::fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The access token was rejected")
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+/// The event doesn't exist anymore, e.g. because it was deleted in the calendar.
+#[derive(Debug)]
+pub struct NotFound;
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The event was deleted")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Returns an `Unauthorized` error if the request was rejected because of its access token.
+pub(crate) fn check_authorized(resp: Response) -> anyhow::Result<Response> {
+    if resp.status() == StatusCode::UNAUTHORIZED {
+        return Err(Unauthorized.into());
+    }
+    Ok(resp)
 }
 
 #[async_trait]
 pub trait GetResources {
     async fn get_calendars(token: &str) -> anyhow::Result<Vec<Calendar>>;
-    async fn get_calendar_events(
+    /// Retrieves the changes since the sync token was issued, or every event between
+    /// the start and end time if no sync token is given.
+    async fn sync_calendar_events(
         token: &str,
         calendar_id: &str,
         start_time: DateTime<Local>,
         end_time: DateTime<Local>,
-    ) -> anyhow::Result<Vec<Event>>;
+        sync_token: Option<String>,
+    ) -> anyhow::Result<EventChanges>;
     async fn create_event(
         token: &str,
         calendar_id: &str,
-        title: &str,
-        start_time: DateTime<Local>,
-        end_time: DateTime<Local>,
+        event: &NewEvent,
+    ) -> anyhow::Result<CreatedEvent>;
+    /// Returns the event's current etag, or `None` if it was deleted.
+    async fn get_event_etag(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<String>>;
+    /// Returns when the event was created, or `None` if it was deleted.
+    async fn get_event_created(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+    ) -> anyhow::Result<Option<DateTime<Local>>>;
+    /// Updates an event, returning a `NotFound` error if it was deleted.
+    async fn update_event(
+        token: &str,
+        calendar_id: &str,
+        event_id: &str,
+        update: &EventUpdate,
     ) -> anyhow::Result<()>;
+    /// Deletes an event, succeeding if it was already deleted.
+    async fn delete_event(token: &str, calendar_id: &str, event_id: &str) -> anyhow::Result<()>;
 }
@@ -1,19 +1,34 @@
 mod cli;
+mod clipboard;
 mod commands;
 mod datetime;
 mod events;
+mod migrations;
 mod oauth;
 mod store;
+mod template;
+mod token_storage;
+mod tokens;
 mod util;
 
-use std::{process::exit, sync::Mutex};
+use std::{
+    process::exit,
+    sync::{Arc, Mutex},
+};
 
 use chrono::{prelude::*, Duration};
 use clap::Parser;
 use colored::Colorize;
 use tokio::sync::oneshot;
 
-use crate::{cli::ProgressIndicator, datetime::finder::AvailabilityFinder};
+use crate::{
+    cli::ProgressIndicator,
+    clipboard::ClipboardBackend,
+    datetime::{finder::AvailabilityFinder, locale::Locale},
+    events::EventUpdate,
+    template::Template,
+    tokens::TokenCache,
+};
 use util::load_config;
 
 #[tokio::main]
@@ -21,7 +36,17 @@ async fn main() -> anyhow::Result<()> {
     let cli = cli::Cli::parse();
     let cfg = load_config()?;
 
-    let db = store::Store::new(&format!("{}/db.db3", util::get_avail_directory()?));
+    let db = store::Store::open(&format!("{}/db.db3", util::get_avail_directory()?))?;
+    if !matches!(cli.command, Some(cli::Commands::Db(_))) {
+        if let (applied, Some(backup)) = db.migrate().await? {
+            eprintln!(
+                "Migrated the database to schema version {}, the previous version was backed up to {}\n",
+                applied.last().unwrap().version,
+                backup
+            );
+        }
+    }
+    let tokens = Arc::new(TokenCache::new(&cfg));
 
     let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
     let tx_mutex: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(Some(shutdown_sender));
@@ -41,21 +66,172 @@ async fn main() -> anyhow::Result<()> {
     match &cli.command {
         Some(cli::Commands::Accounts(account_cmd)) => match &account_cmd.command {
             cli::AccountCommands::Add(cmd) => {
-                commands::add_account(db, &cmd.email, &cfg, shutdown_receiver).await?
+                commands::add_account(db, &cmd.email, &cfg, cmd.device, shutdown_receiver).await?
+            }
+            cli::AccountCommands::Remove(cmd) => {
+                commands::remove_account(db, &tokens, &cmd.email, &cfg).await?
+            }
+            cli::AccountCommands::List(_) => commands::list_accounts(db).await?,
+            cli::AccountCommands::Reauth(cmd) => {
+                commands::reauth_account(db, &cmd.email, &cfg, cmd.device, shutdown_receiver)
+                    .await?
+            }
+        },
+        Some(cli::Commands::Calendars(calendars_cmd)) => match &calendars_cmd.command {
+            Some(cli::CalendarCommands::List(_)) => commands::list_calendars(db).await?,
+            Some(cli::CalendarCommands::Select(cmd)) => {
+                commands::select_calendar(db, &cmd.calendar, cmd.account.as_deref(), true).await?
+            }
+            Some(cli::CalendarCommands::Deselect(cmd)) => {
+                commands::select_calendar(db, &cmd.calendar, cmd.account.as_deref(), false).await?
+            }
+            Some(cli::CalendarCommands::SetHold(cmd)) => {
+                commands::set_hold_calendar(db, &cmd.calendar, cmd.account.as_deref(), true).await?
+            }
+            Some(cli::CalendarCommands::UnsetHold(cmd)) => {
+                commands::set_hold_calendar(db, &cmd.calendar, cmd.account.as_deref(), false)
+                    .await?
+            }
+            Some(cli::CalendarCommands::Refresh(cmd)) => {
+                commands::refresh_calendars(db, &tokens, cmd.account.as_deref()).await?
+            }
+            Some(cli::CalendarCommands::Configure(_)) | None => {
+                commands::configure_calendars(db, &tokens).await?
+            }
+        },
+        Some(cli::Commands::Groups(groups_cmd)) => match &groups_cmd.command {
+            cli::GroupCommands::Add(cmd) => {
+                commands::add_to_group(db, &cmd.group, &cmd.calendars, cmd.account.as_deref())
+                    .await?
+            }
+            cli::GroupCommands::Remove(cmd) => {
+                commands::remove_from_group(db, &cmd.group, &cmd.calendars, cmd.account.as_deref())
+                    .await?
+            }
+            cli::GroupCommands::List(_) => commands::list_groups(db).await?,
+        },
+        Some(cli::Commands::Holds(holds_cmd)) => {
+            let locale = Locale::from_config(&cfg.locale.to_owned().unwrap_or_default())?;
+            match &holds_cmd.command {
+                cli::HoldCommands::List(cmd) => {
+                    commands::list_holds(db, cmd.query, &locale).await?
+                }
+                cli::HoldCommands::Show(cmd) => commands::show_hold(db, cmd.id, &locale).await?,
+                cli::HoldCommands::Release(cmd) => {
+                    let selection = if cmd.all {
+                        commands::HoldSelection::All
+                    } else if let Some(query) = cmd.query {
+                        commands::HoldSelection::Query(query)
+                    } else {
+                        commands::HoldSelection::Ids(cmd.ids.to_owned())
+                    };
+                    commands::release_holds(db, &tokens, selection, &locale).await?
+                }
+                cli::HoldCommands::Confirm(cmd) => {
+                    let update = EventUpdate {
+                        title: cmd.title.to_owned(),
+                        description: cmd.description.to_owned(),
+                        attendees: cmd.attendees.to_owned(),
+                        ..Default::default()
+                    };
+                    let hold_cfg = cfg.holds.to_owned().unwrap_or_default();
+                    commands::confirm_hold(db, &tokens, cmd.id, update, &hold_cfg, &locale).await?
+                }
+                cli::HoldCommands::Gc(cmd) => {
+                    let hold_cfg = cfg.holds.to_owned().unwrap_or_default();
+                    let release_untracked = cmd
+                        .release_untracked
+                        .then(|| cmd.min_age.unwrap_or_else(|| Duration::days(1)));
+                    commands::gc_holds(
+                        db,
+                        &tokens,
+                        cmd.dry_run,
+                        release_untracked,
+                        &hold_cfg,
+                        &locale,
+                    )
+                    .await?
+                }
+            }
+        }
+        Some(cli::Commands::Sync(sync_cmd)) => match &sync_cmd.command {
+            Some(cli::SyncCommands::Add(cmd)) => {
+                commands::add_sync_pair(
+                    db,
+                    &cmd.source,
+                    cmd.source_account.as_deref(),
+                    &cmd.target,
+                    cmd.target_account.as_deref(),
+                )
+                .await?
+            }
+            Some(cli::SyncCommands::Remove(cmd)) => {
+                commands::remove_sync_pair(
+                    db,
+                    &tokens,
+                    &cmd.source,
+                    cmd.source_account.as_deref(),
+                    &cmd.target,
+                    cmd.target_account.as_deref(),
+                )
+                .await?
+            }
+            Some(cli::SyncCommands::List(_)) => commands::list_sync_pairs(db).await?,
+            Some(cli::SyncCommands::Run(_)) | None => {
+                let window = match &sync_cmd.command {
+                    Some(cli::SyncCommands::Run(cmd)) => cmd.window,
+                    _ => None,
+                };
+                let progress = ProgressIndicator::default();
+                commands::sync_busy_blocks(
+                    db,
+                    &tokens,
+                    window.unwrap_or_else(|| Duration::weeks(4)),
+                    &progress,
+                )
+                .await?;
+                progress.clear();
+            }
+        },
+        Some(cli::Commands::Tokens(tokens_cmd)) => match &tokens_cmd.command {
+            cli::TokenCommands::Migrate(cmd) => {
+                commands::migrate_tokens(db, &cfg, &cmd.from, cmd.to.as_ref()).await?
+            }
+        },
+        Some(cli::Commands::Db(db_cmd)) => match &db_cmd.command {
+            cli::DbCommands::Status(_) => commands::db_status(db).await?,
+            cli::DbCommands::Migrate(cmd) => commands::migrate_db(db, cmd.dry_run).await?,
+        },
+        Some(cli::Commands::Cache(cache_cmd)) => match &cache_cmd.command {
+            cli::CacheCommands::Clear(_) => commands::clear_cache(db).await?,
+            cli::CacheCommands::Stats(_) => {
+                let locale = Locale::from_config(&cfg.locale.to_owned().unwrap_or_default())?;
+                commands::cache_stats(db, &locale).await?
             }
-            cli::AccountCommands::Remove(cmd) => commands::remove_account(db, &cmd.email)?,
-            cli::AccountCommands::List(_) => commands::list_accounts(db)?,
         },
-        Some(cli::Commands::Calendars(_)) => commands::refresh_calendars(db, &cfg).await?,
         _ => {
-            let start_time = cli
-                .start
-                .unwrap_or_else(|| datetime::finder::Round::ceil(&Local::now()));
+            let mut options = cli.search_options();
+            if let Some(preset) = &cli.preset {
+                options = options.or(cfg.get_preset(preset)?);
+            }
+            if let Some(defaults) = &cfg.defaults {
+                options = options.or(defaults.to_owned());
+            }
+
+            let (start, end) = match options.week {
+                Some(week) => {
+                    let (start, end) = datetime::parse::week_bounds(week, Local::now())?;
+                    (Some(start), Some(end))
+                }
+                None => (options.start, options.end),
+            };
 
-            let end_time = if let Some(end) = cli.end {
+            let start_time = start.unwrap_or_else(|| datetime::finder::Round::ceil(&Local::now()));
+
+            let end_time = if let Some(end) = end {
                 end
             } else {
-                let window = cli.window.unwrap_or_else(|| Duration::days(7));
+                let window = options.window.unwrap_or_else(|| Duration::days(7));
                 start_time + window
             };
 
@@ -63,7 +239,9 @@ async fn main() -> anyhow::Result<()> {
                 return Err(anyhow::anyhow!("end time cannot be before start time"));
             }
 
-            if cli.end.is_some() && cli.window.is_some() {
+            // Merging drops an end or window from a lower source when the other is set, so
+            // both are only set when they come from the same source.
+            if options.end.is_some() && options.window.is_some() {
                 println!(
                     "{}",
                     "Specified both end and window options, using end.\n"
@@ -72,10 +250,23 @@ async fn main() -> anyhow::Result<()> {
                 );
             }
 
-            let min_time = cli.min.unwrap_or_else(|| NaiveTime::from_hms(9, 0, 0));
-            let max_time = cli.max.unwrap_or_else(|| NaiveTime::from_hms(17, 0, 0));
+            let min_time = options.min.unwrap_or_else(|| NaiveTime::from_hms(9, 0, 0));
+            let max_time = options.max.unwrap_or_else(|| NaiveTime::from_hms(17, 0, 0));
 
-            let duration = cli.duration.unwrap_or_else(|| Duration::minutes(30));
+            let duration = options.duration.unwrap_or_else(|| Duration::minutes(30));
+
+            let template = Template::load(
+                options
+                    .template
+                    .as_deref()
+                    .unwrap_or(template::DEFAULT_TEMPLATE),
+            )?;
+            let locale = Locale::from_config(&cfg.locale.to_owned().unwrap_or_default())?;
+            let clipboard = if options.no_copy.unwrap_or(false) {
+                ClipboardBackend::None
+            } else {
+                cfg.clipboard.unwrap_or_default()
+            };
 
             let finder = AvailabilityFinder {
                 start: start_time,
@@ -83,12 +274,37 @@ async fn main() -> anyhow::Result<()> {
                 min: min_time,
                 max: max_time,
                 duration,
-                include_weekends: cli.include_weekends,
+                include_weekends: options.include_weekends.unwrap_or(false),
+            };
+
+            // --offline only conflicts with --create-hold-event on the command line, a
+            // default or preset can turn it on too.
+            if cli.offline && options.create_hold_event.unwrap_or(false) {
+                return Err(anyhow::anyhow!(
+                    "Hold events can't be created with --offline, pass --no-create-hold-event to skip them"
+                ));
+            }
+
+            let cache_mode = if cli.offline {
+                commands::CacheMode::Offline
+            } else if cli.refresh {
+                commands::CacheMode::Refresh
+            } else {
+                commands::CacheMode::Sync
             };
 
             let progress = ProgressIndicator::default();
 
-            let avails = commands::find_availability(&db, &cfg, finder, &progress).await?;
+            let avails = commands::find_availability(
+                &db,
+                &tokens,
+                finder,
+                options.calendars.as_deref(),
+                &locale,
+                cache_mode,
+                &progress,
+            )
+            .await?;
 
             progress.clear();
 
@@ -97,13 +313,23 @@ async fn main() -> anyhow::Result<()> {
                 return Ok(());
             }
 
-            if !cli.create_hold_event {
-                commands::print_and_copy_availability(&avails);
+            if !options.create_hold_event.unwrap_or(false) {
+                commands::print_and_copy_availability(&avails, &template, &locale, clipboard)?;
                 return Ok(());
             }
 
-            commands::create_hold_events(db, &cfg, &avails, &progress).await?;
-            commands::print_and_copy_availability(&avails);
+            let hold_cfg = cfg.holds.to_owned().unwrap_or_default();
+            commands::create_hold_events(
+                db,
+                &tokens,
+                &avails,
+                options.hold_ttl,
+                &hold_cfg,
+                &locale,
+                &progress,
+            )
+            .await?;
+            commands::print_and_copy_availability(&avails, &template, &locale, clipboard)?;
         }
     }
 
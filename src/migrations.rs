@@ -0,0 +1,381 @@
+use rusqlite::Connection;
+
+/// A change to the database schema, applied in a transaction and recorded in
+/// `PRAGMA user_version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+/// All migrations in the order they're applied, new ones must be added at the end with the
+/// next version.
+///
+/// Tables are created with `IF NOT EXISTS` since older versions created them without
+/// recording a schema version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create accounts and calendars tables",
+        sql: "
+            CREATE TABLE IF NOT EXISTS accounts (
+                id          INTEGER PRIMARY KEY,
+                name        TEXT NOT NULL UNIQUE,
+                platform    TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS calendars (
+                account_id  INTEGER NOT NULL,
+                id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                query BOOLEAN,
+                can_edit BOOLEAN,
+                use_for_hold_events BOOLEAN,
+                PRIMARY KEY (account_id, id),
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "Cache calendar events and their sync state",
+        sql: "
+            CREATE TABLE IF NOT EXISTS events (
+                account_id  INTEGER NOT NULL,
+                calendar_id TEXT NOT NULL,
+                id TEXT NOT NULL,
+                name TEXT,
+                start INTEGER NOT NULL,
+                end INTEGER NOT NULL,
+                PRIMARY KEY (account_id, calendar_id, id),
+                FOREIGN KEY(account_id, calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS calendar_syncs (
+                account_id  INTEGER NOT NULL,
+                calendar_id TEXT NOT NULL,
+                sync_token TEXT NOT NULL,
+                window_start INTEGER NOT NULL,
+                window_end INTEGER NOT NULL,
+                last_synced INTEGER NOT NULL,
+                PRIMARY KEY (account_id, calendar_id),
+                FOREIGN KEY(account_id, calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "Track accounts that need to be re-authenticated",
+        sql: "
+            CREATE TABLE IF NOT EXISTS account_reauths (
+                account_id  INTEGER PRIMARY KEY,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "Allow the same email to be added on several platforms",
+        // The table is recreated with ids kept, so calendars and events are preserved.
+        sql: "
+            CREATE TABLE accounts_new (
+                id          INTEGER PRIMARY KEY,
+                name        TEXT NOT NULL,
+                platform    TEXT NOT NULL,
+                UNIQUE (platform, name)
+            );
+            INSERT INTO accounts_new (id, name, platform) SELECT id, name, platform FROM accounts;
+            DROP TABLE accounts;
+            ALTER TABLE accounts_new RENAME TO accounts;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "Add calendar groups",
+        sql: "
+            CREATE TABLE calendar_groups (
+                name        TEXT NOT NULL,
+                account_id  INTEGER NOT NULL,
+                calendar_id TEXT NOT NULL,
+                PRIMARY KEY (name, account_id, calendar_id),
+                FOREIGN KEY(account_id, calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "Track hold events",
+        sql: "
+            CREATE TABLE holds (
+                id          INTEGER PRIMARY KEY,
+                account_id  INTEGER NOT NULL,
+                calendar_id TEXT NOT NULL,
+                event_id    TEXT NOT NULL,
+                title       TEXT NOT NULL,
+                start       INTEGER NOT NULL,
+                end         INTEGER NOT NULL,
+                created     INTEGER NOT NULL,
+                query_id    INTEGER NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "Expire hold events",
+        sql: "
+            ALTER TABLE holds ADD COLUMN expires INTEGER;
+            ALTER TABLE holds ADD COLUMN etag TEXT;
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "Allow creating hold events in several calendars",
+        sql: "
+            CREATE TABLE hold_calendars (
+                account_id  INTEGER NOT NULL,
+                calendar_id TEXT NOT NULL,
+                PRIMARY KEY (account_id, calendar_id),
+                FOREIGN KEY(account_id, calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE
+            );
+            INSERT INTO hold_calendars (account_id, calendar_id)
+                SELECT account_id, id FROM calendars WHERE use_for_hold_events = true;
+            ALTER TABLE calendars DROP COLUMN use_for_hold_events;
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "Mirror events of calendars as busy blocks",
+        sql: "
+            CREATE TABLE sync_pairs (
+                id                  INTEGER PRIMARY KEY,
+                source_account_id   INTEGER NOT NULL,
+                source_calendar_id  TEXT NOT NULL,
+                target_account_id   INTEGER NOT NULL,
+                target_calendar_id  TEXT NOT NULL,
+                UNIQUE (source_account_id, source_calendar_id, target_account_id, target_calendar_id),
+                FOREIGN KEY(source_account_id, source_calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE,
+                FOREIGN KEY(target_account_id, target_calendar_id) REFERENCES calendars(account_id, id) ON DELETE CASCADE
+            );
+            CREATE TABLE busy_blocks (
+                pair_id         INTEGER NOT NULL,
+                source_event_id TEXT NOT NULL,
+                target_event_id TEXT NOT NULL,
+                start           INTEGER NOT NULL,
+                end             INTEGER NOT NULL,
+                PRIMARY KEY (pair_id, source_event_id),
+                FOREIGN KEY(pair_id) REFERENCES sync_pairs(id) ON DELETE CASCADE
+            );
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "Cache the markers of events created by avail",
+        // Cached events are synced again so their markers are read.
+        sql: "
+            ALTER TABLE events ADD COLUMN marker TEXT;
+            DELETE FROM events;
+            DELETE FROM calendar_syncs;
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "Never reuse the query ids of released holds",
+        // Continues after the latest query id that's still in use.
+        sql: "
+            CREATE TABLE hold_queries (
+                id  INTEGER PRIMARY KEY AUTOINCREMENT
+            );
+            INSERT INTO hold_queries (id)
+                SELECT MAX(query_id) FROM holds HAVING MAX(query_id) IS NOT NULL;
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "Cache how events are shown, e.g. free or declined",
+        // Cached events are synced again so how they're shown is read.
+        sql: "
+            ALTER TABLE events ADD COLUMN show_as TEXT NOT NULL DEFAULT 'busy';
+            DELETE FROM events;
+            DELETE FROM calendar_syncs;
+        ",
+    },
+];
+
+/// The schema version the database is migrated to.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> anyhow::Result<u32> {
+    Ok(conn.query_row("PRAGMA user_version", (), |row| row.get(0))?)
+}
+
+/// Returns the migrations that haven't been applied to the database yet.
+pub fn pending(conn: &Connection) -> anyhow::Result<Vec<&'static Migration>> {
+    let version = current_version(conn)?;
+    if version > latest_version() {
+        return Err(anyhow::anyhow!(
+            "The database has schema version {} but this version of avail only supports up to {}, please upgrade avail",
+            version,
+            latest_version()
+        ));
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Whether the database has any tables, i.e. wasn't just created.
+pub fn has_tables(conn: &Connection) -> anyhow::Result<bool> {
+    let count: u32 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+        (),
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Applies the pending migrations in order, each in its own transaction, returning the
+/// ones that were applied.
+pub fn migrate(conn: &Connection) -> anyhow::Result<Vec<&'static Migration>> {
+    let pending = pending(conn)?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+
+    // Foreign keys can't be toggled inside a transaction, they're disabled so that
+    // recreating a table doesn't cascade and checked before each migration is committed.
+    conn.execute_batch("PRAGMA foreign_keys = false")?;
+    let res = pending.iter().try_for_each(|m| apply(conn, m));
+    conn.execute_batch("PRAGMA foreign_keys = true")?;
+    res?;
+
+    Ok(pending)
+}
+
+fn apply(conn: &Connection, migration: &Migration) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(migration.sql).map_err(|e| {
+        anyhow::anyhow!(
+            "Migration {} ({}) failed: {}",
+            migration.version,
+            migration.description,
+            e
+        )
+    })?;
+
+    let violations: u32 =
+        tx.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", (), |row| {
+            row.get(0)
+        })?;
+    if violations > 0 {
+        return Err(anyhow::anyhow!(
+            "Migration {} ({}) left {} rows with invalid references",
+            migration.version,
+            migration.description,
+            violations
+        ));
+    }
+
+    tx.pragma_update(None, "user_version", migration.version)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Copies the database to `path`, replacing any previous backup there.
+pub fn backup(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    if std::path::Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    conn.execute("VACUUM INTO ?", [path])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_are_ordered() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version as usize, i + 1);
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(!has_tables(&conn).unwrap());
+
+        let applied = migrate(&conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(migrate(&conn).unwrap().is_empty());
+
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(pending(&conn).is_err());
+    }
+
+    #[test]
+    fn test_migrate_unversioned_database() {
+        // Created by a version without migrations.
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+                CREATE TABLE accounts (
+                    id          INTEGER PRIMARY KEY,
+                    name        TEXT NOT NULL UNIQUE,
+                    platform    TEXT NOT NULL
+                );
+                CREATE TABLE calendars (
+                    account_id  INTEGER NOT NULL,
+                    id TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    query BOOLEAN,
+                    can_edit BOOLEAN,
+                    use_for_hold_events BOOLEAN,
+                    PRIMARY KEY (account_id, id),
+                    FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+                );
+                INSERT INTO accounts (name, platform) VALUES ('me@example.com', 'google');
+                INSERT INTO calendars (account_id, id, name, use_for_hold_events) VALUES (1, 'primary', 'Primary', true);
+            ",
+        )
+        .unwrap();
+        assert!(has_tables(&conn).unwrap());
+
+        migrate(&conn).unwrap();
+
+        // The same email can be added on another platform and calendars are kept.
+        conn.execute(
+            "INSERT INTO accounts (name, platform) VALUES ('me@example.com', 'microsoft')",
+            (),
+        )
+        .unwrap();
+        let calendars: u32 = conn
+            .query_row("SELECT COUNT(*) FROM calendars", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(calendars, 1);
+
+        // The calendar used for hold events is kept.
+        let hold_calendars: u32 = conn
+            .query_row("SELECT COUNT(*) FROM hold_calendars", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(hold_calendars, 1);
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Makes migration 4 fail after migrations 1-3 were applied.
+        conn.execute_batch("CREATE TABLE accounts_new (id INTEGER)")
+            .unwrap();
+
+        let err = migrate(&conn).err().unwrap();
+        assert!(err.to_string().contains("Migration 4"));
+        assert_eq!(current_version(&conn).unwrap(), 3);
+
+        let foreign_keys: bool = conn
+            .query_row("PRAGMA foreign_keys", (), |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+    }
+}
@@ -2,7 +2,7 @@ use super::OauthClient;
 
 const AUTH_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
 const TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
-const REDIRECT_URL: &str = "http://localhost:3003/redirect";
+const DEVICE_AUTH_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
 
 pub fn new_client(client_id: &str, _client_secret: &str) -> OauthClient {
     OauthClient::new(
@@ -16,6 +16,6 @@ pub fn new_client(client_id: &str, _client_secret: &str) -> OauthClient {
         ],
         AUTH_URL,
         TOKEN_URL,
-        REDIRECT_URL,
+        DEVICE_AUTH_URL,
     )
 }
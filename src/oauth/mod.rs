@@ -1,19 +1,60 @@
 pub mod google;
 pub mod microsoft;
+mod redirect;
 
+use chrono::{prelude::*, Duration};
 use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AuthType, AuthUrl, AuthorizationCode, ClientId,
-    ClientSecret, CsrfToken, PkceCodeChallenge, RedirectUrl, Scope, TokenResponse, TokenUrl,
+    basic::{BasicClient, BasicErrorResponseType},
+    devicecode::{DeviceCodeErrorResponseType, StandardDeviceAuthorizationResponse},
+    reqwest::async_http_client,
+    AuthType, AuthUrl, ClientId, ClientSecret, CsrfToken, DeviceAuthorizationUrl,
+    PkceCodeChallenge, RedirectUrl, RequestTokenError, Scope, TokenResponse, TokenUrl,
 };
-use reqwest::Url;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::task::JoinHandle;
+use tokio::net::TcpListener;
+
+/// An access token and when it expires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessToken {
+    pub secret: String,
+    pub expires_at: DateTime<Local>,
+}
+
+impl AccessToken {
+    fn new(secret: String, expires_in: Option<std::time::Duration>) -> Self {
+        // Providers issue access tokens that are valid for an hour unless stated otherwise.
+        let expires_in = expires_in
+            .and_then(|d| Duration::from_std(d).ok())
+            .unwrap_or_else(|| Duration::hours(1));
+
+        Self {
+            secret,
+            expires_at: Local::now() + expires_in,
+        }
+    }
+
+    /// Whether the token has expired, or will within a minute.
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        now + Duration::minutes(1) >= self.expires_at
+    }
+}
+
+/// The refresh token has expired or was revoked, the account must be authorized again.
+#[derive(Debug)]
+pub struct ReauthRequired;
+
+impl std::fmt::Display for ReauthRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The refresh token has expired or was revoked")
+    }
+}
+
+impl std::error::Error for ReauthRequired {}
 
 pub struct OauthClient {
     pub(crate) inner: BasicClient,
+    #[allow(dead_code)]
     pub client_id: String,
+    #[allow(dead_code)]
     pub client_secret: String,
     pub scopes: Vec<String>,
 }
@@ -25,11 +66,13 @@ impl OauthClient {
         scopes: Vec<&str>,
         auth_url: &str,
         token_url: &str,
-        redirect_url: &str,
+        device_auth_url: &str,
     ) -> Self {
         let auth_url =
             AuthUrl::new(auth_url.to_string()).expect("Invalid authorization endpoint URL");
         let token_url = TokenUrl::new(token_url.to_string()).expect("Invalid token endpoint URL");
+        let device_auth_url = DeviceAuthorizationUrl::new(device_auth_url.to_string())
+            .expect("Invalid device authorization endpoint URL");
 
         let client = BasicClient::new(
             ClientId::new(client_id.to_string()),
@@ -38,9 +81,7 @@ impl OauthClient {
             Some(token_url),
         )
         .set_auth_type(AuthType::RequestBody)
-        .set_redirect_uri(
-            RedirectUrl::new(redirect_url.to_string()).expect("Invalid redirect URL"),
-        );
+        .set_device_authorization_url(device_auth_url);
 
         Self {
             inner: client,
@@ -52,6 +93,7 @@ impl OauthClient {
 
     fn get_authorization_url(
         &self,
+        client: &BasicClient,
     ) -> (
         oauth2::url::Url,
         oauth2::CsrfToken,
@@ -63,10 +105,7 @@ impl OauthClient {
 
         let s = self.scopes.iter().map(|f| Scope::new(f.to_string()));
 
-        let auth_request = self
-            .inner
-            .authorize_url(CsrfToken::new_random)
-            .add_scopes(s);
+        let auth_request = client.authorize_url(CsrfToken::new_random).add_scopes(s);
 
         // Generate the authorization URL to which we'll redirect the user.
         let (authorize_url, csrf_state) =
@@ -75,130 +114,221 @@ impl OauthClient {
         (authorize_url, csrf_state, pkce_code_verifier)
     }
 
-    pub async fn refresh_access_token(&self, refresh_token: String) -> String {
+    pub async fn refresh_access_token(&self, refresh_token: String) -> anyhow::Result<AccessToken> {
         let token = self
             .inner
             .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token))
             .request_async(async_http_client)
             .await;
 
-        let inner = token.unwrap();
+        let inner = match token {
+            Ok(inner) => inner,
+            Err(RequestTokenError::ServerResponse(resp))
+                if *resp.error() == BasicErrorResponseType::InvalidGrant =>
+            {
+                return Err(ReauthRequired.into())
+            }
+            Err(e) => return Err(anyhow::anyhow!("Failed to refresh access token: {}", e)),
+        };
 
-        inner.access_token().secret().to_owned()
+        Ok(AccessToken::new(
+            inner.access_token().secret().to_owned(),
+            inner.expires_in(),
+        ))
     }
 
+    /// Authorizes the user in the browser, returning the access and refresh tokens.
+    ///
+    /// The provider redirects to a server on `port`, or any free port if it's 0, which
+    /// stops waiting after `timeout`.
     pub async fn get_authorization_code(
         &self,
+        port: u16,
+        timeout: Duration,
         shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
-    ) -> (String, String) {
-        let (authorize_url, _csrf_state, pkce_code_verifier) = self.get_authorization_url();
+    ) -> anyhow::Result<(String, String)> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to listen on port {}: {}", port, e))?;
+        let port = listener.local_addr()?.port();
+
+        let client = self
+            .inner
+            .clone()
+            .set_redirect_uri(RedirectUrl::new(format!(
+                "http://localhost:{}{}",
+                port,
+                redirect::REDIRECT_PATH
+            ))?);
+        let (authorize_url, csrf_state, pkce_code_verifier) = self.get_authorization_url(&client);
 
         let authorize_url_with_offline = format!("{}&access_type=offline", authorize_url);
         println!("Opening browser to {}", authorize_url_with_offline);
 
         webbrowser::open(authorize_url_with_offline.as_str()).expect("failed to open web browser");
 
-        // A very naive implementation of the redirect server.
-        let listener = TcpListener::bind("127.0.0.1:3003").await.unwrap();
-
-        let handle: JoinHandle<Result<Option<AuthorizationCode>, _>> = tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    conn = listener.accept() => {
-                        // Process the connection
-                        match conn {
-                            Ok((stream, _addr)) => {
-                                return process_stream(stream).await;
-                            }
-                            Err(e) => {
-                                // An error occurred, so log it and continue
-                                println!("Error accepting connection: {:?}", e);
-                                return Err(anyhow::anyhow!("Error accepting connection {:?}", e));
-                            }
-                        }
-                    }
-                    _ = shutdown_receiver => {
-                        // The shutdown signal has been received, so break out of the loop
-                        // and shutdown the TcpListener
-                        return Ok(None)
-                    }
-                };
-            }
-        });
-
-        let code = match handle.await.unwrap() {
-            Ok(c) => c.expect("failed to retrieve authorization code"),
-            Err(e) => {
-                panic!("{:?}", e);
-            }
-        };
+        let code =
+            redirect::wait_for_code(listener, csrf_state, timeout, shutdown_receiver).await?;
 
         // Exchange the code with a token.
-        let token_result = self
-            .inner
+        let token_result = client
             .exchange_code(code)
             // Send the PKCE code verifier in the token request
             .set_pkce_verifier(pkce_code_verifier)
             .request_async(async_http_client)
             .await;
 
-        let inner = token_result.unwrap();
+        let inner = token_result
+            .map_err(|e| anyhow::anyhow!("Failed to exchange authorization code: {}", e))?;
         let access_token = inner.access_token().secret().to_owned();
-        let refresh_token = inner.refresh_token().unwrap().secret().to_owned();
-        (access_token, refresh_token)
+        let refresh_token = inner
+            .refresh_token()
+            .ok_or_else(|| anyhow::anyhow!("No refresh token was returned"))?
+            .secret()
+            .to_owned();
+        Ok((access_token, refresh_token))
+    }
+
+    /// Authorizes the user with the device authorization grant, where the user enters a
+    /// code on another device. Returns the access and refresh tokens.
+    pub async fn get_device_authorization(&self) -> anyhow::Result<(String, String)> {
+        let s = self.scopes.iter().map(|f| Scope::new(f.to_string()));
+
+        let details: StandardDeviceAuthorizationResponse = self
+            .inner
+            .exchange_device_code()?
+            .add_scopes(s)
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to request a device code: {}", e))?;
+
+        println!(
+            "Open {} on any device and enter the code {}",
+            details.verification_uri().as_str(),
+            details.user_code().secret()
+        );
+
+        // Polls the token endpoint until the code is entered or expires.
+        let token_result = self
+            .inner
+            .exchange_device_access_token(&details)
+            .request_async(async_http_client, tokio::time::sleep, None)
+            .await;
+
+        let inner = match token_result {
+            Ok(inner) => inner,
+            Err(RequestTokenError::ServerResponse(resp)) => {
+                return Err(match resp.error() {
+                    DeviceCodeErrorResponseType::AccessDenied => {
+                        anyhow::anyhow!("Access was denied")
+                    }
+                    DeviceCodeErrorResponseType::ExpiredToken => {
+                        anyhow::anyhow!("The code expired before it was entered, please try again")
+                    }
+                    _ => anyhow::anyhow!("Failed to retrieve access token: {}", resp),
+                })
+            }
+            Err(e) => return Err(anyhow::anyhow!("Failed to retrieve access token: {}", e)),
+        };
+
+        let access_token = inner.access_token().secret().to_owned();
+        let refresh_token = inner
+            .refresh_token()
+            .ok_or_else(|| anyhow::anyhow!("No refresh token was returned"))?
+            .secret()
+            .to_owned();
+        Ok((access_token, refresh_token))
     }
 }
 
+#[allow(dead_code)]
 trait OauthTokenRetriever {
     fn get_authorization_code(&self) -> (String, String);
     fn refresh_access_token(&self, refresh_token: String) -> String;
 }
 
-async fn process_stream(mut stream: TcpStream) -> anyhow::Result<Option<AuthorizationCode>> {
-    let code;
-    let _state;
-    let code = {
-        let mut request_line = String::new();
-        let _ = stream.readable().await;
-        stream.read_to_string(&mut request_line).await?;
-
-        let redirect_url = request_line.split_whitespace().nth(1).unwrap();
-        let url = Url::parse(&("http://localhost".to_string() + redirect_url)).unwrap();
-
-        let code_pair = url.query_pairs().find(|pair| {
-            let &(ref key, _) = pair;
-            key == "code"
-        });
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use super::*;
+
+    /// Serves the device authorization and token endpoints, the token is only issued on
+    /// the second poll.
+    async fn serve_stub(listener: TcpListener) {
+        let polls = Arc::new(AtomicUsize::new(0));
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let polls = polls.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).await.unwrap();
+
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await.unwrap();
+                let body = String::from_utf8(body).unwrap();
+
+                let (status, json) = if request_line.starts_with("POST /device") {
+                    assert!(body.contains("scope=calendar"));
+                    (
+                        "200 OK",
+                        r#"{"device_code": "device123", "user_code": "ABCD-EFGH", "verification_uri": "http://localhost/device", "expires_in": 60, "interval": 0}"#,
+                    )
+                } else if polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    assert!(body.contains("device_code=device123"));
+                    ("400 Bad Request", r#"{"error": "authorization_pending"}"#)
+                } else {
+                    (
+                        "200 OK",
+                        r#"{"access_token": "access", "token_type": "Bearer", "expires_in": 3600, "refresh_token": "refresh"}"#,
+                    )
+                };
 
-        if code_pair.is_none() {
-            return Err(anyhow::anyhow!("Code pair was not received"));
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    json.len(),
+                    json
+                );
+                let mut stream = reader.into_inner();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
         }
+    }
 
-        let (_, value) = code_pair.unwrap();
-        code = AuthorizationCode::new(value.into_owned());
-
-        let state_pair = url
-            .query_pairs()
-            .find(|pair| {
-                let &(ref key, _) = pair;
-                key == "state"
-            })
-            .unwrap();
-
-        let (_, value) = state_pair;
-        _state = CsrfToken::new(value.into_owned());
-
-        code
-    };
-
-    let message = "Go back to your terminal :)";
-    let response = format!(
-        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
-        message.len(),
-        message
-    );
-    stream.write_all_buf(&mut response.as_bytes()).await?;
-
-    // The server will terminate itself after collecting the first code.
-    Ok(Some(code))
+    #[tokio::test]
+    async fn test_device_authorization() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_stub(listener));
+
+        let client = OauthClient::new(
+            "client_id",
+            "client_secret",
+            vec!["calendar"],
+            &format!("{}/authorize", url),
+            &format!("{}/token", url),
+            &format!("{}/device", url),
+        );
+        let (access_token, refresh_token) = client.get_device_authorization().await.unwrap();
+        assert_eq!(access_token, "access");
+        assert_eq!(refresh_token, "refresh");
+    }
 }
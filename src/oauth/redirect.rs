@@ -0,0 +1,345 @@
+use chrono::Duration;
+use oauth2::{AuthorizationCode, CsrfToken};
+use reqwest::Url;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Path the provider redirects to with the authorization code.
+pub const REDIRECT_PATH: &str = "/redirect";
+
+// Requests with larger headers are rejected, the redirect only needs the request line.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+// Browsers may open connections they never send a request on, so each connection is
+// only given a short time to send its headers.
+const READ_TIMEOUT_SECS: u64 = 10;
+
+/// An error the provider redirected with instead of an authorization code, e.g. when the
+/// user denies access.
+#[derive(Debug)]
+pub struct AuthorizationError {
+    pub error: String,
+    pub description: Option<String>,
+}
+
+impl std::fmt::Display for AuthorizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.error == "access_denied" {
+            write!(f, "Access was denied")?;
+        } else {
+            write!(f, "Authorization failed with \"{}\"", self.error)?;
+        }
+        match &self.description {
+            Some(description) => write!(f, ": {}", description),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for AuthorizationError {}
+
+/// The request line of an HTTP/1.1 request.
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    target: String,
+}
+
+/// Parses the head of an HTTP/1.1 request, i.e. everything before the empty line.
+fn parse_request(head: &str) -> anyhow::Result<Request> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let (method, target, version) = match parts[..] {
+        [method, target, version] => (method, target, version),
+        _ => return Err(anyhow::anyhow!("Invalid request line \"{}\"", request_line)),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow::anyhow!("Unsupported HTTP version \"{}\"", version));
+    }
+
+    for header in lines.filter(|l| !l.is_empty()) {
+        if !header.contains(':') {
+            return Err(anyhow::anyhow!("Invalid header \"{}\"", header));
+        }
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+    })
+}
+
+/// Reads the head of a request, returning as soon as the headers end without waiting for
+/// the connection to be closed.
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!(
+                "Connection closed before the request was received"
+            ));
+        }
+        if line == "\r\n" || line == "\n" {
+            break;
+        }
+        head.push_str(line.trim_end_matches(['\r', '\n']));
+        head.push_str("\r\n");
+
+        if head.len() > MAX_HEAD_LEN {
+            return Err(anyhow::anyhow!("Request headers are too large"));
+        }
+    }
+
+    parse_request(&head)
+}
+
+/// Parses the target of the redirect request, returning the authorization code if the
+/// state matches the one sent with the authorization request.
+fn parse_redirect(target: &str, csrf_state: &CsrfToken) -> anyhow::Result<AuthorizationCode> {
+    let url = Url::parse(&("http://localhost".to_string() + target))?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    if let Some(error) = param("error") {
+        return Err(AuthorizationError {
+            error,
+            description: param("error_description"),
+        }
+        .into());
+    }
+
+    if param("state").as_deref() != Some(csrf_state.secret().as_str()) {
+        return Err(anyhow::anyhow!(
+            "The state of the redirect doesn't match the authorization request, please try again"
+        ));
+    }
+
+    match param("code") {
+        Some(code) => Ok(AuthorizationCode::new(code)),
+        None => Err(anyhow::anyhow!("No authorization code was received")),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_page(title: &str, message: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\
+        <html><head><meta charset=\"utf-8\"><title>avail - {title}</title></head>\
+        <body style=\"font-family: sans-serif; text-align: center; margin-top: 4em\">\
+        <h1>{title}</h1><p>{message}</p></body></html>",
+        title = escape_html(title),
+        message = escape_html(message)
+    )
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Handles a single connection, returning the result of the redirect if it was one.
+async fn handle_connection(
+    mut stream: TcpStream,
+    csrf_state: &CsrfToken,
+) -> Option<anyhow::Result<AuthorizationCode>> {
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(READ_TIMEOUT_SECS),
+        read_request(&mut stream),
+    )
+    .await;
+
+    let request = match read {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            let _ = respond(&mut stream, "400 Bad Request", "text/plain", &e.to_string()).await;
+            return None;
+        }
+        Err(_) => return None,
+    };
+
+    // Other requests, e.g. for the favicon, don't end the flow.
+    let path = request.target.split('?').next().unwrap_or_default();
+    if request.method != "GET" || path != REDIRECT_PATH {
+        let _ = respond(&mut stream, "404 Not Found", "text/plain", "Not found").await;
+        return None;
+    }
+
+    let res = parse_redirect(&request.target, csrf_state);
+    let (status, body) = match &res {
+        Ok(_) => (
+            "200 OK",
+            html_page(
+                "Signed in",
+                "You can close this tab and go back to your terminal.",
+            ),
+        ),
+        Err(e) => (
+            "400 Bad Request",
+            html_page("Unable to sign in", &e.to_string()),
+        ),
+    };
+    let _ = respond(&mut stream, status, "text/html; charset=utf-8", &body).await;
+
+    Some(res)
+}
+
+/// Serves redirect requests on the listener until the provider redirects back with an
+/// authorization code or an error, or the timeout elapses.
+pub async fn wait_for_code(
+    listener: TcpListener,
+    csrf_state: CsrfToken,
+    timeout: Duration,
+    shutdown_receiver: tokio::sync::oneshot::Receiver<()>,
+) -> anyhow::Result<AuthorizationCode> {
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let serve = async move {
+        loop {
+            tokio::select! {
+                conn = listener.accept() => {
+                    let (stream, _addr) = conn
+                        .map_err(|e| anyhow::anyhow!("Error accepting connection {:?}", e))?;
+                    let (tx, csrf_state) = (tx.clone(), csrf_state.clone());
+                    tokio::spawn(async move {
+                        if let Some(res) = handle_connection(stream, &csrf_state).await {
+                            let _ = tx.send(res).await;
+                        }
+                    });
+                }
+                Some(res) = rx.recv() => return res,
+            }
+        }
+    };
+
+    tokio::select! {
+        res = tokio::time::timeout(timeout.to_std()?, serve) => match res {
+            Ok(res) => res,
+            Err(_) => Err(anyhow::anyhow!(
+                "Timed out after {} minute(s) waiting for authorization",
+                timeout.num_minutes()
+            )),
+        },
+        // The shutdown signal has been received, so shutdown the TcpListener
+        _ = shutdown_receiver => Err(anyhow::anyhow!("Authorization was cancelled")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(
+            "GET /redirect?code=abc HTTP/1.1\r\nHost: localhost:3003\r\nConnection: keep-alive\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "GET".to_string(),
+                target: "/redirect?code=abc".to_string(),
+            }
+        );
+
+        assert!(parse_request("GET /favicon.ico HTTP/1.1\r\n").is_ok());
+        assert!(parse_request("GET /redirect\r\n").is_err());
+        assert!(parse_request("GET /redirect HTTP/2\r\n").is_err());
+        assert!(parse_request("GET /redirect HTTP/1.1\r\nHost localhost\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        let state = CsrfToken::new("state123".to_string());
+
+        let code = parse_redirect("/redirect?code=abc&state=state123", &state).unwrap();
+        assert_eq!(code.secret(), "abc");
+
+        let err = parse_redirect("/redirect?code=abc&state=other", &state).unwrap_err();
+        assert!(err.to_string().contains("state"));
+        assert!(parse_redirect("/redirect?code=abc", &state).is_err());
+        assert!(parse_redirect("/redirect?state=state123", &state).is_err());
+
+        let err = parse_redirect(
+            "/redirect?error=access_denied&error_description=The+user+cancelled&state=state123",
+            &state,
+        )
+        .unwrap_err();
+        assert!(err.is::<AuthorizationError>());
+        assert_eq!(err.to_string(), "Access was denied: The user cancelled");
+
+        let err = parse_redirect("/redirect?error=invalid_scope", &state).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Authorization failed with \"invalid_scope\""
+        );
+    }
+
+    #[test]
+    fn test_html_page() {
+        let page = html_page("Unable to sign in", "<script>\"x\" & 'y'</script>");
+        assert!(page.contains("&lt;script&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/script&gt;"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_code() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = CsrfToken::new("state123".to_string());
+        let (_shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel();
+
+        let server = tokio::spawn(wait_for_code(
+            listener,
+            state,
+            Duration::seconds(10),
+            shutdown_receiver,
+        ));
+
+        // An idle connection and a favicon request don't end the flow.
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let mut favicon = TcpStream::connect(addr).await.unwrap();
+        favicon
+            .write_all(b"GET /favicon.ico HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        // The connection is kept open after the request, as browsers do.
+        let mut redirect = TcpStream::connect(addr).await.unwrap();
+        redirect
+            .write_all(b"GET /redirect?code=abc&state=state123 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let code = server.await.unwrap().unwrap();
+        assert_eq!(code.secret(), "abc");
+    }
+}
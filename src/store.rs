@@ -1,7 +1,14 @@
+use chrono::prelude::*;
 use rusqlite::Connection;
 
+use crate::events::ShowAs;
+use crate::migrations::{self, Migration};
+
+/// A handle to the database, clones share the same connection.
+#[derive(Clone)]
 pub struct Store {
-    connection: Connection,
+    sender: std::sync::mpsc::Sender<Job>,
+    path: String,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -26,6 +33,15 @@ impl From<&std::string::String> for Platform {
 pub const PLATFORMS: [Platform; 2] = [Platform::Google, Platform::Microsoft];
 
 impl Platform {
+    /// Short name used in the keys tokens are stored under.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Platform::Microsoft => "microsoft",
+            Platform::Google => "google",
+            Platform::Unsupported => "unsupported",
+        }
+    }
+
     fn as_str(&self) -> &'static str {
         match self {
             Platform::Microsoft => OUTLOOK,
@@ -41,10 +57,13 @@ impl std::fmt::Display for Platform {
     }
 }
 
+#[derive(Clone)]
 pub struct AccountModel {
     pub id: Option<u32>,
     pub name: String,
     pub platform: Option<Platform>,
+    // Set when the refresh token has expired or was revoked.
+    pub needs_reauth: bool,
 }
 
 impl std::fmt::Display for AccountModel {
@@ -54,13 +73,27 @@ impl std::fmt::Display for AccountModel {
 }
 
 impl AccountModel {
+    /// The user the account's tokens are stored under, unique even if the same email is
+    /// used on several platforms.
+    pub fn token_user(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.platform.unwrap().key(),
+            self.id.unwrap(),
+            self.name
+        )
+    }
+
     pub fn get(conn: &Connection) -> anyhow::Result<Vec<AccountModel>> {
-        let mut stmt = conn.prepare("SELECT id, name, platform FROM accounts")?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, platform, account_id IS NOT NULL FROM accounts LEFT JOIN account_reauths ON account_id = id",
+        )?;
         let accounts: Vec<AccountModel> = stmt
             .query_map([], |row| {
                 let id: u32 = row.get(0)?;
                 let name: String = row.get(1)?;
                 let platform_str: String = row.get(2)?;
+                let needs_reauth: bool = row.get(3)?;
 
                 let platform = if platform_str == Platform::Microsoft.as_str() {
                     Platform::Microsoft
@@ -72,6 +105,7 @@ impl AccountModel {
                     id: Some(id),
                     name,
                     platform: Some(platform),
+                    needs_reauth,
                 })
             })?
             .filter_map(|s| s.ok())
@@ -81,7 +115,8 @@ impl AccountModel {
 
     // pub fn get_uncached_calendar()
 
-    pub fn insert(&self, conn: &Connection) -> anyhow::Result<()> {
+    /// Inserts the account, returning its id.
+    pub fn insert(&self, conn: &Connection) -> anyhow::Result<u32> {
         conn.execute(
             "INSERT INTO accounts (name, platform) VALUES (?1, ?2)",
             [
@@ -89,14 +124,30 @@ impl AccountModel {
                 self.platform.as_ref().unwrap().as_str().to_string(),
             ],
         )?;
-        Ok(())
+        Ok(conn.last_insert_rowid() as u32)
     }
 
     pub fn delete(&self, conn: &Connection) -> anyhow::Result<()> {
-        conn.execute(
-            "DELETE FROM accounts where name = ?",
-            [self.name.to_owned()],
-        )?;
+        conn.execute("DELETE FROM accounts where id = ?", [self.id.unwrap()])?;
+        Ok(())
+    }
+
+    pub fn set_needs_reauth(
+        conn: &Connection,
+        account_id: &u32,
+        needs_reauth: bool,
+    ) -> anyhow::Result<()> {
+        if needs_reauth {
+            conn.execute(
+                "INSERT OR IGNORE INTO account_reauths (account_id) VALUES (?)",
+                [account_id],
+            )?;
+        } else {
+            conn.execute(
+                "DELETE FROM account_reauths where account_id = ?",
+                [account_id],
+            )?;
+        }
         Ok(())
     }
 }
@@ -105,38 +156,11 @@ pub struct CalendarModel {
     pub account_id: Option<u32>,
     pub id: String,
     pub name: String,
-    // Used to indicate query and use_for_hold_events.
+    // Used to indicate query.
     pub selected: bool,
 }
 
 impl CalendarModel {
-    pub fn insert_many(conn: &Connection, calendars: Vec<CalendarModel>) -> anyhow::Result<()> {
-        let mut stmt = conn.prepare("INSERT INTO calendars (account_id, id, name, query, can_edit, use_for_hold_events) VALUES (?, ?, ?, ?, ?, ?)")?;
-        for cal in calendars.into_iter() {
-            stmt.execute((
-                cal.account_id.unwrap(),
-                cal.id,
-                cal.name,
-                cal.selected, // query
-                false,        // can_edit
-                false,        // use_for_hold_events
-            ))?;
-        }
-        Ok(())
-    }
-
-    pub fn update_hold_event_calendar(conn: &Connection, cal: CalendarModel) -> anyhow::Result<()> {
-        // Set all to false.
-        conn.execute("UPDATE calendars SET use_for_hold_events = false", ())?;
-
-        let mut stmt = conn.prepare(
-            "UPDATE calendars SET use_for_hold_events = true where account_id = ? and id = ?",
-        )?;
-        stmt.execute((cal.account_id.unwrap(), cal.id))?;
-
-        Ok(())
-    }
-
     pub fn get_all(conn: &Connection) -> anyhow::Result<Vec<CalendarModel>> {
         let mut stmt = conn.prepare("SELECT account_id, id, name FROM calendars")?;
         let prev_unselected_calendars: Vec<CalendarModel> = stmt
@@ -182,109 +206,1140 @@ impl CalendarModel {
         Ok(prev_unselected_calendars)
     }
 
-    pub fn get_hold_event_calendar(
+    /// Replaces the account's calendars, keeping the selection, hold flag and cached events
+    /// of calendars that still exist. New calendars are selected if `selected` is set.
+    pub fn replace_for_account(
         conn: &Connection,
-    ) -> anyhow::Result<Option<(String, CalendarModel)>> {
+        account_id: &u32,
+        calendars: Vec<CalendarModel>,
+    ) -> anyhow::Result<()> {
+        let tx = conn.unchecked_transaction()?;
+
+        let existing: Vec<String> = CalendarModel::get_all(&tx)?
+            .into_iter()
+            .filter(|c| c.account_id == Some(*account_id))
+            .map(|c| c.id)
+            .collect();
+        for id in existing {
+            if !calendars.iter().any(|c| c.id == id) {
+                tx.execute(
+                    "DELETE FROM calendars where account_id = ? and id = ?",
+                    (account_id, id),
+                )?;
+            }
+        }
+
+        {
+            let mut stmt = tx.prepare(
+                "
+                    INSERT INTO calendars (account_id, id, name, query, can_edit) VALUES (?, ?, ?, ?, false)
+                    ON CONFLICT (account_id, id) DO UPDATE SET name = excluded.name
+                ",
+            )?;
+            for cal in calendars.into_iter() {
+                stmt.execute((account_id, cal.id, cal.name, cal.selected))?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Sets whether the calendar's events are used when querying.
+    pub fn set_selected(
+        conn: &Connection,
+        account_id: &u32,
+        id: &str,
+        selected: bool,
+    ) -> anyhow::Result<()> {
+        conn.execute(
+            "UPDATE calendars SET query = ? where account_id = ? and id = ?",
+            (selected, account_id, id),
+        )?;
+        Ok(())
+    }
+
+    /// Sets whether hold events are created in the calendar.
+    pub fn set_hold(
+        conn: &Connection,
+        account_id: &u32,
+        id: &str,
+        hold: bool,
+    ) -> anyhow::Result<()> {
+        if hold {
+            conn.execute(
+                "INSERT OR IGNORE INTO hold_calendars (account_id, calendar_id) VALUES (?, ?)",
+                (account_id, id),
+            )?;
+        } else {
+            conn.execute(
+                "DELETE FROM hold_calendars where account_id = ? and calendar_id = ?",
+                (account_id, id),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct EventModel {
+    pub account_id: u32,
+    pub calendar_id: String,
+    pub id: String,
+    pub name: Option<String>,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub marker: Option<String>,
+    pub show_as: ShowAs,
+}
+
+fn timestamp_to_local(timestamp: i64) -> DateTime<Local> {
+    Utc.timestamp(timestamp, 0).with_timezone(&Local)
+}
+
+impl EventModel {
+    pub fn insert_many(conn: &Connection, events: Vec<EventModel>) -> anyhow::Result<()> {
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO events (account_id, calendar_id, id, name, start, end, marker, show_as) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for event in events.into_iter() {
+            stmt.execute((
+                event.account_id,
+                event.calendar_id,
+                event.id,
+                event.name,
+                event.start.timestamp(),
+                event.end.timestamp(),
+                event.marker,
+                event.show_as.as_str(),
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_many(
+        conn: &Connection,
+        account_id: &u32,
+        calendar_id: &str,
+        ids: Vec<String>,
+    ) -> anyhow::Result<()> {
         let mut stmt =
-            conn.prepare("SELECT c.account_id, c.id, c.name, a.platform FROM calendars c JOIN accounts a on c.account_id = a.id where use_for_hold_events = true;")?;
-        let calendars: Vec<(String, CalendarModel)> = stmt
-            .query_map((), |row| {
-                let account_id: u32 = row.get(0)?;
-                let id: String = row.get(1)?;
-                let name: String = row.get(2)?;
-                let platform: String = row.get(3)?;
-                Ok((
-                    platform,
-                    CalendarModel {
-                        account_id: Some(account_id),
-                        id,
-                        name,
-                        selected: false,
-                    },
-                ))
+            conn.prepare("DELETE FROM events where account_id = ? and calendar_id = ? and id = ?")?;
+        for id in ids.into_iter() {
+            stmt.execute((account_id, calendar_id, id))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_for_calendar(
+        conn: &Connection,
+        account_id: &u32,
+        calendar_id: &str,
+    ) -> anyhow::Result<()> {
+        conn.execute(
+            "DELETE FROM events where account_id = ? and calendar_id = ?",
+            (account_id, calendar_id),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the events of the calendar that overlap with the start and end time.
+    pub fn get_between(
+        conn: &Connection,
+        account_id: &u32,
+        calendar_id: &str,
+        start: DateTime<Local>,
+        end: DateTime<Local>,
+    ) -> anyhow::Result<Vec<EventModel>> {
+        let mut stmt = conn.prepare(
+            "SELECT account_id, calendar_id, id, name, start, end, marker, show_as FROM events where account_id = ?1 and calendar_id = ?2 and start < ?4 and end > ?3",
+        )?;
+        let events: Vec<EventModel> = stmt
+            .query_map(
+                (account_id, calendar_id, start.timestamp(), end.timestamp()),
+                EventModel::from_row,
+            )?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(events)
+    }
+
+    /// Returns the events that avail marked as `kind`, e.g. holds, ending after `after`.
+    pub fn get_marked(
+        conn: &Connection,
+        kind: &str,
+        after: DateTime<Local>,
+    ) -> anyhow::Result<Vec<EventModel>> {
+        let mut stmt = conn.prepare(
+            "SELECT account_id, calendar_id, id, name, start, end, marker, show_as FROM events where marker LIKE ?1 || ':%' and end > ?2",
+        )?;
+        let events: Vec<EventModel> = stmt
+            .query_map((kind, after.timestamp()), EventModel::from_row)?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(events)
+    }
+
+    pub fn set_marker(
+        conn: &Connection,
+        account_id: &u32,
+        calendar_id: &str,
+        id: &str,
+        marker: &str,
+    ) -> anyhow::Result<()> {
+        conn.execute(
+            "UPDATE events SET marker = ? where account_id = ? and calendar_id = ? and id = ?",
+            (marker, account_id, calendar_id, id),
+        )?;
+        Ok(())
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<EventModel> {
+        let start: i64 = row.get(4)?;
+        let end: i64 = row.get(5)?;
+        let show_as: String = row.get(7)?;
+        Ok(EventModel {
+            account_id: row.get(0)?,
+            calendar_id: row.get(1)?,
+            id: row.get(2)?,
+            name: row.get(3)?,
+            start: timestamp_to_local(start),
+            end: timestamp_to_local(end),
+            marker: row.get(6)?,
+            show_as: ShowAs::from(show_as.as_str()),
+        })
+    }
+
+    pub fn delete_all(conn: &Connection) -> anyhow::Result<()> {
+        conn.execute("DELETE FROM events", ())?;
+        conn.execute("DELETE FROM calendar_syncs", ())?;
+        Ok(())
+    }
+}
+
+/// The incremental sync state of a calendar's cached events.
+pub struct SyncModel {
+    pub account_id: u32,
+    pub calendar_id: String,
+    pub sync_token: String,
+    // The time range of events that were synced.
+    pub window_start: DateTime<Local>,
+    pub window_end: DateTime<Local>,
+    pub last_synced: DateTime<Local>,
+}
+
+impl SyncModel {
+    pub fn get(
+        conn: &Connection,
+        account_id: &u32,
+        calendar_id: &str,
+    ) -> anyhow::Result<Option<SyncModel>> {
+        let mut stmt = conn.prepare(
+            "SELECT sync_token, window_start, window_end, last_synced FROM calendar_syncs where account_id = ? and calendar_id = ?",
+        )?;
+        let syncs: Vec<SyncModel> = stmt
+            .query_map((account_id, calendar_id), |row| {
+                let sync_token: String = row.get(0)?;
+                let window_start: i64 = row.get(1)?;
+                let window_end: i64 = row.get(2)?;
+                let last_synced: i64 = row.get(3)?;
+                Ok(SyncModel {
+                    account_id: *account_id,
+                    calendar_id: calendar_id.to_owned(),
+                    sync_token,
+                    window_start: timestamp_to_local(window_start),
+                    window_end: timestamp_to_local(window_end),
+                    last_synced: timestamp_to_local(last_synced),
+                })
             })?
             .filter_map(|s| s.ok())
             .collect();
 
-        let res = calendars.get(0);
-        if let Some((platform, cal)) = res {
-            Ok(Some((
-                platform.to_owned(),
-                CalendarModel {
-                    account_id: cal.account_id,
-                    id: cal.id.to_owned(),
-                    name: cal.name.to_owned(),
-                    selected: false,
-                },
-            )))
-        } else {
-            Ok(None)
+        Ok(syncs.into_iter().next())
+    }
+
+    /// Applies synced changes to the cached events and records the new sync state.
+    pub fn save_changes(
+        &self,
+        conn: &Connection,
+        full: bool,
+        events: Vec<EventModel>,
+        deleted: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let tx = conn.unchecked_transaction()?;
+        if full {
+            EventModel::delete_for_calendar(&tx, &self.account_id, &self.calendar_id)?;
         }
+        EventModel::insert_many(&tx, events)?;
+        EventModel::delete_many(&tx, &self.account_id, &self.calendar_id, deleted)?;
+        self.upsert(&tx)?;
+        tx.commit()?;
+        Ok(())
     }
 
-    pub fn delete_for_account(conn: &Connection, account_id: &u32) -> anyhow::Result<()> {
-        conn.execute("DELETE FROM calendars where account_id = ?", [account_id])?;
+    fn upsert(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO calendar_syncs (account_id, calendar_id, sync_token, window_start, window_end, last_synced) VALUES (?, ?, ?, ?, ?, ?)",
+            (
+                self.account_id,
+                &self.calendar_id,
+                &self.sync_token,
+                self.window_start.timestamp(),
+                self.window_end.timestamp(),
+                self.last_synced.timestamp(),
+            ),
+        )?;
         Ok(())
     }
 }
 
-impl Store {
-    pub fn new(path: &str) -> Self {
-        let conn = Connection::open(path).expect("failed to open database");
-        conn.execute("PRAGMA foreign_keys = true", ())
-            .expect("failed to enable foreign keys");
+pub struct CacheStats {
+    pub account: String,
+    pub calendar: String,
+    pub events: u32,
+    pub last_synced: Option<DateTime<Local>>,
+}
+
+/// A hold event created by avail, kept so it can be released later.
+#[derive(Clone)]
+pub struct HoldModel {
+    pub id: Option<u32>,
+    pub account_id: u32,
+    pub calendar_id: String,
+    /// The id of the event in the provider's calendar.
+    pub event_id: String,
+    pub title: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    pub created: DateTime<Local>,
+    /// Holds created by the same search share a query id.
+    pub query_id: u32,
+    /// When the hold can be released by `holds gc`, if ever.
+    pub expires: Option<DateTime<Local>>,
+    /// The version of the event when it was created, used to detect holds that were edited.
+    pub etag: Option<String>,
+}
+
+impl HoldModel {
+    /// The query id for the holds of a new search, ids of released holds aren't reused so
+    /// their markers can't be mistaken for the new ones.
+    pub fn next_query_id(conn: &Connection) -> anyhow::Result<u32> {
+        conn.execute("INSERT INTO hold_queries DEFAULT VALUES", ())?;
+        let id = conn.last_insert_rowid();
+        // AUTOINCREMENT keeps counting after the previous ids are deleted.
+        conn.execute("DELETE FROM hold_queries WHERE id < ?", (id,))?;
+        Ok(id as u32)
+    }
+
+    pub fn insert(&self, conn: &Connection) -> anyhow::Result<u32> {
         conn.execute(
-            "
-                CREATE TABLE IF NOT EXISTS accounts (
-                    id          INTEGER PRIMARY KEY,
-                    name        TEXT NOT NULL UNIQUE,
-                    platform    TEXT NOT NULL
-                );
-            ",
-            (),
+            "INSERT INTO holds (account_id, calendar_id, event_id, title, start, end, created, query_id, expires, etag) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                self.account_id,
+                &self.calendar_id,
+                &self.event_id,
+                &self.title,
+                self.start.timestamp(),
+                self.end.timestamp(),
+                self.created.timestamp(),
+                self.query_id,
+                self.expires.map(|e| e.timestamp()),
+                &self.etag,
+            ),
+        )?;
+        Ok(conn.last_insert_rowid() as u32)
+    }
+
+    pub fn get_all(conn: &Connection) -> anyhow::Result<Vec<HoldModel>> {
+        Self::query(conn, "ORDER BY start, id", ())
+    }
+
+    /// Holds that expired by `now`.
+    pub fn get_expired(conn: &Connection, now: DateTime<Local>) -> anyhow::Result<Vec<HoldModel>> {
+        Self::query(
+            conn,
+            "WHERE expires IS NOT NULL AND expires <= ? ORDER BY expires, id",
+            [now.timestamp()],
         )
-        .expect("failed to create accounts table");
+    }
+
+    fn query<P: rusqlite::Params>(
+        conn: &Connection,
+        clauses: &str,
+        params: P,
+    ) -> anyhow::Result<Vec<HoldModel>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, account_id, calendar_id, event_id, title, start, end, created, query_id, expires, etag FROM holds {}",
+            clauses
+        ))?;
+        let holds: Vec<HoldModel> = stmt
+            .query_map(params, |row| {
+                let start: i64 = row.get(5)?;
+                let end: i64 = row.get(6)?;
+                let created: i64 = row.get(7)?;
+                Ok(HoldModel {
+                    id: row.get(0)?,
+                    account_id: row.get(1)?,
+                    calendar_id: row.get(2)?,
+                    event_id: row.get(3)?,
+                    title: row.get(4)?,
+                    start: timestamp_to_local(start),
+                    end: timestamp_to_local(end),
+                    created: timestamp_to_local(created),
+                    query_id: row.get(8)?,
+                    expires: row.get::<_, Option<i64>>(9)?.map(timestamp_to_local),
+                    etag: row.get(10)?,
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(holds)
+    }
+
+    pub fn delete(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute("DELETE FROM holds where id = ?", [self.id.unwrap()])?;
+        Ok(())
+    }
+}
+
+/// A calendar's membership in a named group of calendars.
+pub struct CalendarGroupModel {
+    pub name: String,
+    pub account_id: u32,
+    pub calendar_id: String,
+}
+
+impl CalendarGroupModel {
+    pub fn get_all(conn: &Connection) -> anyhow::Result<Vec<CalendarGroupModel>> {
+        let mut stmt = conn
+            .prepare("SELECT name, account_id, calendar_id FROM calendar_groups ORDER BY name")?;
+        let groups: Vec<CalendarGroupModel> = stmt
+            .query_map((), |row| {
+                Ok(CalendarGroupModel {
+                    name: row.get(0)?,
+                    account_id: row.get(1)?,
+                    calendar_id: row.get(2)?,
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(groups)
+    }
+
+    pub fn insert(&self, conn: &Connection) -> anyhow::Result<()> {
         conn.execute(
+            "INSERT OR IGNORE INTO calendar_groups (name, account_id, calendar_id) VALUES (?, ?, ?)",
+            (&self.name, self.account_id, &self.calendar_id),
+        )?;
+        Ok(())
+    }
+
+    /// Removes the calendar from the group, returning whether it was a member.
+    pub fn delete(&self, conn: &Connection) -> anyhow::Result<bool> {
+        let deleted = conn.execute(
+            "DELETE FROM calendar_groups where name = ? and account_id = ? and calendar_id = ?",
+            (&self.name, self.account_id, &self.calendar_id),
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Deletes the group, returning the number of calendars that were in it.
+    pub fn delete_group(conn: &Connection, name: &str) -> anyhow::Result<usize> {
+        Ok(conn.execute("DELETE FROM calendar_groups where name = ?", [name])?)
+    }
+}
+
+/// A source calendar whose events are mirrored as busy blocks in a target calendar.
+#[derive(Clone)]
+pub struct SyncPairModel {
+    pub id: Option<u32>,
+    pub source_account_id: u32,
+    pub source_calendar_id: String,
+    pub target_account_id: u32,
+    pub target_calendar_id: String,
+}
+
+impl SyncPairModel {
+    pub fn get_all(conn: &Connection) -> anyhow::Result<Vec<SyncPairModel>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, source_account_id, source_calendar_id, target_account_id, target_calendar_id FROM sync_pairs ORDER BY id",
+        )?;
+        let pairs: Vec<SyncPairModel> = stmt
+            .query_map((), |row| {
+                Ok(SyncPairModel {
+                    id: row.get(0)?,
+                    source_account_id: row.get(1)?,
+                    source_calendar_id: row.get(2)?,
+                    target_account_id: row.get(3)?,
+                    target_calendar_id: row.get(4)?,
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(pairs)
+    }
+
+    /// Adds the pair, returning whether it didn't exist yet.
+    pub fn insert(&self, conn: &Connection) -> anyhow::Result<bool> {
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO sync_pairs (source_account_id, source_calendar_id, target_account_id, target_calendar_id) VALUES (?, ?, ?, ?)",
+            (
+                self.source_account_id,
+                &self.source_calendar_id,
+                self.target_account_id,
+                &self.target_calendar_id,
+            ),
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Deletes the pair along with its busy blocks.
+    pub fn delete(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute("DELETE FROM sync_pairs where id = ?", [self.id.unwrap()])?;
+        Ok(())
+    }
+}
+
+/// A busy block created in the target calendar of a pair for an event of its source
+/// calendar.
+#[derive(Clone)]
+pub struct BusyBlockModel {
+    pub pair_id: u32,
+    pub source_event_id: String,
+    pub target_event_id: String,
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+}
+
+impl BusyBlockModel {
+    pub fn get_all(conn: &Connection) -> anyhow::Result<Vec<BusyBlockModel>> {
+        let mut stmt = conn.prepare(
+            "SELECT pair_id, source_event_id, target_event_id, start, end FROM busy_blocks ORDER BY start",
+        )?;
+        let blocks: Vec<BusyBlockModel> = stmt
+            .query_map((), |row| {
+                let start: i64 = row.get(3)?;
+                let end: i64 = row.get(4)?;
+                Ok(BusyBlockModel {
+                    pair_id: row.get(0)?,
+                    source_event_id: row.get(1)?,
+                    target_event_id: row.get(2)?,
+                    start: timestamp_to_local(start),
+                    end: timestamp_to_local(end),
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(blocks)
+    }
+
+    /// Inserts the block, replacing the previous block of the same source event.
+    pub fn upsert(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO busy_blocks (pair_id, source_event_id, target_event_id, start, end) VALUES (?, ?, ?, ?, ?)",
+            (
+                self.pair_id,
+                &self.source_event_id,
+                &self.target_event_id,
+                self.start.timestamp(),
+                self.end.timestamp(),
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &Connection) -> anyhow::Result<()> {
+        conn.execute(
+            "DELETE FROM busy_blocks where pair_id = ? and source_event_id = ?",
+            (self.pair_id, &self.source_event_id),
+        )?;
+        Ok(())
+    }
+}
+
+/// A calendar with the account it belongs to, as shown by `calendars list`.
+#[derive(Clone)]
+pub struct CalendarInfo {
+    pub account_id: u32,
+    pub account: String,
+    pub id: String,
+    pub name: String,
+    pub selected: bool,
+    pub hold: bool,
+}
+
+impl CalendarInfo {
+    pub fn get(conn: &Connection) -> anyhow::Result<Vec<CalendarInfo>> {
+        let mut stmt = conn.prepare(
             "
-                CREATE TABLE IF NOT EXISTS calendars (
-                    account_id  INTEGER NOT NULL,
-                    id TEXT NOT NULL,
-                    name TEXT NOT NULL,
-                    query BOOLEAN,
-                    can_edit BOOLEAN,
-                    use_for_hold_events BOOLEAN,
-                    PRIMARY KEY (account_id, id),
-                    FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
-                );
+                SELECT c.account_id, a.name, c.id, c.name, c.query,
+                    EXISTS (SELECT 1 FROM hold_calendars h WHERE h.account_id = c.account_id and h.calendar_id = c.id)
+                FROM calendars c
+                JOIN accounts a on c.account_id = a.id
+                ORDER BY a.name, c.name
             ",
-            (),
-        )
-        .expect("failed to create calendars table");
+        )?;
+        let calendars: Vec<CalendarInfo> = stmt
+            .query_map((), |row| {
+                let selected: Option<bool> = row.get(4)?;
+                let hold: bool = row.get(5)?;
+                Ok(CalendarInfo {
+                    account_id: row.get(0)?,
+                    account: row.get(1)?,
+                    id: row.get(2)?,
+                    name: row.get(3)?,
+                    selected: selected.unwrap_or(false),
+                    hold,
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
 
-        Self { connection: conn }
+        Ok(calendars)
     }
+}
 
-    pub fn execute<T>(&self, func: Box<dyn FnOnce(&Connection) -> T>) -> anyhow::Result<T> {
-        Ok(func(&self.connection))
+impl CacheStats {
+    pub fn get(conn: &Connection) -> anyhow::Result<Vec<CacheStats>> {
+        let mut stmt = conn.prepare(
+            "
+                SELECT a.name, c.name, (SELECT COUNT(*) FROM events e WHERE e.account_id = c.account_id and e.calendar_id = c.id), s.last_synced
+                FROM calendars c
+                JOIN accounts a on c.account_id = a.id
+                LEFT JOIN calendar_syncs s on s.account_id = c.account_id and s.calendar_id = c.id
+                WHERE c.query = true
+                ORDER BY a.name, c.name
+            ",
+        )?;
+        let stats: Vec<CacheStats> = stmt
+            .query_map((), |row| {
+                let account: String = row.get(0)?;
+                let calendar: String = row.get(1)?;
+                let events: u32 = row.get(2)?;
+                let last_synced: Option<i64> = row.get(3)?;
+                Ok(CacheStats {
+                    account,
+                    calendar,
+                    events,
+                    last_synced: last_synced.map(timestamp_to_local),
+                })
+            })?
+            .filter_map(|s| s.ok())
+            .collect();
+
+        Ok(stats)
     }
 }
 
-const SERVICE_NAME: &str = "avail";
+type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+impl Store {
+    /// Opens the database without migrating it.
+    ///
+    /// The connection is owned by a dedicated thread that runs the queries sent by all
+    /// clones of the store, so blocking sqlite calls never run on the async runtime.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute("PRAGMA foreign_keys = true", ())?;
+
+        let (sender, receiver) = std::sync::mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("store".to_string())
+            .spawn(move || {
+                // Runs until all clones of the store are dropped.
+                for job in receiver {
+                    job(&conn);
+                }
+            })?;
 
-pub fn store_token(user: &str, token: &str) -> anyhow::Result<()> {
-    let entry = keyring::Entry::new(SERVICE_NAME, user);
-    entry.set_password(token)?;
-    Ok(())
+        Ok(Self {
+            sender,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The path the database is copied to before it's migrated.
+    pub async fn backup_path(&self) -> anyhow::Result<Option<String>> {
+        let path = self.path.to_owned();
+        self.execute(move |conn| backup_path(conn, &path)).await
+    }
+
+    /// Applies pending migrations, backing up the database first if it has any data.
+    /// Returns the applied migrations and the path of the backup.
+    pub async fn migrate(&self) -> anyhow::Result<(Vec<&'static Migration>, Option<String>)> {
+        let path = self.path.to_owned();
+        self.execute(move |conn| {
+            if migrations::pending(conn)?.is_empty() {
+                return Ok((vec![], None));
+            }
+
+            let backup = backup_path(conn, &path)?;
+            if let Some(path) = &backup {
+                migrations::backup(conn, path)?;
+            }
+            Ok((migrations::migrate(conn)?, backup))
+        })
+        .await
+    }
+
+    /// Runs `func` with the connection on the store's thread.
+    pub async fn execute<T, F>(&self, func: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(Box::new(move |conn| {
+                let _ = tx.send(func(conn));
+            }))
+            .map_err(|_| anyhow::anyhow!("The database connection was closed"))?;
+        rx.await
+            .map_err(|_| anyhow::anyhow!("The database connection was closed"))?
+    }
 }
 
-pub fn get_token(user: &str) -> anyhow::Result<String> {
-    let entry = keyring::Entry::new(SERVICE_NAME, user);
-    let token = entry.get_password()?;
-    Ok(token)
+fn backup_path(conn: &Connection, path: &str) -> anyhow::Result<Option<String>> {
+    if path == ":memory:" || !migrations::has_tables(conn)? {
+        return Ok(None);
+    }
+    let version = migrations::current_version(conn)?;
+    Ok(Some(format!("{}.v{}.bak", path, version)))
 }
 
-pub fn delete_token(user: &str) -> anyhow::Result<()> {
-    let entry = keyring::Entry::new(SERVICE_NAME, user);
-    entry.delete_password()?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    async fn create_store() -> Store {
+        let db = Store::open(":memory:").unwrap();
+        db.migrate().await.unwrap();
+        let account = AccountModel {
+            id: None,
+            name: "me@example.com".to_string(),
+            platform: Some(Platform::Google),
+            needs_reauth: false,
+        };
+        db.execute(move |conn| account.insert(conn)).await.unwrap();
+        let calendar = CalendarModel {
+            account_id: Some(1),
+            id: "primary".to_string(),
+            name: "Primary".to_string(),
+            selected: true,
+        };
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, vec![calendar]))
+            .await
+            .unwrap();
+        db
+    }
+
+    fn create_event(id: &str, start: DateTime<Local>, hours: i64) -> EventModel {
+        EventModel {
+            account_id: 1,
+            calendar_id: "primary".to_string(),
+            id: id.to_string(),
+            name: None,
+            start,
+            end: start + Duration::hours(hours),
+            marker: None,
+            show_as: ShowAs::Busy,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_changes() {
+        let db = create_store().await;
+        let now = Local.timestamp(Local::now().timestamp(), 0);
+        let sync = SyncModel {
+            account_id: 1,
+            calendar_id: "primary".to_string(),
+            sync_token: "token".to_string(),
+            window_start: now,
+            window_end: now + Duration::weeks(4),
+            last_synced: now,
+        };
+
+        let get_events = |db: Store| async move {
+            db.execute(move |conn| {
+                EventModel::get_between(conn, &1, "primary", now, now + Duration::days(1))
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.id)
+            .collect::<Vec<String>>()
+        };
+
+        // Full sync
+        let events = vec![
+            create_event("a", now, 1),
+            create_event("b", now + Duration::hours(2), 1),
+            create_event("c", now + Duration::days(2), 1),
+        ];
+        db.execute(move |conn| sync.save_changes(conn, true, events, vec![]))
+            .await
+            .unwrap();
+        assert_eq!(get_events(db.clone()).await, vec!["a", "b"]);
+
+        // Incremental sync, "b" was moved and "a" was deleted.
+        let sync = db
+            .execute(|conn| SyncModel::get(conn, &1, "primary"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sync.sync_token, "token");
+        assert_eq!(sync.last_synced, now);
+
+        let events = vec![create_event("b", now + Duration::days(3), 1)];
+        db.execute(move |conn| sync.save_changes(conn, false, events, vec!["a".to_string()]))
+            .await
+            .unwrap();
+        assert!(get_events(db.clone()).await.is_empty());
+
+        // Removing calendars removes their cached events.
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, vec![]))
+            .await
+            .unwrap();
+        let synced = db
+            .execute(|conn| SyncModel::get(conn, &1, "primary"))
+            .await
+            .unwrap();
+        assert!(synced.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_marked_events() {
+        let db = create_store().await;
+        let now = Local.timestamp(Local::now().timestamp(), 0);
+        let events = vec![
+            EventModel {
+                marker: Some("hold:1".to_string()),
+                ..create_event("a", now, 1)
+            },
+            EventModel {
+                marker: Some("busy:1".to_string()),
+                ..create_event("b", now, 1)
+            },
+            EventModel {
+                marker: Some("hold:2".to_string()),
+                ..create_event("c", now - Duration::days(1), 1)
+            },
+            create_event("d", now, 1),
+        ];
+        db.execute(move |conn| EventModel::insert_many(conn, events))
+            .await
+            .unwrap();
+
+        let get_holds = |db: Store| async move {
+            db.execute(move |conn| EventModel::get_marked(conn, "hold", now))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|e| e.id)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(get_holds(db.clone()).await, vec!["a"]);
+
+        db.execute(|conn| EventModel::set_marker(conn, &1, "primary", "a", "meeting:1"))
+            .await
+            .unwrap();
+        assert!(get_holds(db.clone()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_needs_reauth() {
+        let db = create_store().await;
+        let needs_reauth =
+            |db: Store| async move { db.execute(AccountModel::get).await.unwrap()[0].needs_reauth };
+        assert!(!needs_reauth(db.clone()).await);
+
+        for _ in 0..2 {
+            db.execute(|conn| AccountModel::set_needs_reauth(conn, &1, true))
+                .await
+                .unwrap();
+        }
+        assert!(needs_reauth(db.clone()).await);
+
+        db.execute(|conn| AccountModel::set_needs_reauth(conn, &1, false))
+            .await
+            .unwrap();
+        assert!(!needs_reauth(db.clone()).await);
+    }
+
+    #[tokio::test]
+    async fn test_replace_for_account() {
+        let db = create_store().await;
+        let calendar = |id: &str, name: &str| CalendarModel {
+            account_id: Some(1),
+            id: id.to_string(),
+            name: name.to_string(),
+            selected: true,
+        };
+        db.execute(|conn| CalendarModel::set_hold(conn, &1, "primary", true))
+            .await
+            .unwrap();
+        db.execute(|conn| CalendarModel::set_selected(conn, &1, "primary", false))
+            .await
+            .unwrap();
+        let event = create_event("a", Local::now(), 1);
+        db.execute(|conn| EventModel::insert_many(conn, vec![event]))
+            .await
+            .unwrap();
+
+        let calendars = vec![calendar("primary", "Personal"), calendar("work", "Work")];
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, calendars))
+            .await
+            .unwrap();
+        let calendars = db.execute(CalendarInfo::get).await.unwrap();
+        let summary: Vec<(&str, &str, bool, bool)> = calendars
+            .iter()
+            .map(|c| (c.id.as_str(), c.name.as_str(), c.selected, c.hold))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("primary", "Personal", false, true),
+                ("work", "Work", true, false)
+            ]
+        );
+
+        // Cached events of calendars that still exist are kept.
+        let events = db
+            .execute(|conn| {
+                let now = Local::now();
+                EventModel::get_between(
+                    conn,
+                    &1,
+                    "primary",
+                    now - Duration::days(1),
+                    now + Duration::days(1),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, vec![]))
+            .await
+            .unwrap();
+        assert!(db.execute(CalendarInfo::get).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_calendar_groups() {
+        let db = create_store().await;
+        let member = |name: &str| CalendarGroupModel {
+            name: name.to_string(),
+            account_id: 1,
+            calendar_id: "primary".to_string(),
+        };
+
+        for name in ["work", "work", "family"] {
+            let group = member(name);
+            db.execute(move |conn| group.insert(conn)).await.unwrap();
+        }
+        let names = |db: Store| async move {
+            db.execute(CalendarGroupModel::get_all)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.name)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(names(db.clone()).await, vec!["family", "work"]);
+
+        let group = member("family");
+        assert!(db.execute(move |conn| group.delete(conn)).await.unwrap());
+        let group = member("family");
+        assert!(!db.execute(move |conn| group.delete(conn)).await.unwrap());
+
+        // Removed calendars are removed from their groups.
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, vec![]))
+            .await
+            .unwrap();
+        assert!(names(db.clone()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_holds() {
+        let db = create_store().await;
+        let now = Local.timestamp(Local::now().timestamp(), 0);
+
+        assert_eq!(db.execute(HoldModel::next_query_id).await.unwrap(), 1);
+        for (event_id, hours) in [("b", 2), ("a", 1)] {
+            let hold = HoldModel {
+                id: None,
+                account_id: 1,
+                calendar_id: "primary".to_string(),
+                event_id: event_id.to_string(),
+                title: "HOLD - Interview".to_string(),
+                start: now + Duration::hours(hours),
+                end: now + Duration::hours(hours + 1),
+                created: now,
+                query_id: 1,
+                expires: (event_id == "b").then(|| now + Duration::days(3)),
+                etag: Some(format!("etag-{}", event_id)),
+            };
+            db.execute(move |conn| hold.insert(conn)).await.unwrap();
+        }
+        assert_eq!(db.execute(HoldModel::next_query_id).await.unwrap(), 2);
+
+        // Holds are ordered by their start.
+        let holds = db.execute(HoldModel::get_all).await.unwrap();
+        let ids: Vec<&str> = holds.iter().map(|h| h.event_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(holds[0].id, Some(2));
+        assert_eq!(holds[0].start, now + Duration::hours(1));
+        assert_eq!(holds[0].created, now);
+        assert_eq!(holds[1].expires, Some(now + Duration::days(3)));
+        assert_eq!(holds[1].etag.as_deref(), Some("etag-b"));
+
+        // Holds without an expiry never expire.
+        let expired = db
+            .execute(move |conn| HoldModel::get_expired(conn, now + Duration::days(3)))
+            .await
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].event_id, "b");
+        let expired = db
+            .execute(move |conn| HoldModel::get_expired(conn, now + Duration::days(2)))
+            .await
+            .unwrap();
+        assert!(expired.is_empty());
+
+        let hold = holds[0].clone();
+        db.execute(move |conn| hold.delete(conn)).await.unwrap();
+        assert_eq!(db.execute(HoldModel::get_all).await.unwrap().len(), 1);
+
+        // Query ids aren't reused once the holds are released.
+        let hold = holds[1].clone();
+        db.execute(move |conn| hold.delete(conn)).await.unwrap();
+        assert_eq!(db.execute(HoldModel::next_query_id).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_pairs() {
+        let db = create_store().await;
+        let calendars = ["primary", "work"].map(|id| CalendarModel {
+            account_id: Some(1),
+            id: id.to_string(),
+            name: id.to_string(),
+            selected: true,
+        });
+        db.execute(|conn| CalendarModel::replace_for_account(conn, &1, calendars.into()))
+            .await
+            .unwrap();
+
+        let pair = SyncPairModel {
+            id: None,
+            source_account_id: 1,
+            source_calendar_id: "primary".to_string(),
+            target_account_id: 1,
+            target_calendar_id: "work".to_string(),
+        };
+        for inserted in [true, false] {
+            let pair = pair.clone();
+            assert_eq!(
+                db.execute(move |conn| pair.insert(conn)).await.unwrap(),
+                inserted
+            );
+        }
+        let pair = db.execute(SyncPairModel::get_all).await.unwrap()[0].clone();
+        assert_eq!(pair.id, Some(1));
+
+        // Blocks of the same source event are replaced.
+        let now = Local.timestamp(Local::now().timestamp(), 0);
+        for hours in [1, 2] {
+            let block = BusyBlockModel {
+                pair_id: 1,
+                source_event_id: "a".to_string(),
+                target_event_id: format!("blocker-{}", hours),
+                start: now,
+                end: now + Duration::hours(hours),
+            };
+            db.execute(move |conn| block.upsert(conn)).await.unwrap();
+        }
+        let blocks = db.execute(BusyBlockModel::get_all).await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].target_event_id, "blocker-2");
+        assert_eq!(blocks[0].end, now + Duration::hours(2));
+
+        db.execute(move |conn| pair.delete(conn)).await.unwrap();
+        assert!(db
+            .execute(BusyBlockModel::get_all)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_execute() {
+        let db = create_store().await;
+
+        // Clones share the connection, so queries from concurrent tasks see each other's writes.
+        let tasks = (0..4).map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let event = create_event(&i.to_string(), Local::now(), 1);
+                db.execute(move |conn| EventModel::insert_many(conn, vec![event]))
+                    .await
+            })
+        });
+        for res in futures::future::join_all(tasks).await {
+            res.unwrap().unwrap();
+        }
+
+        let count: u32 = db
+            .execute(
+                |conn| Ok(conn.query_row("SELECT COUNT(*) FROM events", (), |row| row.get(0))?),
+            )
+            .await
+            .unwrap();
+        assert_eq!(count, 4);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_backs_up_database() {
+        let dir = std::env::temp_dir().join(format!("avail-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.db3").to_str().unwrap().to_string();
+
+        // A new database isn't backed up.
+        let db = Store::open(&path).unwrap();
+        let (applied, backup) = db.migrate().await.unwrap();
+        assert!(!applied.is_empty());
+        assert!(backup.is_none());
+
+        // Created by a version without migrations.
+        let path = dir.join("old.db3").to_str().unwrap().to_string();
+        let db = Store::open(&path).unwrap();
+        db.execute(|conn| {
+            Ok(conn.execute_batch(
+                "CREATE TABLE accounts (id INTEGER PRIMARY KEY, name TEXT NOT NULL UNIQUE, platform TEXT NOT NULL)",
+            )?)
+        })
+        .await
+        .unwrap();
+        let (applied, backup) = db.migrate().await.unwrap();
+        assert_eq!(applied.len() as u32, migrations::latest_version());
+        assert_eq!(backup, Some(format!("{}.v0.bak", path)));
+
+        let backup = Store::open(&backup.unwrap()).unwrap();
+        let version = backup.execute(migrations::current_version).await.unwrap();
+        assert_eq!(version, 0);
+        assert!(backup.execute(migrations::has_tables).await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
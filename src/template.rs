@@ -0,0 +1,402 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use chrono::prelude::*;
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::datetime::{
+    availability::{format_duration, Availability},
+    locale::Locale,
+};
+use crate::util::get_avail_directory;
+
+pub const DEFAULT_TEMPLATE: &str = "plain";
+
+const BUILTIN_TEMPLATES: [&str; 3] = ["plain", "markdown", "html"];
+
+const DAY_VARIABLES: [&str; 2] = ["day", "offset"];
+const SLOT_VARIABLES: [&str; 6] = ["day", "start", "end", "duration", "offset", "index"];
+
+/// Describes how availabilities are written out, one piece per line.
+///
+/// Each piece may reference variables as `{name}`, use `{{` and `}}` for literal braces.
+/// `{offset}` is the UTC offset of the times, e.g. `+02:00`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Template {
+    /// Written once before everything else, supports `{offset}`.
+    #[serde(default)]
+    pub header: String,
+    /// Written at the start of every day, supports `{day}` and `{offset}`.
+    pub day: String,
+    /// Written for every availability, supports `{day}`, `{start}`, `{end}`, `{duration}`,
+    /// `{offset}` and `{index}`.
+    pub slot: String,
+    /// Written at the end of every day, supports `{day}` and `{offset}`.
+    #[serde(default)]
+    pub day_end: String,
+    /// Written once after everything else, supports `{offset}`.
+    #[serde(default)]
+    pub footer: String,
+    /// Whether the output is HTML, variables are escaped if so.
+    #[serde(default)]
+    pub html: bool,
+}
+
+impl Template {
+    fn builtin(name: &str) -> Option<Template> {
+        let template = match name {
+            "plain" => Template {
+                day: "{day}".to_string(),
+                slot: "- {start} to {end}".to_string(),
+                ..Default::default()
+            },
+            "markdown" => Template {
+                header: "Times are in UTC{offset}.\n".to_string(),
+                day: "**{day}**".to_string(),
+                slot: "- {start} to {end} ({duration})".to_string(),
+                ..Default::default()
+            },
+            "html" => Template {
+                header: "<p>Times are in UTC{offset}.</p>".to_string(),
+                day: "<p><strong>{day}</strong></p>\n<ul>".to_string(),
+                slot: "<li>{start} to {end} ({duration})</li>".to_string(),
+                day_end: "</ul>".to_string(),
+                html: true,
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(template)
+    }
+
+    /// Loads a template by name, user templates in `~/.avail/templates/<name>.toml`
+    /// take precedence over the built-in ones.
+    pub fn load(name: &str) -> anyhow::Result<Template> {
+        // Names are file stems in the templates directory, not paths.
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return Err(anyhow::anyhow!("Invalid template name \"{}\"", name));
+        }
+
+        let templates_dir = format!("{}/templates", get_avail_directory()?);
+        let path_str = format!("{}/{}.toml", templates_dir, name);
+        let path = Path::new(&path_str);
+
+        let template = if path.exists() {
+            toml::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| anyhow::anyhow!("Failed to parse template {}: {}", path_str, e))?
+        } else if let Some(template) = Template::builtin(name) {
+            template
+        } else {
+            return Err(anyhow::anyhow!(
+                "Unknown template \"{}\", available templates: {}",
+                name,
+                Template::available(&templates_dir).join(", ")
+            ));
+        };
+
+        template.validate()?;
+        Ok(template)
+    }
+
+    fn available(templates_dir: &str) -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN_TEMPLATES.iter().map(|n| n.to_string()).collect();
+
+        if let Ok(entries) = fs::read_dir(templates_dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|e| e == "toml") {
+                    if let Some(stem) = path.file_stem() {
+                        names.push(stem.to_string_lossy().to_string());
+                    }
+                }
+            }
+        }
+
+        names.into_iter().unique().collect()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let pieces = [
+            ("header", &self.header, &["offset"][..]),
+            ("day", &self.day, &DAY_VARIABLES[..]),
+            ("slot", &self.slot, &SLOT_VARIABLES[..]),
+            ("day_end", &self.day_end, &DAY_VARIABLES[..]),
+            ("footer", &self.footer, &["offset"][..]),
+        ];
+
+        for (piece, template, allowed) in pieces {
+            let vars: Vec<(&str, String)> = allowed.iter().map(|v| (*v, String::new())).collect();
+            if let Err(e) = substitute(template, &vars, false) {
+                return Err(anyhow::anyhow!(
+                    "Invalid \"{}\" in template: {} (supported: {})",
+                    piece,
+                    e,
+                    allowed.iter().map(|v| format!("{{{}}}", v)).join(", ")
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn render(
+        &self,
+        avails: &[Availability<Local>],
+        locale: &Locale,
+    ) -> anyhow::Result<String> {
+        let mut s = String::new();
+
+        // Local times format `%Z` as their UTC offset, e.g. `+02:00`.
+        let offset = avails
+            .first()
+            .map(|a| a.start.format("%Z").to_string())
+            .unwrap_or_else(|| Local::now().format("%Z").to_string());
+
+        self.write_piece(&mut s, &self.header, &[("offset", offset.clone())])?;
+
+        let mut index = 0;
+        let avail_days = avails.iter().group_by(|e| e.start.date());
+
+        for (day, avails) in avail_days.into_iter() {
+            let day_vars = [("day", locale.day(&day)), ("offset", offset.clone())];
+            self.write_piece(&mut s, &self.day, &day_vars)?;
+
+            for avail in avails {
+                index += 1;
+                let slot_vars = [
+                    ("day", day_vars[0].1.clone()),
+                    ("start", locale.time(&avail.start)),
+                    ("end", locale.time(&avail.end)),
+                    ("duration", format_duration(avail.end - avail.start)),
+                    ("offset", offset.clone()),
+                    ("index", index.to_string()),
+                ];
+                self.write_piece(&mut s, &self.slot, &slot_vars)?;
+            }
+
+            self.write_piece(&mut s, &self.day_end, &day_vars)?;
+        }
+
+        self.write_piece(&mut s, &self.footer, &[("offset", offset)])?;
+
+        Ok(s)
+    }
+
+    fn write_piece(
+        &self,
+        s: &mut String,
+        template: &str,
+        vars: &[(&str, String)],
+    ) -> anyhow::Result<()> {
+        if template.is_empty() {
+            return Ok(());
+        }
+        let _ = writeln!(s, "{}", substitute(template, vars, self.html)?);
+        Ok(())
+    }
+}
+
+fn substitute(template: &str, vars: &[(&str, String)], escape: bool) -> anyhow::Result<String> {
+    let mut res = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                res.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                res.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(anyhow::anyhow!("unclosed {{{}", name)),
+                    }
+                }
+                let value = vars
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value)
+                    .ok_or_else(|| anyhow::anyhow!("unknown variable {{{}}}", name))?;
+
+                if escape {
+                    res.push_str(&escape_html(value));
+                } else {
+                    res.push_str(value);
+                }
+            }
+            '}' => return Err(anyhow::anyhow!("unmatched }}")),
+            _ => res.push(c),
+        }
+    }
+
+    Ok(res)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Converts rendered HTML to plain text for clipboards that can't hold HTML, list items
+/// become `- ` lines and tags are dropped.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut chars = html.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '<' {
+            text.push(c);
+            continue;
+        }
+
+        let tag: String = chars.by_ref().take_while(|c| *c != '>').collect();
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match name.as_str() {
+            "li" if !closing => text.push_str("\n- "),
+            "br" | "p" | "div" | "li" | "ul" | "ol" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5"
+            | "h6" => text.push('\n'),
+            _ => {}
+        }
+    }
+
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_avail(start: &str, end: &str) -> Availability<Local> {
+        let datetime_fmt = "%m-%d-%Y %H:%M";
+        let start = NaiveDateTime::parse_from_str(start, datetime_fmt).unwrap();
+        let end = NaiveDateTime::parse_from_str(end, datetime_fmt).unwrap();
+        Availability {
+            start: Local.from_local_datetime(&start).unwrap(),
+            end: Local.from_local_datetime(&end).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let avails = vec![
+            create_avail("11-07-2022 09:00", "11-07-2022 10:00"),
+            create_avail("11-07-2022 13:00", "11-07-2022 13:30"),
+            create_avail("11-08-2022 15:00", "11-08-2022 17:00"),
+        ];
+
+        let s = Template::builtin("plain")
+            .unwrap()
+            .render(&avails, &Locale::default())
+            .unwrap();
+        assert_eq!(
+            s,
+            "Mon Nov 07 2022\n- 09:00 AM to 10:00 AM\n- 01:00 PM to 01:30 PM\nTue Nov 08 2022\n- 03:00 PM to 05:00 PM\n"
+        );
+    }
+
+    #[test]
+    fn test_render_custom() {
+        let avails = vec![
+            create_avail("11-07-2022 09:00", "11-07-2022 10:30"),
+            create_avail("11-08-2022 15:00", "11-08-2022 15:45"),
+        ];
+
+        let template = Template {
+            slot: "{index}. {day} {{{start}}} ({duration})".to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate().is_ok());
+
+        let s = template.render(&avails, &Locale::default()).unwrap();
+        assert_eq!(
+            s,
+            "1. Mon Nov 07 2022 {09:00 AM} (1h30m)\n2. Tue Nov 08 2022 {03:00 PM} (45m)\n"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let avails = vec![
+            create_avail("11-07-2022 09:00", "11-07-2022 10:00"),
+            create_avail("11-08-2022 15:00", "11-08-2022 17:00"),
+        ];
+
+        let html = Template::builtin("html")
+            .unwrap()
+            .render(&avails, &Locale::default())
+            .unwrap();
+        // Local times format `%Z` as their UTC offset, e.g. `+02:00`.
+        let offset = avails[0].start.format("%Z");
+        assert_eq!(
+            html_to_text(&html),
+            format!(
+                "Times are in UTC{}.\nMon Nov 07 2022\n- 09:00 AM to 10:00 AM (1h)\nTue Nov 08 2022\n- 03:00 PM to 05:00 PM (2h)\n",
+                offset
+            )
+        );
+
+        assert_eq!(
+            html_to_text("<p>Tom &amp; Jerry<br>&lt;3</p><p>Bye</p>"),
+            "Tom & Jerry\n<3\nBye\n"
+        );
+    }
+
+    #[test]
+    fn test_load_invalid_name() {
+        for name in ["../../x", "a/b", "a\\b", "..", ""] {
+            assert!(Template::load(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_validate_unknown_variable() {
+        let template = Template {
+            day: "{day} {start}".to_string(),
+            slot: "{start}".to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate().is_err());
+
+        let template = Template {
+            slot: "{start} }".to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate().is_err());
+
+        let template = Template {
+            slot: "{start} to {end".to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate().is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use dialoguer::{theme::ColorfulTheme, Password};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+const SERVICE_NAME: &str = "avail";
+
+// Read instead of prompting for the passphrase of the encrypted file, e.g. in CI.
+const PASSPHRASE_ENV: &str = "AVAIL_TOKEN_PASSPHRASE";
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Where tokens are stored, `keyring` requires a Secret Service, Keychain or Credential
+/// Manager to be running.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum TokenStorage {
+    #[default]
+    Keyring,
+    /// A file in the avail directory encrypted with a passphrase.
+    File,
+    /// An external helper that is run with `get`, `store` or `erase`, like git credential
+    /// helpers.
+    Command { command: String },
+}
+
+impl TokenStorage {
+    pub fn store(&self, user: &str, token: &str) -> anyhow::Result<()> {
+        match self {
+            TokenStorage::Keyring => {
+                keyring::Entry::new(SERVICE_NAME, user).set_password(token)?;
+                Ok(())
+            }
+            TokenStorage::File => {
+                let file = EncryptedFile::open()?;
+                let mut tokens = file.read()?;
+                tokens.insert(user.to_owned(), token.to_owned());
+                file.write(&tokens)
+            }
+            TokenStorage::Command { command } => {
+                run_helper(command, "store", user, Some(token))?;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get(&self, user: &str) -> anyhow::Result<String> {
+        self.find(user)?
+            .ok_or_else(|| anyhow::anyhow!("No token is stored for {}", user))
+    }
+
+    /// Returns the token stored under `user`, or None if there isn't one. Other errors, e.g.
+    /// a wrong passphrase or a failing helper, are returned as errors.
+    pub fn find(&self, user: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            TokenStorage::Keyring => match keyring::Entry::new(SERVICE_NAME, user).get_password() {
+                Ok(token) => Ok(Some(token)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            TokenStorage::File => Ok(EncryptedFile::open()?.read()?.remove(user)),
+            TokenStorage::Command { command } => {
+                let output = run_helper(command, "get", user, None)?;
+                Ok(parse_helper_output(&output))
+            }
+        }
+    }
+
+    /// Deletes the token stored under `user`, succeeding if there isn't one.
+    pub fn delete(&self, user: &str) -> anyhow::Result<()> {
+        match self {
+            TokenStorage::Keyring => {
+                match keyring::Entry::new(SERVICE_NAME, user).delete_password() {
+                    Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            TokenStorage::File => {
+                let file = EncryptedFile::open()?;
+                let mut tokens = file.read()?;
+                if tokens.remove(user).is_none() {
+                    return Ok(());
+                }
+                file.write(&tokens)
+            }
+            TokenStorage::Command { command } => {
+                run_helper(command, "erase", user, None)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl TokenStorage {
+    /// Returns the token stored under `user`, moving it there from `legacy_user` if it was
+    /// stored by an older version.
+    pub fn get_or_migrate(&self, user: &str, legacy_user: &str) -> anyhow::Result<String> {
+        match self.get(user) {
+            Ok(token) => Ok(token),
+            Err(e) => match self.get(legacy_user) {
+                Ok(token) => {
+                    self.store(user, &token)?;
+                    let _ = self.delete(legacy_user);
+                    Ok(token)
+                }
+                Err(_) => Err(e),
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for TokenStorage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keyring" => Ok(TokenStorage::Keyring),
+            "file" => Ok(TokenStorage::File),
+            _ => match s.strip_prefix("command:") {
+                Some(command) if !command.trim().is_empty() => Ok(TokenStorage::Command {
+                    command: command.trim().to_owned(),
+                }),
+                _ => Err(anyhow::anyhow!(
+                    "invalid token storage \"{}\", expected keyring, file or command:<helper>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for TokenStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenStorage::Keyring => write!(f, "keyring"),
+            TokenStorage::File => write!(f, "file"),
+            TokenStorage::Command { command } => write!(f, "command:{}", command),
+        }
+    }
+}
+
+/// Runs the helper with the action, passing the attributes on stdin as `key=value` lines.
+fn run_helper(
+    command: &str,
+    action: &str,
+    user: &str,
+    token: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut input = format!("service={}\naccount={}\n", SERVICE_NAME, user);
+    if let Some(token) = token {
+        input.push_str(&format!("token={}\n", token));
+    }
+    input.push('\n');
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", command, action))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run token helper \"{}\": {}", command, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Token helper \"{} {}\" exited with {}",
+            command,
+            action,
+            output.status
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn parse_helper_output(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|l| l.strip_prefix("token="))
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_owned())
+}
+
+// The passphrase is only asked for once per run.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedContents {
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Tokens encrypted with AES-256-GCM, using a key derived from the passphrase.
+struct EncryptedFile {
+    path: PathBuf,
+    passphrase: String,
+}
+
+impl EncryptedFile {
+    fn open() -> anyhow::Result<Self> {
+        let path = Path::new(&crate::util::get_avail_directory()?).join("tokens.enc");
+
+        let mut passphrase = PASSPHRASE.lock().unwrap();
+        if passphrase.is_none() {
+            *passphrase = Some(match std::env::var(PASSPHRASE_ENV) {
+                Ok(p) => p,
+                Err(_) => prompt_passphrase(!path.exists())?,
+            });
+        }
+
+        Ok(Self {
+            path,
+            passphrase: passphrase.to_owned().unwrap(),
+        })
+    }
+
+    fn read(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        decrypt(&fs::read_to_string(&self.path)?, &self.passphrase)
+    }
+
+    fn write(&self, tokens: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        let contents = encrypt(tokens, &self.passphrase)?;
+
+        let tmp = self.path.with_extension("enc.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(contents.as_bytes())?;
+
+        fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn prompt_passphrase(new: bool) -> anyhow::Result<String> {
+    let theme = ColorfulTheme::default();
+    let mut prompt = Password::with_theme(&theme);
+    if new {
+        prompt
+            .with_prompt("Choose a passphrase to encrypt your tokens")
+            .with_confirmation("Confirm passphrase", "Passphrases don't match");
+    } else {
+        prompt.with_prompt("Enter the passphrase for your tokens");
+    }
+    Ok(prompt.interact()?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> anyhow::Result<LessSafeKey> {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        std::num::NonZeroU32::new(iterations)
+            .ok_or_else(|| anyhow::anyhow!("invalid iterations"))?,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow::anyhow!("invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn encrypt(tokens: &BTreeMap<String, String>, passphrase: &str) -> anyhow::Result<String> {
+    let rng = SystemRandom::new();
+    let mut salt = [0; 16];
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut salt)
+        .and_then(|_| rng.fill(&mut nonce))
+        .map_err(|_| anyhow::anyhow!("Failed to generate random bytes"))?;
+
+    let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+    let mut data = serde_json::to_vec(tokens)?;
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt tokens"))?;
+
+    Ok(serde_json::to_string(&EncryptedContents {
+        iterations: PBKDF2_ITERATIONS,
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(data),
+    })?)
+}
+
+fn decrypt(contents: &str, passphrase: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    let contents: EncryptedContents = serde_json::from_str(contents)?;
+    let salt = base64::decode(contents.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&base64::decode(contents.nonce)?)
+        .map_err(|_| anyhow::anyhow!("Invalid nonce"))?;
+    let mut data = base64::decode(contents.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, contents.iterations)?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| anyhow::anyhow!("Unable to decrypt tokens, is the passphrase correct?"))?;
+
+    Ok(serde_json::from_slice(plaintext)?)
+}
+
+/// Moves the tokens of the users from one storage to another, returning how many were moved.
+pub fn migrate(from: &TokenStorage, to: &TokenStorage, users: &[String]) -> anyhow::Result<usize> {
+    let mut moved = 0;
+    for user in users {
+        // Accounts without a token, e.g. ones that were never authorized, are skipped.
+        let token = match from.find(user)? {
+            Some(token) => token,
+            None => continue,
+        };
+        to.store(user, &token)?;
+        from.delete(user)?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let mut tokens = BTreeMap::new();
+        tokens.insert("me@example.com".to_string(), "refresh".to_string());
+
+        let contents = encrypt(&tokens, "passphrase").unwrap();
+        assert!(!contents.contains("refresh"));
+        assert_eq!(decrypt(&contents, "passphrase").unwrap(), tokens);
+        assert!(decrypt(&contents, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_parse_token_storage() {
+        assert_eq!(
+            "keyring".parse::<TokenStorage>().unwrap(),
+            TokenStorage::Keyring
+        );
+        assert_eq!("file".parse::<TokenStorage>().unwrap(), TokenStorage::File);
+        assert_eq!(
+            "command:pass-helper".parse::<TokenStorage>().unwrap(),
+            TokenStorage::Command {
+                command: "pass-helper".to_string()
+            }
+        );
+        assert!("command:".parse::<TokenStorage>().is_err());
+        assert!("vault".parse::<TokenStorage>().is_err());
+
+        let storage: TokenStorage = toml::from_str(
+            r#"
+            backend = "command"
+            command = "pass-helper"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(storage.to_string(), "command:pass-helper");
+    }
+
+    #[test]
+    fn test_command_helper() {
+        let dir = std::env::temp_dir().join(format!("avail-helper-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Stores each token in a file named after the account.
+        let script = r#"
+            account=$(sed -n 's/^account=//p' | head -n 1)
+            case "$1" in
+                get) [ -f "$DIR/$account" ] && echo "token=$(cat "$DIR/$account")" ;;
+                store) ;;
+                erase) rm -f "$DIR/$account" ;;
+            esac
+        "#;
+        let helper = dir.join("helper.sh");
+        fs::write(&helper, script).unwrap();
+        fs::write(dir.join("me@example.com"), "refresh").unwrap();
+
+        let storage = TokenStorage::Command {
+            command: format!("DIR={} sh {}", dir.display(), helper.display()),
+        };
+        assert_eq!(storage.get("me@example.com").unwrap(), "refresh");
+        assert!(storage.get("other@example.com").is_err());
+        storage.delete("me@example.com").unwrap();
+        assert!(storage.get("me@example.com").is_err());
+        // Deleting a missing token succeeds.
+        storage.delete("me@example.com").unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
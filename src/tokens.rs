@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use chrono::prelude::*;
+use tokio::sync::Mutex;
+
+use crate::events::{google, microsoft, Unauthorized};
+use crate::oauth::AccessToken;
+use crate::store::{AccountModel, Platform};
+use crate::token_storage::TokenStorage;
+use crate::util::{AvailConfig, OAuthConfig};
+
+/// Caches access tokens per account so they're only refreshed once they expire.
+///
+/// Tokens are shared between concurrent tasks and, if `cache_access_tokens` is set in the
+/// config file, persisted with the refresh tokens between runs.
+pub struct TokenCache {
+    google: OAuthConfig,
+    microsoft: OAuthConfig,
+    storage: TokenStorage,
+    persist: bool,
+    tokens: Mutex<HashMap<String, AccessToken>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredAccessToken {
+    secret: String,
+    expires_at: i64,
+}
+
+/// The user the access token is stored under, `user` is the one of the refresh token.
+pub fn access_token_user(user: &str) -> String {
+    format!("{}:access_token", user)
+}
+
+impl TokenCache {
+    pub fn new(cfg: &AvailConfig) -> Self {
+        Self {
+            google: cfg.google.to_owned().unwrap_or_default(),
+            microsoft: cfg.microsoft.to_owned().unwrap_or_default(),
+            storage: cfg.token_storage.to_owned().unwrap_or_default(),
+            persist: cfg.cache_access_tokens.unwrap_or(false),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns an access token for the account, refreshing it only if it has expired.
+    pub async fn get(&self, account: &AccountModel) -> anyhow::Result<String> {
+        let user = account.token_user();
+
+        // Holding the lock while refreshing ensures concurrent tasks refresh at most once.
+        let mut tokens = self.tokens.lock().await;
+        let now = Local::now();
+
+        if let Some(token) = tokens.get(&user) {
+            if !token.is_expired(now) {
+                return Ok(token.secret.to_owned());
+            }
+        }
+
+        if self.persist {
+            if let Some(token) = load_token(&self.storage, &user) {
+                if !token.is_expired(now) {
+                    let secret = token.secret.to_owned();
+                    tokens.insert(user, token);
+                    return Ok(secret);
+                }
+            }
+        }
+
+        // Refresh tokens used to be stored under the email only.
+        let refresh_token = self.storage.get_or_migrate(&user, &account.name)?;
+        let token = match account.platform.unwrap() {
+            Platform::Microsoft => {
+                microsoft::refresh_access_token(&self.microsoft, &refresh_token).await?
+            }
+            Platform::Google => google::refresh_access_token(&self.google, &refresh_token).await?,
+            _ => return Err(anyhow::anyhow!("Unsupported platform")),
+        };
+
+        if self.persist {
+            save_token(&self.storage, &user, &token)?;
+        }
+
+        let secret = token.secret.to_owned();
+        tokens.insert(user, token);
+        Ok(secret)
+    }
+
+    /// Discards the account's access token if it's still `rejected`, so that the next
+    /// `get` refreshes it.
+    pub async fn invalidate(&self, account: &AccountModel, rejected: &str) {
+        let user = account.token_user();
+        let mut tokens = self.tokens.lock().await;
+        if tokens.get(&user).map(|t| t.secret.as_str()) == Some(rejected) {
+            tokens.remove(&user);
+            if self.persist {
+                delete_persisted_token(&self.storage, &user);
+            }
+        }
+    }
+
+    /// Runs `f` with an access token for the account, refreshing the token and retrying
+    /// once if it's rejected.
+    pub async fn with_token<T, F, Fut>(&self, account: &AccountModel, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let token = self.get(account).await?;
+        match f(token.to_owned()).await {
+            Err(e) if e.is::<Unauthorized>() => {
+                self.invalidate(account, &token).await;
+                f(self.get(account).await?).await
+            }
+            res => res,
+        }
+    }
+}
+
+fn load_token(storage: &TokenStorage, user: &str) -> Option<AccessToken> {
+    let stored = storage.get(&access_token_user(user)).ok()?;
+    let stored: StoredAccessToken = serde_json::from_str(&stored).ok()?;
+    Some(AccessToken {
+        secret: stored.secret,
+        expires_at: Local.timestamp(stored.expires_at, 0),
+    })
+}
+
+fn save_token(storage: &TokenStorage, user: &str, token: &AccessToken) -> anyhow::Result<()> {
+    let stored = StoredAccessToken {
+        secret: token.secret.to_owned(),
+        expires_at: token.expires_at.timestamp(),
+    };
+    storage.store(&access_token_user(user), &serde_json::to_string(&stored)?)
+}
+
+/// Removes the persisted access token of the refresh token stored under `user`, if any.
+pub fn delete_persisted_token(storage: &TokenStorage, user: &str) {
+    let _ = storage.delete(&access_token_user(user));
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn cache_with(account: &AccountModel, token: AccessToken) -> TokenCache {
+        let cache = TokenCache::new(&AvailConfig::default());
+        cache
+            .tokens
+            .try_lock()
+            .unwrap()
+            .insert(account.token_user(), token);
+        cache
+    }
+
+    #[test]
+    fn test_access_token_expiry() {
+        let now = Local::now();
+        let token = AccessToken {
+            secret: "secret".to_string(),
+            expires_at: now + Duration::minutes(30),
+        };
+        assert!(!token.is_expired(now));
+        assert!(token.is_expired(now + Duration::minutes(29) + Duration::seconds(30)));
+        assert!(token.is_expired(now + Duration::hours(1)));
+    }
+
+    #[tokio::test]
+    async fn test_token_cache() {
+        let token = AccessToken {
+            secret: "secret".to_string(),
+            expires_at: Local::now() + Duration::hours(1),
+        };
+        let account = AccountModel {
+            id: Some(1),
+            name: "me@example.com".to_string(),
+            platform: Some(Platform::Google),
+            needs_reauth: false,
+        };
+        let cache = cache_with(&account, token);
+
+        // Unexpired tokens are reused without refreshing.
+        let secret = cache.get(&account).await.unwrap();
+        assert_eq!(secret, "secret");
+
+        // A token that was already replaced isn't discarded.
+        cache.invalidate(&account, "other").await;
+        assert!(cache
+            .tokens
+            .lock()
+            .await
+            .contains_key("google:1:me@example.com"));
+
+        cache.invalidate(&account, "secret").await;
+        assert!(!cache
+            .tokens
+            .lock()
+            .await
+            .contains_key("google:1:me@example.com"));
+    }
+}
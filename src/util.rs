@@ -1,8 +1,18 @@
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
 
-use serde::{Deserialize, Serialize};
+use chrono::{prelude::*, Duration};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::clipboard::ClipboardBackend;
+use crate::datetime::{
+    locale::LocaleConfig,
+    parse::{self, Week},
+};
+use crate::events::ShowAs;
+use crate::token_storage::TokenStorage;
 
 pub fn get_avail_directory() -> anyhow::Result<String> {
     let home_dir = dirs::home_dir();
@@ -23,18 +33,83 @@ pub fn get_avail_directory() -> anyhow::Result<String> {
 pub struct OAuthConfig {
     pub client_id: String,
     pub client_secret: String,
+    /// Port of the server the provider redirects to after signing in, 0 for any free port.
+    pub redirect_port: Option<u16>,
+    /// How long to wait for the redirect before giving up.
+    #[serde(default, skip_serializing, deserialize_with = "deserialize_duration")]
+    pub redirect_timeout: Option<Duration>,
 }
 
 impl OAuthConfig {
     pub fn is_unconfigured(&self) -> bool {
         self.client_id.is_empty() || self.client_secret.is_empty()
     }
+
+    pub fn redirect_port(&self) -> u16 {
+        self.redirect_port.unwrap_or(3003)
+    }
+
+    pub fn redirect_timeout(&self) -> Duration {
+        self.redirect_timeout
+            .unwrap_or_else(|| Duration::minutes(5))
+    }
+}
+
+/// How hold events are created.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoldConfig {
+    pub title_prefix: Option<String>,
+    /// Added to the description of each hold.
+    pub description: Option<String>,
+    /// Whether the description lists the other times that were held.
+    pub list_slots: Option<bool>,
+    /// Google Calendar has no tentative status and shows tentative holds as busy.
+    pub show_as: Option<ShowAs>,
+    pub private: Option<bool>,
+    pub reminders: Option<bool>,
+    /// Outlook category, e.g. "Yellow category".
+    pub category: Option<String>,
+    /// Google Calendar color id, from 1 to 11.
+    pub color: Option<String>,
+}
+
+impl HoldConfig {
+    pub fn title_prefix(&self) -> &str {
+        self.title_prefix.as_deref().unwrap_or("HOLD - ")
+    }
+
+    pub fn list_slots(&self) -> bool {
+        self.list_slots.unwrap_or(true)
+    }
+
+    pub fn show_as(&self) -> ShowAs {
+        self.show_as.unwrap_or(ShowAs::Tentative)
+    }
+
+    pub fn private(&self) -> bool {
+        self.private.unwrap_or(false)
+    }
+
+    pub fn reminders(&self) -> bool {
+        self.reminders.unwrap_or(false)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AvailConfig {
     pub google: Option<OAuthConfig>,
     pub microsoft: Option<OAuthConfig>,
+    pub locale: Option<LocaleConfig>,
+    pub clipboard: Option<ClipboardBackend>,
+    /// Whether access tokens are stored with the refresh tokens to reuse them between runs.
+    pub cache_access_tokens: Option<bool>,
+    pub token_storage: Option<TokenStorage>,
+    pub holds: Option<HoldConfig>,
+    #[serde(skip_serializing)]
+    pub defaults: Option<SearchOptions>,
+    #[serde(skip_serializing)]
+    pub presets: Option<BTreeMap<String, SearchOptions>>,
 }
 
 impl Default for AvailConfig {
@@ -42,15 +117,145 @@ impl Default for AvailConfig {
         AvailConfig {
             google: Some(OAuthConfig::default()),
             microsoft: Some(OAuthConfig::default()),
+            locale: None,
+            clipboard: None,
+            cache_access_tokens: None,
+            token_storage: None,
+            holds: None,
+            defaults: None,
+            presets: None,
+        }
+    }
+}
+
+impl AvailConfig {
+    pub fn get_preset(&self, name: &str) -> anyhow::Result<SearchOptions> {
+        let presets = self.presets.to_owned().unwrap_or_default();
+        match presets.get(name) {
+            Some(preset) => Ok(preset.to_owned()),
+            None if presets.is_empty() => Err(anyhow::anyhow!(
+                "Unknown preset \"{}\", no presets are configured",
+                name
+            )),
+            None => Err(anyhow::anyhow!(
+                "Unknown preset \"{}\", available presets: {}",
+                name,
+                presets.keys().cloned().collect::<Vec<String>>().join(", ")
+            )),
         }
     }
 }
 
+/// Search options that can be set on the command line, in a preset or as defaults.
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchOptions {
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub start: Option<DateTime<Local>>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub end: Option<DateTime<Local>>,
+    pub week: Option<Week>,
+    #[serde(default, deserialize_with = "deserialize_time")]
+    pub min: Option<NaiveTime>,
+    #[serde(default, deserialize_with = "deserialize_time")]
+    pub max: Option<NaiveTime>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub window: Option<Duration>,
+    pub include_weekends: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub duration: Option<Duration>,
+    pub create_hold_event: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub hold_ttl: Option<Duration>,
+    pub template: Option<String>,
+    pub no_copy: Option<bool>,
+    pub calendars: Option<Vec<String>>,
+}
+
+impl SearchOptions {
+    /// Fills in options that aren't set from `fallback`.
+    ///
+    /// The search window is treated as a whole, a week from one side is not mixed
+    /// with a start, end or window from the other, and an end from one side is not mixed
+    /// with a window from the other.
+    pub fn or(self, fallback: SearchOptions) -> SearchOptions {
+        let fallback = if self.week.is_some() {
+            SearchOptions {
+                start: None,
+                end: None,
+                window: None,
+                ..fallback
+            }
+        } else if self.end.is_some() || self.window.is_some() {
+            SearchOptions {
+                end: None,
+                week: None,
+                window: None,
+                ..fallback
+            }
+        } else if self.start.is_some() {
+            SearchOptions {
+                week: None,
+                ..fallback
+            }
+        } else {
+            fallback
+        };
+
+        SearchOptions {
+            start: self.start.or(fallback.start),
+            end: self.end.or(fallback.end),
+            week: self.week.or(fallback.week),
+            min: self.min.or(fallback.min),
+            max: self.max.or(fallback.max),
+            window: self.window.or(fallback.window),
+            include_weekends: self.include_weekends.or(fallback.include_weekends),
+            duration: self.duration.or(fallback.duration),
+            create_hold_event: self.create_hold_event.or(fallback.create_hold_event),
+            hold_ttl: self.hold_ttl.or(fallback.hold_ttl),
+            template: self.template.or(fallback.template),
+            no_copy: self.no_copy.or(fallback.no_copy),
+            calendars: self.calendars.or(fallback.calendars),
+        }
+    }
+}
+
+fn deserialize_date<'de, D>(deserializer: D) -> Result<Option<DateTime<Local>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse::parse_date(&s, Local::now())
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse::parse_time(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse::parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
 pub fn load_config() -> anyhow::Result<AvailConfig> {
     let str_path = format!("{}/conf.toml", get_avail_directory()?);
     let config_path = Path::new(&str_path);
     if config_path.exists() {
-        let cfg: AvailConfig = toml::from_str(&fs::read_to_string(config_path)?)?;
+        let cfg: AvailConfig = toml::from_str(&fs::read_to_string(config_path)?)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", str_path, e))?;
 
         // Ensure that at least one of google, microsoft are configured
         if cfg.google.to_owned().unwrap_or_default().is_unconfigured()
@@ -77,3 +282,147 @@ pub fn load_config() -> anyhow::Result<AvailConfig> {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_options_precedence() {
+        let cfg: AvailConfig = toml::from_str(
+            r#"
+            [defaults]
+            min = "10:00am"
+            max = "4:00pm"
+            duration = "45m"
+            include_weekends = true
+            hold_ttl = "3d"
+
+            [presets.interview]
+            duration = "1h30m"
+            week = "next"
+            calendars = ["work"]
+
+            [presets.coffee-chat]
+            max = "17:00"
+            start = "2022-11-07"
+            "#,
+        )
+        .unwrap();
+
+        let cli = SearchOptions {
+            duration: Some(Duration::hours(2)),
+            ..Default::default()
+        };
+        let options = cli
+            .or(cfg.get_preset("interview").unwrap())
+            .or(cfg.defaults.to_owned().unwrap());
+
+        assert_eq!(options.duration, Some(Duration::hours(2)));
+        assert_eq!(options.week, Some(Week::Next));
+        assert_eq!(options.min, Some(NaiveTime::from_hms(10, 0, 0)));
+        assert_eq!(options.max, Some(NaiveTime::from_hms(16, 0, 0)));
+        assert_eq!(options.include_weekends, Some(true));
+        assert_eq!(options.hold_ttl, Some(Duration::days(3)));
+        assert_eq!(options.template, None);
+        assert_eq!(options.calendars, Some(vec!["work".to_string()]));
+
+        // A negated flag on the command line turns off the default.
+        let cli = SearchOptions {
+            include_weekends: Some(false),
+            ..Default::default()
+        };
+        let options = cli.or(cfg.defaults.to_owned().unwrap());
+        assert_eq!(options.include_weekends, Some(false));
+
+        // A window on the command line replaces the preset's week.
+        let cli = SearchOptions {
+            window: Some(Duration::days(3)),
+            ..Default::default()
+        };
+        let options = cli.or(cfg.get_preset("interview").unwrap());
+        assert_eq!(options.week, None);
+        assert_eq!(options.window, Some(Duration::days(3)));
+
+        // An end on the command line replaces a default window, and the other way around.
+        let defaults = SearchOptions {
+            window: Some(Duration::days(14)),
+            ..Default::default()
+        };
+        let cli = SearchOptions {
+            end: Some(Local::now()),
+            ..Default::default()
+        };
+        let options = cli.or(defaults);
+        assert!(options.end.is_some());
+        assert_eq!(options.window, None);
+
+        let defaults = SearchOptions {
+            end: Some(Local::now()),
+            ..Default::default()
+        };
+        let cli = SearchOptions {
+            window: Some(Duration::days(3)),
+            ..Default::default()
+        };
+        let options = cli.or(defaults);
+        assert_eq!(options.end, None);
+        assert_eq!(options.window, Some(Duration::days(3)));
+
+        let options = SearchOptions::default().or(cfg.get_preset("coffee-chat").unwrap());
+        assert_eq!(options.max, Some(NaiveTime::from_hms(17, 0, 0)));
+        assert!(options.start.is_some());
+
+        assert!(cfg.get_preset("unknown").is_err());
+    }
+
+    #[test]
+    fn test_hold_config() {
+        let cfg: AvailConfig = toml::from_str(
+            r#"
+            [holds]
+            title_prefix = "Tentative: "
+            show_as = "free"
+            private = true
+            "#,
+        )
+        .unwrap();
+        let holds = cfg.holds.unwrap();
+        assert_eq!(holds.title_prefix(), "Tentative: ");
+        assert_eq!(holds.show_as(), ShowAs::Free);
+        assert!(holds.private());
+        assert!(!holds.reminders());
+        assert!(holds.list_slots());
+
+        let holds = HoldConfig::default();
+        assert_eq!(holds.title_prefix(), "HOLD - ");
+        assert_eq!(holds.show_as(), ShowAs::Tentative);
+
+        let res: Result<AvailConfig, _> = toml::from_str(
+            r#"
+            [holds]
+            show_as = "away"
+            "#,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_search_options_invalid() {
+        let res: Result<AvailConfig, _> = toml::from_str(
+            r#"
+            [defaults]
+            duration = "abc"
+            "#,
+        );
+        assert!(res.unwrap_err().to_string().contains("invalid duration"));
+
+        let res: Result<AvailConfig, _> = toml::from_str(
+            r#"
+            [defaults]
+            durations = "1h"
+            "#,
+        );
+        assert!(res.is_err());
+    }
+}